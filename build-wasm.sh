@@ -1,3 +1,7 @@
+## the UI types are generated from the Rust types and must match the module being built
+echo Checking TypeScript types...
+cargo run -q -p ts-gen -- --check || exit 1
+
 ## --release or --dev - exclude/include debug info
 ## --no-typescript - disable .d.ts files output
 ## --out-dir - where to write the compiled files
@@ -1,20 +1,49 @@
-use crate::USER_BOOKS_TABLE_NAME;
+use crate::{USER_BOOKS_SYNCED_INDEX_NAME, USER_BOOKS_TABLE_NAME};
 use anyhow::Error;
-use aws_sdk_dynamodb::{types::AttributeValue, Client};
-use bookworm_types::{jwt::User, lambda::user_books_table_fields as fields, Book, Books, ReadStatus};
-use chrono::{DateTime, Utc};
+use aws_sdk_dynamodb::{
+    operation::update_item::UpdateItemError,
+    types::{AttributeValue, ReturnValuesOnConditionCheckFailure},
+    Client,
+};
+use bookworm_types::{
+    jwt::User, lambda::user_books_table_fields as fields, Book, BookSyncResult, BookSyncResults, Books, BooksPage,
+    ReadStatus, TOMBSTONE_RETENTION_DAYS,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use tracing::info;
 
-/// Save a book in the user_books table.
-/// Replaces existing records unconditionally.
-pub(crate) async fn save(book: &Book, client: &Client, user: User) -> Result<(), Error> {
-    info!("Saving book {}/{}", user.id, book.isbn);
+/// The max number of books `save_batch` writes at the same time.
+const MAX_CONCURRENT_SAVES: usize = 25;
+/// The max number of records returned by `get_by_user` in a single page.
+const PAGE_SIZE: i32 = 500;
+
+/// A DDB record as a map of attribute names and values.
+type Item = HashMap<String, AttributeValue>;
+
+/// Save a book in the user_books table if the book version matches the version of the record.
+/// Brings deleted books back to life.
+/// The update timestamps come from the client for per-field merging, see `Book::merge_fields`.
+/// The server time goes into `synced` for incremental syncs.
+/// Returns the new version on success or the current record in `conflict` if the book version is stale.
+pub(crate) async fn save(book: &Book, client: &Client, user: &User) -> Result<BookSyncResult, Error> {
+    info!("Saving book {}/{}, version {:?}", user.id, book.isbn, book.version);
     // this has to be an update to prevent overwriting photo IDs
     const UPDATE_EXPRESSION: &str =
-        "SET email = :email, title = :title, authors = :authors, read_status = :read_status, updated = :updated";
+        "SET email = :email, title = :title, authors = :authors, read_status = :read_status, updated = :updated, \
+        title_updated = :title_updated, authors_updated = :authors_updated, read_status_updated = :read_status_updated, \
+        synced = :synced, version = :version REMOVE deleted, expires";
 
-    match client
+    // records created before versioning have no version and accept any write
+    let version = book.version.unwrap_or_default() + 1;
+    let condition_expression = match book.version {
+        Some(_) => "attribute_not_exists(version) OR version = :expected_version",
+        None => "attribute_not_exists(version)",
+    };
+
+    let update = client
         .update_item()
         .table_name(USER_BOOKS_TABLE_NAME)
         .update_expression(UPDATE_EXPRESSION)
@@ -30,116 +59,318 @@ pub(crate) async fn save(book: &Book, client: &Client, user: User) -> Result<(),
         )
         .expression_attribute_values(
             [":", fields::UPDATED].concat(),
+            AttributeValue::S(book.timestamp_update.to_rfc3339()),
+        )
+        .expression_attribute_values(
+            [":", fields::TITLE_UPDATED].concat(),
+            attr_val_ts(&book.field_timestamps.title),
+        )
+        .expression_attribute_values(
+            [":", fields::AUTHORS_UPDATED].concat(),
+            attr_val_ts(&book.field_timestamps.authors),
+        )
+        .expression_attribute_values(
+            [":", fields::READ_STATUS_UPDATED].concat(),
+            attr_val_ts(&book.field_timestamps.read_status),
+        )
+        .expression_attribute_values(
+            [":", fields::SYNCED].concat(),
             AttributeValue::S(Utc::now().to_rfc3339()),
         )
-        .send()
-        .await
-    {
+        .expression_attribute_values([":", fields::VERSION].concat(), AttributeValue::N(version.to_string()))
+        .condition_expression(condition_expression)
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld);
+
+    let update = match book.version {
+        Some(v) => update.expression_attribute_values(":expected_version", AttributeValue::N(v.to_string())),
+        None => update,
+    };
+
+    match update.send().await {
         Ok(_) => {
-            info!("Book saved in DDB");
-            Ok(())
-        }
-        Err(e) => {
-            info!("Failed to save book {}/{}: {:?}", user.id, book.isbn, e);
-            Err(Error::msg("Failed to save book".to_string()))
+            info!("Book saved in DDB, version {version}");
+            Ok(BookSyncResult {
+                isbn: book.isbn,
+                saved: true,
+                error: None,
+                version: Some(version),
+                conflict: None,
+            })
         }
+        Err(e) => match e.into_service_error() {
+            UpdateItemError::ConditionalCheckFailedException(e) => {
+                let current = e.item.and_then(item_to_book);
+                info!(
+                    "Version conflict for {}/{}: {:?} vs {:?}",
+                    user.id,
+                    book.isbn,
+                    book.version,
+                    current.as_ref().map(|v| v.version)
+                );
+                Ok(conflict_result(book.isbn, current))
+            }
+            e => {
+                info!("Failed to save book {}/{}: {:?}", user.id, book.isbn, e);
+                Err(Error::msg("Failed to save book".to_string()))
+            }
+        },
+    }
+}
+
+/// Returns a result for a write rejected because of a stale version.
+fn conflict_result(isbn: u64, current: Option<Book>) -> BookSyncResult {
+    BookSyncResult {
+        isbn,
+        saved: false,
+        error: Some("Version conflict".to_string()),
+        version: current.as_ref().and_then(|v| v.version),
+        conflict: current.map(Box::new),
     }
 }
 
-/// Returns all book records for the given user.
+/// Save a list of books in the user_books table with a conditional update per book, same as `save`.
+/// BatchWriteItem is not used because it can only replace whole records without checking their version,
+/// which would overwrite concurrent writes and the photo IDs added by photo-tracker.
+/// Returns one result per unique ISBN with the new version or the current record on conflict. Errors are logged.
+pub(crate) async fn save_batch(books: Books, client: &Client, user: &User) -> BookSyncResults {
+    info!("Saving {} books for {}", books.books.len(), user.id);
+
+    // the last copy of the book wins if the list has duplicates
+    let books = books.books.into_iter().map(|v| (v.isbn, v)).collect::<HashMap<_, _>>();
+
+    let results = stream::iter(books.values())
+        .map(|book| async move {
+            match save(book, client, user).await {
+                Ok(v) => v,
+                Err(e) => BookSyncResult {
+                    isbn: book.isbn,
+                    saved: false,
+                    error: Some(e.to_string()),
+                    version: None,
+                    conflict: None,
+                },
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_SAVES)
+        .collect::<Vec<_>>()
+        .await;
+
+    info!(
+        "Saved {} of {} books for {}",
+        results.iter().filter(|v| v.saved).count(),
+        results.len(),
+        user.id
+    );
+
+    BookSyncResults { results }
+}
+
+/// Returns a page of book records for the given user or only the records written at or after `since`.
+/// The `since` records are looked up in `USER_BOOKS_SYNCED_INDEX_NAME`.
+/// The time of the last write is returned in `Book::timestamp_sync`.
+/// `page` is the cursor from the previous page or None for the first page.
+/// The cursor of the next page is returned in `BooksPage::next_page` if there are more records.
 /// Returns an empty list if no records found.
-pub(crate) async fn get_by_user(client: &Client, user_id: &str) -> Result<Books, Error> {
-    info!("Getting books for {}", user_id);
-    let books = match client
+pub(crate) async fn get_by_user(
+    client: &Client,
+    user_id: &str,
+    since: Option<DateTime<Utc>>,
+    page: Option<&str>,
+) -> Result<BooksPage, Error> {
+    info!("Getting books for {}, since {:?}, page {:?}", user_id, since, page);
+
+    let start_key = match page {
+        Some(v) => Some(decode_page_cursor(v, user_id)?),
+        None => None,
+    };
+
+    let query = client
         .query()
         .table_name(USER_BOOKS_TABLE_NAME)
-        .key_condition_expression("#user_id = :user_id")
+        .limit(PAGE_SIZE)
+        .set_exclusive_start_key(start_key)
         .expression_attribute_names("#user_id", fields::UID)
-        .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_owned()))
-        .send()
-        .await
-    {
-        Ok(v) => match v.items {
-            // convert the items into books
-            Some(items) => {
-                let mut books = Vec::with_capacity(items.len());
-                // loop thru the records
-                'item: for item in items {
-                    let mut book = Book::new(Book::FAKE_ISBN);
-
-                    // iterate through the list of attributes for the record
-                    // instead of looking them up by name
-                    for attr in item {
-                        match attr.0.as_str() {
-                            fields::ISBN => {
-                                book.isbn = match attr_to_isbn(attr.1) {
-                                    Some(v) => v,
-                                    None => continue 'item,
-                                }
-                            }
-                            fields::SHARE_ID => book.share_id = attr_s_to_option_u64(attr.1),
-                            fields::TITLE => book.title = attr_s_to_option(attr.1),
-                            fields::AUTHORS => {
-                                book.authors = match attr.1 {
-                                    AttributeValue::Ss(v) => Some(v),
-                                    _ => None,
-                                }
-                            }
-                            fields::UPDATED => {
-                                book.timestamp_update = match DateTime::parse_from_rfc3339(&attr_s_to_string(attr.1)) {
-                                    Ok(v) => v.into(),
-                                    Err(_) => DateTime::<Utc>::MIN_UTC,
-                                }
-                            }
-                            fields::READ_STATUS => {
-                                book.read_status = ReadStatus::from_str(&attr_s_to_string(attr.1)).ok()
-                            }
-                            fields::PHOTO_IDS => {
-                                // info!("Photo IDs: {:?}", attr.1);
-                                book.photos = match attr.1 {
-                                    AttributeValue::Ss(v) => Some(v),
-                                    _ => None,
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-
-                    // there is potential for an incomplete record if ISBN/Updated fields are missing
-
-                    books.push(book);
-                }
+        .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_owned()));
 
-                Books { books }
-            }
-            None => {
-                info!("No books found for user {}", user_id);
-                Books { books: Vec::new() }
-            }
-        },
+    let query = match since {
+        // the timestamp must be formatted the same way as in `save` for the string comparison to work
+        Some(since) => query
+            .index_name(USER_BOOKS_SYNCED_INDEX_NAME)
+            .key_condition_expression("#user_id = :user_id AND #synced >= :since")
+            .expression_attribute_names("#synced", fields::SYNCED)
+            .expression_attribute_values(":since", AttributeValue::S(since.to_rfc3339())),
+        None => query.key_condition_expression("#user_id = :user_id"),
+    };
+
+    let resp = match query.send().await {
+        Ok(v) => v,
         Err(e) => {
             info!("Failed to get books for {}: {:?}", user_id, e);
-            return Err(Error::msg("Failed to save book".to_string()));
+            return Err(Error::msg("Failed to get books".to_string()));
+        }
+    };
+
+    // DDB stops at PAGE_SIZE records or 1MB of data, whichever comes first
+    let next_page = resp.last_evaluated_key.as_ref().map(encode_page_cursor);
+
+    let books = match resp.items {
+        // convert the items into books
+        // expired tombstones may still be returned until DDB TTL removes them, which is harmless
+        Some(items) => items.into_iter().filter_map(item_to_book).collect::<Vec<_>>(),
+        None => {
+            info!("No books found for user {}", user_id);
+            Vec::new()
         }
     };
 
-    info!("Returning {} books for {}", books.books.len(), user_id);
-    Ok(books)
+    info!("Returning {} books for {}, next: {:?}", books.len(), user_id, next_page);
+    Ok(BooksPage { books, next_page })
+}
+
+/// Converts `LastEvaluatedKey` of a query into a cursor for the client to send back for the next page.
+/// The cursor is the hex-encoded JSON of the key attributes, so it needs no escaping in the URL.
+/// The user ID is left out because it comes from the token.
+fn encode_page_cursor(key: &Item) -> String {
+    let attrs = key
+        .iter()
+        .filter(|(k, _)| k.as_str() != fields::UID)
+        .filter_map(|(k, v)| match v {
+            AttributeValue::S(v) => Some((k.clone(), ["s:", v].concat())),
+            AttributeValue::N(v) => Some((k.clone(), ["n:", v].concat())),
+            _ => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    hex::encode(serde_json::to_string(&attrs).unwrap_or_default())
+}
+
+/// Converts the cursor from `encode_page_cursor` back into `ExclusiveStartKey` for the user.
+fn decode_page_cursor(cursor: &str, user_id: &str) -> Result<Item, Error> {
+    let attrs = hex::decode(cursor)
+        .ok()
+        .and_then(|v| serde_json::from_slice::<BTreeMap<String, String>>(&v).ok())
+        .ok_or_else(|| {
+            info!("Invalid page cursor: {cursor}");
+            Error::msg("Invalid page param".to_string())
+        })?;
+
+    let mut key = Item::with_capacity(attrs.len() + 1);
+    for (k, v) in attrs {
+        let v = match v.split_once(':') {
+            Some(("s", v)) => AttributeValue::S(v.to_owned()),
+            Some(("n", v)) => AttributeValue::N(v.to_owned()),
+            _ => {
+                info!("Invalid page cursor attribute: {k}={v}");
+                return Err(Error::msg("Invalid page param".to_string()));
+            }
+        };
+        key.insert(k, v);
+    }
+    key.insert(fields::UID.to_owned(), AttributeValue::S(user_id.to_owned()));
+
+    Ok(key)
+}
+
+/// Returns the ETag for the response of `get_by_user`.
+/// It is derived from the latest `updated` value of the books.
+/// photo-tracker sets both `updated` and `synced` to the server time when photos are added or removed.
+/// The latest `synced` and the number of books are added to it to catch changes that keep the client timestamps,
+/// e.g. a merge after a conflict, and tombstones removed by TTL.
+/// The cursor of the next page is added because it changes when more records are written after this page.
+pub(crate) fn etag(page: &BooksPage) -> String {
+    let updated = page.books.iter().map(|v| v.timestamp_update).max();
+    let synced = page.books.iter().filter_map(|v| v.timestamp_sync).max();
+
+    format!(
+        "\"{}-{}-{}{}\"",
+        page.books.len(),
+        updated.map(|v| v.timestamp_micros()).unwrap_or_default(),
+        synced.map(|v| v.timestamp_micros()).unwrap_or_default(),
+        page.next_page.as_deref().map(|v| ["-", v].concat()).unwrap_or_default()
+    )
+}
+
+/// Converts a DDB record into a book.
+/// Returns None if the record has no valid ISBN.
+fn item_to_book(item: Item) -> Option<Book> {
+    let mut book = Book::new(Book::FAKE_ISBN);
+
+    // iterate through the list of attributes for the record
+    // instead of looking them up by name
+    for attr in item {
+        match attr.0.as_str() {
+            fields::ISBN => book.isbn = attr_to_isbn(attr.1)?,
+            fields::SHARE_ID => book.share_id = attr_s_to_option_u64(attr.1),
+            fields::TITLE => book.title = attr_s_to_option(attr.1),
+            fields::AUTHORS => {
+                book.authors = match attr.1 {
+                    AttributeValue::Ss(v) => Some(v),
+                    _ => None,
+                }
+            }
+            fields::UPDATED => {
+                book.timestamp_update = match DateTime::parse_from_rfc3339(&attr_s_to_string(attr.1)) {
+                    Ok(v) => v.into(),
+                    Err(_) => DateTime::<Utc>::MIN_UTC,
+                }
+            }
+            fields::READ_STATUS => book.read_status = ReadStatus::from_str(&attr_s_to_string(attr.1)).ok(),
+            fields::TITLE_UPDATED => book.field_timestamps.title = attr_s_to_timestamp(attr.1),
+            fields::AUTHORS_UPDATED => book.field_timestamps.authors = attr_s_to_timestamp(attr.1),
+            fields::READ_STATUS_UPDATED => book.field_timestamps.read_status = attr_s_to_timestamp(attr.1),
+            fields::SYNCED => book.timestamp_sync = attr_s_to_timestamp(attr.1),
+            fields::DELETED => book.timestamp_delete = attr_s_to_timestamp(attr.1),
+            fields::VERSION => book.version = attr_n_to_option_u64(attr.1),
+            fields::PHOTO_IDS => {
+                // info!("Photo IDs: {:?}", attr.1);
+                book.photos = match attr.1 {
+                    AttributeValue::Ss(v) => Some(v),
+                    _ => None,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // there is potential for an incomplete record if ISBN/Updated fields are missing
+
+    Some(book)
 }
 
-/// Deletes a book from user_books table.
+/// Replaces the book in user_books table with a tombstone record for other devices to pick up the deletion.
+/// The tombstone keeps the keys and the deletion timestamp, which is also the update timestamp,
+/// so that it is returned by `get_by_user` with a `since` value.
+/// It is removed by DDB TTL on `expires` after `TOMBSTONE_RETENTION_DAYS`.
 pub(crate) async fn delete(isbn: &str, client: &Client, user_id: &str) -> Result<(), Error> {
     info!("Deleting book {}/{}", user_id, isbn);
+    // `share` is a reserved keyword in DDB
+    const UPDATE_EXPRESSION: &str =
+        "SET deleted = :deleted, updated = :deleted, synced = :deleted, expires = :expires, \
+        version = if_not_exists(version, :zero) + :one \
+        REMOVE title, authors, read_status, title_updated, authors_updated, read_status_updated, photo_ids, #share";
+
+    let now = Utc::now();
+    let expires = now + TimeDelta::days(TOMBSTONE_RETENTION_DAYS);
+
     match client
-        .delete_item()
+        .update_item()
         .table_name(USER_BOOKS_TABLE_NAME)
+        .update_expression(UPDATE_EXPRESSION)
         .key(fields::UID, AttributeValue::S(user_id.to_owned()))
         .key(fields::ISBN, AttributeValue::N(isbn.to_string()))
+        .expression_attribute_names("#share", fields::SHARE_ID)
+        .expression_attribute_values([":", fields::DELETED].concat(), AttributeValue::S(now.to_rfc3339()))
+        .expression_attribute_values(
+            [":", fields::EXPIRES].concat(),
+            AttributeValue::N(expires.timestamp().to_string()),
+        )
+        .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
         .send()
         .await
     {
         Ok(_) => {
-            info!("Book deleted from DDB: {}/{}", user_id, isbn);
+            info!("Book tombstone saved in DDB: {}/{}", user_id, isbn);
             Ok(())
         }
         Err(e) => {
@@ -161,6 +392,26 @@ fn attr_val_ss(v: &Option<Vec<String>>) -> AttributeValue {
         .map_or_else(|| AttributeValue::Null(true), |v| AttributeValue::Ss(v.clone()))
 }
 
+///Converts the value into an RFC3339 AttributeValue
+fn attr_val_ts(v: &Option<DateTime<Utc>>) -> AttributeValue {
+    v.as_ref()
+        .map_or_else(|| AttributeValue::Null(true), |v| AttributeValue::S(v.to_rfc3339()))
+}
+
+/// Converts an RFC3339 String AttributeValue into an option-timestamp
+fn attr_s_to_timestamp(v: AttributeValue) -> Option<DateTime<Utc>> {
+    match v {
+        AttributeValue::S(v) => match DateTime::parse_from_rfc3339(&v) {
+            Ok(v) => Some(v.into()),
+            Err(e) => {
+                info!("Invalid timestamp: {}, err: {}", v, e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
 /// Converts the AttributeValue into a string
 /// Returns an empty string if the value is not a string
 fn attr_s_to_string(v: AttributeValue) -> String {
@@ -199,6 +450,20 @@ fn attr_s_to_option(v: AttributeValue) -> Option<String> {
     }
 }
 
+/// Converts a Number AttributeValue into an option-u64
+fn attr_n_to_option_u64(v: AttributeValue) -> Option<u64> {
+    match v {
+        AttributeValue::N(v) => match v.parse::<u64>() {
+            Ok(n) => Some(n),
+            Err(e) => {
+                info!("Invalid numeric val: {}, err: {}", v, e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
 /// Converts a Number AttributeValue into an option-string
 fn attr_s_to_option_u64(v: AttributeValue) -> Option<u64> {
     match v {
@@ -215,3 +480,272 @@ fn attr_s_to_option_u64(v: AttributeValue) -> Option<u64> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::config::{
+        http::{HttpRequest, HttpResponse},
+        retry::RetryConfig,
+        BehaviorVersion, Credentials, Region,
+    };
+    use aws_smithy_runtime_api::client::http::{
+        http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector,
+    };
+    use aws_smithy_types::body::SdkBody;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    /// A DDB stand-in that replies to the requests for an ISBN with the canned response, 200 with `{}` by default.
+    /// The request bodies are recorded in the order they were sent.
+    #[derive(Debug, Clone, Default)]
+    struct MockDdb {
+        responses: Arc<Mutex<HashMap<u64, (u16, Value)>>>,
+        requests: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl MockDdb {
+        fn respond(&self, isbn: u64, status: u16, body: Value) {
+            self.responses.lock().unwrap().insert(isbn, (status, body));
+        }
+
+        fn request(&self, isbn: u64) -> Value {
+            let isbn = isbn.to_string();
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|v| v["Key"][fields::ISBN]["N"] == isbn.as_str())
+                .cloned()
+                .unwrap()
+        }
+
+        fn client(&self) -> Client {
+            let ddb = self.clone();
+            let config = aws_sdk_dynamodb::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .region(Region::new("us-east-1"))
+                .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+                .retry_config(RetryConfig::disabled())
+                .http_client(http_client_fn(move |_, _| SharedHttpConnector::new(ddb.clone())))
+                .build();
+            Client::from_conf(config)
+        }
+    }
+
+    impl HttpConnector for MockDdb {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let body = request
+                .body()
+                .bytes()
+                .and_then(|v| serde_json::from_slice::<Value>(v).ok())
+                .unwrap_or_default();
+            let isbn = body["Key"][fields::ISBN]["N"]
+                .as_str()
+                .and_then(|v| v.parse::<u64>().ok());
+            self.requests.lock().unwrap().push(body);
+
+            let (status, body) = isbn
+                .and_then(|v| self.responses.lock().unwrap().get(&v).cloned())
+                .unwrap_or((200, json!({})));
+            let response = HttpResponse::new(status.try_into().unwrap(), SdkBody::from(body.to_string()));
+            HttpConnectorFuture::ready(Ok(response))
+        }
+    }
+
+    fn user() -> User {
+        User {
+            email: "reader@example.com".to_owned(),
+            id: "user-1".to_owned(),
+        }
+    }
+
+    fn book(isbn: u64, version: Option<u64>) -> Book {
+        let mut book = Book::new(isbn);
+        book.title = Some("Title".to_owned());
+        book.version = version;
+        book
+    }
+
+    /// The response of DDB to a failed condition with `ReturnValuesOnConditionCheckFailure::AllOld`.
+    fn condition_failed(isbn: u64, version: u64) -> Value {
+        json!({
+            "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+            "message": "The conditional request failed",
+            "Item": {
+                fields::UID: { "S": "user-1" },
+                fields::ISBN: { "N": isbn.to_string() },
+                fields::TITLE: { "S": "Title from another device" },
+                fields::PHOTO_IDS: { "SS": ["1727129470"] },
+                fields::VERSION: { "N": version.to_string() },
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn batch_saves_are_conditional_updates() {
+        let ddb = MockDdb::default();
+        let books = Books {
+            books: vec![book(9780143107712, None), book(9781761186950, Some(2))],
+        };
+
+        let mut results = save_batch(books, &ddb.client(), &user()).await.results;
+        results.sort_by_key(|v| v.isbn);
+
+        assert!(results.iter().all(|v| v.saved && v.conflict.is_none()));
+        assert_eq!(results[0].version, Some(1));
+        assert_eq!(results[1].version, Some(3));
+
+        // a new book must not overwrite a record created elsewhere in the meantime
+        let new_book = ddb.request(9780143107712);
+        assert_eq!(new_book["ConditionExpression"], "attribute_not_exists(version)");
+
+        // an update only touches the fields the client owns, so the photo IDs are kept
+        let update = ddb.request(9781761186950);
+        assert_eq!(
+            update["ConditionExpression"],
+            "attribute_not_exists(version) OR version = :expected_version"
+        );
+        assert_eq!(update["ExpressionAttributeValues"][":expected_version"]["N"], "2");
+        assert!(!update["UpdateExpression"].as_str().unwrap().contains(fields::PHOTO_IDS));
+    }
+
+    #[tokio::test]
+    async fn stale_batch_version_returns_conflict() {
+        let ddb = MockDdb::default();
+        ddb.respond(9780143107712, 400, condition_failed(9780143107712, 3));
+        let books = Books {
+            books: vec![book(9780143107712, Some(1)), book(9781761186950, Some(1))],
+        };
+
+        let mut results = save_batch(books, &ddb.client(), &user()).await.results;
+        results.sort_by_key(|v| v.isbn);
+
+        let stale = &results[0];
+        assert!(!stale.saved);
+        assert_eq!(stale.error.as_deref(), Some("Version conflict"));
+        assert_eq!(stale.version, Some(3));
+        let current = stale.conflict.as_ref().unwrap();
+        assert_eq!(current.title.as_deref(), Some("Title from another device"));
+        assert_eq!(current.photos, Some(vec!["1727129470".to_owned()]));
+
+        // the conflict does not stop the rest of the batch
+        assert!(results[1].saved);
+        assert_eq!(results[1].version, Some(2));
+    }
+
+    #[tokio::test]
+    async fn tombstones_expire_by_ttl() {
+        let ddb = MockDdb::default();
+        let client = ddb.client();
+
+        delete("9780143107712", &client, "user-1").await.unwrap();
+
+        let tombstone = ddb.request(9780143107712);
+        assert!(tombstone["UpdateExpression"]
+            .as_str()
+            .unwrap()
+            .contains("expires = :expires"));
+        let expires = tombstone["ExpressionAttributeValues"][":expires"]["N"]
+            .as_str()
+            .unwrap()
+            .parse::<i64>()
+            .unwrap();
+        let retention = TimeDelta::days(TOMBSTONE_RETENTION_DAYS).num_seconds();
+        assert!((expires - Utc::now().timestamp() - retention).abs() < 60);
+
+        // a book saved again must not be removed by TTL
+        save(&book(9781761186950, Some(1)), &client, &user()).await.unwrap();
+        let update = ddb.request(9781761186950);
+        assert!(update["UpdateExpression"]
+            .as_str()
+            .unwrap()
+            .ends_with("REMOVE deleted, expires"));
+    }
+
+    #[tokio::test]
+    async fn failed_batch_save_is_reported() {
+        let ddb = MockDdb::default();
+        ddb.respond(
+            9780143107712,
+            500,
+            json!({ "__type": "com.amazonaws.dynamodb.v20120810#InternalServerError", "message": "Oops" }),
+        );
+        let books = Books {
+            books: vec![book(9780143107712, Some(1))],
+        };
+
+        let results = save_batch(books, &ddb.client(), &user()).await.results;
+
+        assert_eq!(results.len(), 1);
+        assert!(!results[0].saved);
+        assert_eq!(results[0].error.as_deref(), Some("Failed to save book"));
+        assert!(results[0].conflict.is_none());
+    }
+
+    #[test]
+    fn page_cursor_round_trip() {
+        let key = Item::from([
+            (fields::UID.to_owned(), AttributeValue::S("user-1".to_owned())),
+            (fields::ISBN.to_owned(), AttributeValue::N("9780143107712".to_owned())),
+            (
+                fields::SYNCED.to_owned(),
+                AttributeValue::S("2024-09-23T22:11:10.123+00:00".to_owned()),
+            ),
+        ]);
+
+        let cursor = encode_page_cursor(&key);
+        assert!(cursor.chars().all(|v| v.is_ascii_hexdigit()));
+        assert_eq!(decode_page_cursor(&cursor, "user-1").unwrap(), key);
+
+        // the user ID always comes from the token
+        let other_user = decode_page_cursor(&cursor, "user-2").unwrap();
+        assert_eq!(other_user[fields::UID], AttributeValue::S("user-2".to_owned()));
+
+        assert!(decode_page_cursor("not-hex", "user-1").is_err());
+        assert!(decode_page_cursor(&hex::encode(r#"{"isbn":"x:1"}"#), "user-1").is_err());
+    }
+
+    #[test]
+    fn page_cursor_keeps_attribute_types() {
+        // a cursor from the main table has only the key, a cursor from the index adds the sort key of the index
+        for key in [
+            Item::from([(fields::ISBN.to_owned(), AttributeValue::N("9781761186950".to_owned()))]),
+            Item::from([
+                (fields::ISBN.to_owned(), AttributeValue::N("9781761186950".to_owned())),
+                (
+                    fields::SYNCED.to_owned(),
+                    AttributeValue::S("title:with:colons".to_owned()),
+                ),
+            ]),
+        ] {
+            let mut expected = key.clone();
+            expected.insert(fields::UID.to_owned(), AttributeValue::S("user-1".to_owned()));
+            assert_eq!(
+                decode_page_cursor(&encode_page_cursor(&key), "user-1").unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_page_cursors_are_rejected() {
+        for cursor in [
+            "",
+            "zz",
+            // odd length
+            "abc",
+            // valid hex, not JSON
+            &hex::encode("not json"),
+            // JSON, but not a map of strings
+            &hex::encode(r#"["s:1"]"#),
+            &hex::encode(r#"{"isbn":1}"#),
+            // no type prefix
+            &hex::encode(r#"{"isbn":"9781761186950"}"#),
+            // unknown type prefix
+            &hex::encode(r#"{"isbn":"b:1"}"#),
+        ] {
+            assert!(decode_page_cursor(cursor, "user-1").is_err(), "{cursor}");
+        }
+    }
+}
@@ -5,11 +5,16 @@ use aws_lambda_events::{
 use aws_sdk_dynamodb::Client;
 use bookworm_types::{
     jwt,
-    lambda::{init_tracing_subscriber, USER_BOOKS_TABLE_NAME},
-    Book, AUTH_HEADER, ISBN_URL_PARAM_NAME,
+    lambda::{
+        get_request_id, init_tracing_subscriber, request_span, USER_BOOKS_SYNCED_INDEX_NAME, USER_BOOKS_TABLE_NAME,
+    },
+    Book, BookSyncResult, Books, PhotoFormat, AUTH_HEADER, ISBN_URL_PARAM_NAME, MAX_PHOTOS_PER_UPLOAD,
+    PAGE_URL_PARAM_NAME, PHOTO_COUNT_URL_PARAM_NAME, PHOTO_TYPE_URL_PARAM_NAME, REQUEST_ID_HEADER,
+    SINCE_URL_PARAM_NAME,
 };
+use chrono::{DateTime, Utc};
 use lambda_runtime::{service_fn, Error, LambdaEvent, Runtime};
-use tracing::info;
+use tracing::{info, Instrument};
 
 mod book;
 mod photo;
@@ -27,9 +32,28 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Runs the handler in a span with the request ID and echoes the ID back in the response header.
 pub(crate) async fn my_handler(
     event: LambdaEvent<LambdaFunctionUrlRequest>,
 ) -> Result<LambdaFunctionUrlResponse, Error> {
+    let request_id = get_request_id(
+        event
+            .payload
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok()),
+        &event.context.request_id,
+    );
+
+    let mut resp = handle_request(event).instrument(request_span(&request_id)).await?;
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        resp.headers.insert(REQUEST_ID_HEADER, v);
+    }
+
+    Ok(resp)
+}
+
+async fn handle_request(event: LambdaEvent<LambdaFunctionUrlRequest>) -> Result<LambdaFunctionUrlResponse, Error> {
     // info!("Received event: {:?}", event);
     let path = event.payload.raw_path.clone().unwrap_or("".to_string());
     info!("Path: {}", path);
@@ -80,37 +104,87 @@ pub(crate) async fn my_handler(
     match method {
         // save the book to the database
         Method::POST => {
+            // a list of books is saved in batches with a result per book
+            if let Some(books) = event
+                .payload
+                .body
+                .as_ref()
+                .and_then(|v| serde_json::from_str::<Books>(v).ok())
+            {
+                let results = book::save_batch(books, &client, &user).await;
+                return match serde_json::to_string(&results) {
+                    Ok(v) => handler_response(Some(v), 200),
+                    Err(e) => {
+                        info!("Failed to serialize sync results for {}: {:?}", user.id, e);
+                        handler_response(Some(e.to_string()), 400)
+                    }
+                };
+            }
+
             // try to deser the body into a book
             let book = match &event.payload.body {
                 Some(v) => match serde_json::from_str::<Book>(v) {
                     Ok(v) => v,
                     Err(e) => {
                         info!("Failed to parse payload: {:?}", e);
-                        return handler_response(Some("Invalid payload. Expected DdbBook".to_string()), 400);
+                        return handler_response(Some("Invalid payload. Expected Book or Books".to_string()), 400);
                     }
                 },
                 None => {
                     info!("Empty input");
-                    return handler_response(Some("Missing payload. Expected DdbBook".to_string()), 400);
+                    return handler_response(Some("Missing payload. Expected Book or Books".to_string()), 400);
                 }
             };
 
-            match book::save(&book, &client, user).await {
-                Ok(_) => handler_response(None, 204),
+            save_response(book::save(&book, &client, &user).await)
+        }
+        // return the list of all books or only the books changed since the given time, one page at a time
+        Method::GET => {
+            let since = match event.payload.query_string_parameters.get(SINCE_URL_PARAM_NAME) {
+                Some(v) => match DateTime::parse_from_rfc3339(v) {
+                    Ok(v) => Some(v.with_timezone(&Utc)),
+                    Err(e) => {
+                        info!("Invalid since param: {v}, {:?}", e);
+                        return handler_response(Some("Invalid since param. Expected RFC3339".to_string()), 400);
+                    }
+                },
+                None => None,
+            };
+
+            // the client sends back the ETag of the response it has cached for the same URL
+            let if_none_match = event
+                .payload
+                .headers
+                .get("If-None-Match")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned());
+
+            // the cursor from the previous page, if any
+            let page = event
+                .payload
+                .query_string_parameters
+                .get(PAGE_URL_PARAM_NAME)
+                .map(|v| v.as_str());
+
+            match book::get_by_user(&client, &user.id, since, page).await {
+                Ok(v) => match serde_json::to_string(&v) {
+                    Ok(body) => {
+                        let etag = book::etag(&v);
+                        if if_none_match.is_some_and(|v| etag_matches(&v, &etag)) {
+                            info!("Not modified for {}: {etag}", user.id);
+                            etag_response(None, 304, &etag)
+                        } else {
+                            etag_response(Some(body), 200, &etag)
+                        }
+                    }
+                    Err(e) => {
+                        info!("Failed to serialize books for {}: {:?}", user.id, e);
+                        handler_response(Some(e.to_string()), 400)
+                    }
+                },
                 Err(e) => handler_response(Some(e.to_string()), 400),
             }
         }
-        // return the list of all books
-        Method::GET => match book::get_by_user(&client, &user.id).await {
-            Ok(v) => match serde_json::to_string(&v) {
-                Ok(v) => handler_response(Some(v), 200),
-                Err(e) => {
-                    info!("Failed to serialize books for {}: {:?}", user.id, e);
-                    handler_response(Some(e.to_string()), 400)
-                }
-            },
-            Err(e) => handler_response(Some(e.to_string()), 400),
-        },
         Method::PUT => {
             // try to deser the body into a book
             let book = match &event.payload.body {
@@ -127,8 +201,51 @@ pub(crate) async fn my_handler(
                 }
             };
 
-            match photo::get_signed_url(&book, &user.id).await {
-                Ok(v) => handler_response(Some(["\"".to_string(), v, "\"".to_string()].concat()), 200),
+            // a list of URLs is returned if the count is given, otherwise a single URL as a JSON string
+            let count = match event.payload.query_string_parameters.get(PHOTO_COUNT_URL_PARAM_NAME) {
+                Some(v) => match v.parse::<usize>() {
+                    Ok(v) if (1..=MAX_PHOTOS_PER_UPLOAD).contains(&v) => Some(v),
+                    _ => {
+                        info!("Invalid count param: {v}");
+                        return handler_response(
+                            Some(format!("Invalid count param. Expected 1..{MAX_PHOTOS_PER_UPLOAD}")),
+                            400,
+                        );
+                    }
+                },
+                None => None,
+            };
+
+            // one content type per photo, JPEG by default
+            let formats = match event.payload.query_string_parameters.get(PHOTO_TYPE_URL_PARAM_NAME) {
+                Some(v) => match v
+                    .split(',')
+                    .map(PhotoFormat::from_content_type)
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(formats) if formats.len() == count.unwrap_or(1) => formats,
+                    _ => {
+                        info!("Invalid type param: {v}");
+                        return handler_response(
+                            Some("Invalid type param. Expected an image content type per photo".to_string()),
+                            400,
+                        );
+                    }
+                },
+                None => vec![PhotoFormat::Jpeg; count.unwrap_or(1)],
+            };
+
+            match photo::get_signed_urls(&book, &user.id, &formats).await {
+                Ok(v) => {
+                    let body = match count {
+                        Some(_) => serde_json::to_string(&v),
+                        None => serde_json::to_string(&v[0]),
+                    };
+                    match body {
+                        Ok(v) => handler_response(Some(v), 200),
+                        Err(e) => handler_response(Some(e.to_string()), 400),
+                    }
+                }
                 Err(e) => handler_response(Some(e.to_string()), 400),
             }
         }
@@ -169,3 +286,90 @@ fn handler_response(body: Option<String>, status: i64) -> Result<LambdaFunctionU
         is_base64_encoded: false,
     })
 }
+
+/// Same as `handler_response`, but with the `ETag` header for the client to revalidate the response later.
+fn etag_response(body: Option<String>, status: i64, etag: &str) -> Result<LambdaFunctionUrlResponse, Error> {
+    let mut response = handler_response(body, status)?;
+    if let Ok(v) = HeaderValue::from_str(etag) {
+        response.headers.append("ETag", v);
+    }
+
+    Ok(response)
+}
+
+/// Converts the result of saving a single book into a response.
+/// A stale write gets the current record back with 409 for the client to merge and retry.
+fn save_response(result: Result<BookSyncResult, anyhow::Error>) -> Result<LambdaFunctionUrlResponse, Error> {
+    match result {
+        Ok(v) => match v.conflict {
+            Some(current) => match serde_json::to_string(&current) {
+                Ok(v) => handler_response(Some(v), 409),
+                Err(e) => handler_response(Some(e.to_string()), 400),
+            },
+            None => match serde_json::to_string(&v) {
+                Ok(v) => handler_response(Some(v), 200),
+                Err(e) => handler_response(Some(e.to_string()), 400),
+            },
+        },
+        Err(e) => handler_response(Some(e.to_string()), 400),
+    }
+}
+
+/// Returns true if the value of `If-None-Match` header lists the ETag.
+/// Weak and strong ETags are compared the same way.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(conflict: Option<Book>) -> BookSyncResult {
+        BookSyncResult {
+            isbn: 9780143107712,
+            saved: conflict.is_none(),
+            error: None,
+            version: Some(3),
+            conflict: conflict.map(Box::new),
+        }
+    }
+
+    #[test]
+    fn conflict_returns_current_record() {
+        let mut current = Book::new(9780143107712);
+        current.title = Some("Title from another device".to_owned());
+        current.version = Some(3);
+
+        let response = save_response(Ok(result(Some(current)))).unwrap();
+        assert_eq!(response.status_code, 409);
+
+        // the body is the cloud record, not the sync result
+        let body = serde_json::from_str::<Book>(&response.body.unwrap()).unwrap();
+        assert_eq!(body.isbn, 9780143107712);
+        assert_eq!(body.title.as_deref(), Some("Title from another device"));
+        assert_eq!(body.version, Some(3));
+    }
+
+    #[test]
+    fn saved_book_returns_sync_result() {
+        let response = save_response(Ok(result(None))).unwrap();
+        assert_eq!(response.status_code, 200);
+
+        let body = serde_json::from_str::<BookSyncResult>(&response.body.unwrap()).unwrap();
+        assert!(body.saved);
+        assert_eq!(body.version, Some(3));
+        assert!(body.conflict.is_none());
+    }
+
+    #[test]
+    fn failed_save_is_bad_request() {
+        let response = save_response(Err(anyhow::Error::msg("Failed to save book"))).unwrap();
+        assert_eq!(response.status_code, 400);
+        assert_eq!(response.body.as_deref(), Some("Failed to save book"));
+    }
+}
@@ -1,45 +1,84 @@
 use anyhow::Error;
 use aws_sdk_s3::{presigning::PresigningConfig, Client};
-use bookworm_types::{Book, TIMESTAMP_BASE, USER_PHOTOS_BUCKET_NAME, USER_PHOTOS_S3_PREFIX, USER_PHOTOS_S3_SUFFIX};
+use bookworm_types::{Book, PhotoFormat, TIMESTAMP_BASE, USER_PHOTOS_BUCKET_NAME, USER_PHOTOS_S3_PREFIX};
 use std::time::Duration;
 use tracing::info;
 
-/// Generates a presigned URL for uploading a photo of the book.
-pub(crate) async fn get_signed_url(book: &Book, user_id: &str) -> Result<String, Error> {
-    // join the different parts together with the path prefix
-    let pid = [
-        USER_PHOTOS_S3_PREFIX,
-        user_id,
-        "-",
-        &book.isbn.to_string(),
-        "-",
-        // taking out the constant part of the timestamp makes it for a shorter URL
-        // potentially fallible if NOW is in the past
-        &(chrono::Utc::now().timestamp() as u64 - TIMESTAMP_BASE).to_string(),
-        USER_PHOTOS_S3_SUFFIX,
-    ]
-    .concat();
+/// Generates presigned URLs for uploading photos of the book, one per format in `formats`.
+/// Every photo gets its own ID, see `photo_keys`.
+/// The URLs are signed with the content type of the format, so the upload has to send the same `Content-Type`.
+pub(crate) async fn get_signed_urls(book: &Book, user_id: &str, formats: &[PhotoFormat]) -> Result<Vec<String>, Error> {
+    // taking out the constant part of the timestamp makes it for a shorter URL
+    // potentially fallible if NOW is in the past
+    let timestamp = chrono::Utc::now().timestamp() as u64 - TIMESTAMP_BASE;
 
     let client = Client::new(&aws_config::load_from_env().await);
 
-    match client
-        .put_object()
-        .bucket(USER_PHOTOS_BUCKET_NAME)
-        .key(pid)
-        .presigned(PresigningConfig::expires_in(Duration::from_secs(300)).expect("Invalid duration. It's a bug."))
-        .await
+    let mut urls = Vec::with_capacity(formats.len());
+    for (pid, format) in photo_keys(user_id, book.isbn, timestamp, formats)
+        .into_iter()
+        .zip(formats)
     {
-        Ok(v) => {
-            let url = v.uri().to_string();
-            info!("Presigned URL: {}", url);
-            Ok(url)
-        }
-        Err(e) => {
-            info!(
-                "Failed to generate presigned request {}/{}: {:?}",
-                user_id, book.isbn, e
-            );
-            Err(Error::msg("Failed to generate presigned request".to_string()))
+        match client
+            .put_object()
+            .bucket(USER_PHOTOS_BUCKET_NAME)
+            .key(pid)
+            .content_type(format.content_type())
+            .presigned(PresigningConfig::expires_in(Duration::from_secs(300)).expect("Invalid duration. It's a bug."))
+            .await
+        {
+            Ok(v) => {
+                let url = v.uri().to_string();
+                info!("Presigned URL: {}", url);
+                urls.push(url);
+            }
+            Err(e) => {
+                info!(
+                    "Failed to generate presigned request {}/{}: {:?}",
+                    user_id, book.isbn, e
+                );
+                return Err(Error::msg("Failed to generate presigned request".to_string()));
+            }
         }
     }
+
+    Ok(urls)
+}
+
+/// Returns the S3 keys for new photos of the book, one per format in `formats`.
+/// The photo ID is the timestamp, so the photos of the same request get consecutive timestamps
+/// to keep them unique and in the order of the upload.
+fn photo_keys(user_id: &str, isbn: u64, timestamp: u64, formats: &[PhotoFormat]) -> Vec<String> {
+    (timestamp..)
+        .zip(formats)
+        .map(|(photo_id, format)| {
+            // join the different parts together with the path prefix
+            [
+                USER_PHOTOS_S3_PREFIX,
+                user_id,
+                "-",
+                &isbn.to_string(),
+                "-",
+                &photo_id.to_string(),
+                format.suffix(),
+            ]
+            .concat()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn photo_keys_are_unique() {
+        assert_eq!(
+            photo_keys("uid", 9780143107712, 23520065, &[PhotoFormat::Jpeg, PhotoFormat::Png]),
+            vec![
+                "photos/uid-9780143107712-23520065.jpg".to_owned(),
+                "photos/uid-9780143107712-23520066.png".to_owned()
+            ]
+        );
+    }
 }
@@ -7,9 +7,10 @@ use lambda_runtime::{service_fn, Error, LambdaEvent, Runtime};
 // use serde::{Deserialize, Serialize};
 // use serde_json::from_str;
 use bookworm_types::google;
-use bookworm_types::lambda::init_tracing_subscriber;
+use bookworm_types::lambda::{get_request_id, init_tracing_subscriber, request_span};
+use bookworm_types::REQUEST_ID_HEADER;
 use index::get_index_from_s3;
-use tracing::{error, info};
+use tracing::{error, info, Instrument};
 
 mod index;
 
@@ -26,9 +27,28 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Runs the handler in a span with the request ID and echoes the ID back in the response header.
 pub(crate) async fn my_handler(
     event: LambdaEvent<LambdaFunctionUrlRequest>,
 ) -> Result<LambdaFunctionUrlResponse, Error> {
+    let request_id = get_request_id(
+        event
+            .payload
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok()),
+        &event.context.request_id,
+    );
+
+    let mut resp = handle_request(event).instrument(request_span(&request_id)).await?;
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        resp.headers.insert(REQUEST_ID_HEADER, v);
+    }
+
+    Ok(resp)
+}
+
+async fn handle_request(event: LambdaEvent<LambdaFunctionUrlRequest>) -> Result<LambdaFunctionUrlResponse, Error> {
     // info!("Received event: {:?}", event);
     let path = event.payload.raw_path.clone().unwrap_or("".to_string());
     info!("Path: {}", path);
@@ -1,9 +1,10 @@
 use aws_lambda_events::s3::{S3Event, S3EventRecord};
+use aws_sdk_dynamodb::Client;
 
-use bookworm_types::lambda::init_tracing_subscriber;
-use bookworm_types::{USER_PHOTOS_S3_PREFIX, USER_PHOTOS_S3_SUFFIX};
+use bookworm_types::lambda::{init_tracing_subscriber, request_span};
+use bookworm_types::{photo_id_from_file_name, USER_PHOTOS_S3_PREFIX};
 use lambda_runtime::{service_fn, Error, LambdaEvent, Runtime};
-use tracing::info;
+use tracing::{info, Instrument};
 
 mod photo;
 
@@ -22,15 +23,23 @@ async fn main() -> Result<(), Error> {
 
 pub(crate) async fn my_handler(event: LambdaEvent<S3Event>) -> Result<(), Error> {
     // info!("Received event: {:?}", event);
-    for record in event.payload.records {
-        // there should be only one record in the event
-        process_record(record).await?;
-    }
+    // S3 events have no client request ID, so the invocation ID is used to tie the log lines together
+    let span = request_span(&event.context.request_id);
 
-    Ok(())
+    async {
+        let client = Client::new(&aws_config::load_from_env().await);
+        for record in event.payload.records {
+            // there should be only one record in the event
+            process_record(record, &client).await?;
+        }
+
+        Ok(())
+    }
+    .instrument(span)
+    .await
 }
 
-async fn process_record(record: S3EventRecord) -> Result<(), Error> {
+async fn process_record(record: S3EventRecord, client: &Client) -> Result<(), Error> {
     // get the file name from the record
     let object_key = match record.s3.object.key {
         Some(v) => v,
@@ -40,20 +49,15 @@ async fn process_record(record: S3EventRecord) -> Result<(), Error> {
     };
 
     // e.g. 2be54aceee5f0f64203861eee7938f594bb0304d84cab1583a0032dec8dcb80d-9780143107712-1727064374.jpg
+    // or ...-1727064374.png for formats other than JPEG
     info!("File name: {object_key}");
 
-    // check if the file name starts with the right prefix and ends with .jpg and remove the prefix and suffix
-    let ids = if object_key.starts_with(USER_PHOTOS_S3_PREFIX) && object_key.ends_with(USER_PHOTOS_S3_SUFFIX) {
-        // strip_prefix returns none if there was no match - return the original string then
-        // TODO: this is lame
-        let object_key = object_key.strip_prefix(USER_PHOTOS_S3_PREFIX).unwrap_or(&object_key);
-        object_key
-            .strip_suffix(USER_PHOTOS_S3_SUFFIX)
-            .unwrap_or(object_key)
-            .split('-')
-            .collect::<Vec<&str>>()
-    } else {
-        return Err(anyhow::Error::msg("Invalid file name: suffix or prefix mismatch").into());
+    // check if the file name starts with the right prefix and remove it
+    let ids = match object_key.strip_prefix(USER_PHOTOS_S3_PREFIX) {
+        Some(v) => v.split('-').collect::<Vec<&str>>(),
+        None => {
+            return Err(anyhow::Error::msg("Invalid file name: prefix mismatch").into());
+        }
     };
 
     // a valid file name consists of 3 parts
@@ -63,7 +67,13 @@ async fn process_record(record: S3EventRecord) -> Result<(), Error> {
 
     let user_id = ids[0].to_string();
     let isbn = ids[1].to_string();
-    let photo_id = ids[2].to_string();
+    // .jpg is dropped from the photo ID, other extensions are kept, see `PhotoFormat`
+    let photo_id = match photo_id_from_file_name(ids[2]) {
+        Some(v) => v,
+        None => {
+            return Err(anyhow::Error::msg(format!("Invalid file name: unsupported photo type {}", ids[2])).into());
+        }
+    };
     info!(
         "Event:{:?}, UID: {}, ISBN: {}, Photo ID{}",
         record.event_name, user_id, isbn, photo_id
@@ -72,8 +82,10 @@ async fn process_record(record: S3EventRecord) -> Result<(), Error> {
     // save the photo ID to the user book record
 
     match &record.event_name {
-        Some(v) if v.starts_with("ObjectCreated:") => photo::add_photo_to_ddb(&user_id, isbn, photo_id).await,
-        Some(v) if v.starts_with("ObjectRemoved:") => photo::remove_photo_from_ddb(&user_id, isbn, photo_id).await,
+        Some(v) if v.starts_with("ObjectCreated:") => photo::add_photo_to_ddb(client, &user_id, isbn, photo_id).await,
+        Some(v) if v.starts_with("ObjectRemoved:") => {
+            photo::remove_photo_from_ddb(client, &user_id, isbn, photo_id).await
+        }
         _ => {
             info!("Unhandled S3 event: {:?}", record.event_name);
             Ok(())
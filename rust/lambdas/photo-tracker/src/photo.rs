@@ -2,17 +2,26 @@ use aws_sdk_dynamodb::{
     types::{AttributeValue, ReturnValue},
     Client,
 };
-use bookworm_types::lambda::{user_books_table_fields as fields, USER_BOOKS_TABLE_NAME};
+use bookworm_types::{
+    lambda::{user_books_table_fields as fields, USER_BOOKS_TABLE_NAME},
+    share_id_from_photo_id,
+};
 use chrono::Utc;
 use tracing::info;
 
 /// Adds a photo ID to a user book record.
-pub(crate) async fn add_photo_to_ddb(user_id: &str, isbn: String, photo_id: String) -> Result<(), crate::Error> {
+pub(crate) async fn add_photo_to_ddb(
+    client: &Client,
+    user_id: &str,
+    isbn: String,
+    photo_id: String,
+) -> Result<(), crate::Error> {
     match update_ddb(
+        client,
         user_id,
         isbn.clone(),
         photo_id.clone(),
-        "ADD photo_ids :photo_ids SET updated = :updated", // TODO: replace attribute names with constants
+        "ADD photo_ids :photo_ids, version :version SET updated = :updated, synced = :updated", // TODO: replace attribute names with constants
     )
     .await
     {
@@ -28,12 +37,18 @@ pub(crate) async fn add_photo_to_ddb(user_id: &str, isbn: String, photo_id: Stri
 }
 
 /// Deletes a photo ID from a user book record.
-pub(crate) async fn remove_photo_from_ddb(user_id: &str, isbn: String, photo_id: String) -> Result<(), crate::Error> {
+pub(crate) async fn remove_photo_from_ddb(
+    client: &Client,
+    user_id: &str,
+    isbn: String,
+    photo_id: String,
+) -> Result<(), crate::Error> {
     match update_ddb(
+        client,
         user_id,
         isbn.clone(),
         photo_id.clone(),
-        "DELETE photo_ids :photo_ids SET updated = :updated", // TODO: replace attribute names with constants
+        "DELETE photo_ids :photo_ids ADD version :version SET updated = :updated, synced = :updated", // TODO: replace attribute names with constants
     )
     .await
     {
@@ -49,14 +64,15 @@ pub(crate) async fn remove_photo_from_ddb(user_id: &str, isbn: String, photo_id:
 }
 
 /// A reusable part of calling DDB for adding or removing a photo ID.
+/// The change is a new version of the record with the server time in `synced`,
+/// so it is picked up by incremental syncs and stale writes from other devices get a version conflict.
 async fn update_ddb(
+    client: &Client,
     user_id: &str,
     isbn: String,
     photo_id: String,
     update_expression: &str,
 ) -> Result<(), crate::Error> {
-    let client = Client::new(&aws_config::load_from_env().await);
-
     // update the list of photos and return the updated item
     let updated_item = match client
         .update_item()
@@ -72,6 +88,7 @@ async fn update_ddb(
             [":", fields::UPDATED].concat(),
             AttributeValue::S(Utc::now().to_rfc3339()),
         )
+        .expression_attribute_values([":", fields::VERSION].concat(), AttributeValue::N("1".to_owned()))
         .return_values(ReturnValue::AllNew)
         .send()
         .await
@@ -86,7 +103,12 @@ async fn update_ddb(
     if let Some(att) = updated_item.attributes {
         // info!("Updated item: {:?}", att);
         if !att.contains_key(fields::SHARE_ID) {
-            info!("Setting share to {photo_id}");
+            // the share ID is a number, so the extension of non-JPEG photos is dropped
+            let share_id = match share_id_from_photo_id(&photo_id) {
+                Some(v) => v.to_string(),
+                None => return Err(anyhow::Error::msg(format!("Invalid photo ID: {photo_id}")).into()),
+            };
+            info!("Setting share to {share_id}");
             let update_expression = ["SET #share = :", fields::SHARE_ID].concat();
             match client
                 .update_item()
@@ -95,7 +117,7 @@ async fn update_ddb(
                 .key(fields::UID, AttributeValue::S(user_id.to_owned()))
                 .key(fields::ISBN, AttributeValue::N(isbn.clone()))
                 .expression_attribute_names("#share", fields::SHARE_ID) // share is a reserved word
-                .expression_attribute_values([":", fields::SHARE_ID].concat(), AttributeValue::S(photo_id.clone()))
+                .expression_attribute_values([":", fields::SHARE_ID].concat(), AttributeValue::S(share_id))
                 .send()
                 .await
             {
@@ -108,3 +130,126 @@ async fn update_ddb(
     // return OK if the share value was set earlier
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::config::{
+        http::{HttpRequest, HttpResponse},
+        retry::RetryConfig,
+        BehaviorVersion, Credentials, Region,
+    };
+    use aws_smithy_runtime_api::client::http::{
+        http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector,
+    };
+    use aws_smithy_types::body::SdkBody;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    /// A DDB stand-in that replies to all requests with the same response and records the request bodies.
+    #[derive(Debug, Clone)]
+    struct MockDdb {
+        response: Value,
+        requests: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl MockDdb {
+        fn new(response: Value) -> Self {
+            Self {
+                response,
+                requests: Default::default(),
+            }
+        }
+
+        fn requests(&self) -> Vec<Value> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn client(&self) -> Client {
+            let ddb = self.clone();
+            let config = aws_sdk_dynamodb::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .region(Region::new("us-east-1"))
+                .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+                .retry_config(RetryConfig::disabled())
+                .http_client(http_client_fn(move |_, _| SharedHttpConnector::new(ddb.clone())))
+                .build();
+            Client::from_conf(config)
+        }
+    }
+
+    impl HttpConnector for MockDdb {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let body = request
+                .body()
+                .bytes()
+                .and_then(|v| serde_json::from_slice::<Value>(v).ok())
+                .unwrap_or_default();
+            self.requests.lock().unwrap().push(body);
+
+            let response = HttpResponse::new(200.try_into().unwrap(), SdkBody::from(self.response.to_string()));
+            HttpConnectorFuture::ready(Ok(response))
+        }
+    }
+
+    /// A record that already has a share ID, so only the photo list is updated.
+    fn shared_book() -> Value {
+        json!({ "Attributes": { fields::SHARE_ID: { "S": "1727129470" } } })
+    }
+
+    #[tokio::test]
+    async fn photo_changes_are_synced() {
+        let ddb = MockDdb::new(shared_book());
+        let client = ddb.client();
+        add_photo_to_ddb(&client, "user-1", "9780143107712".to_owned(), "23520065".to_owned())
+            .await
+            .unwrap();
+        remove_photo_from_ddb(&client, "user-1", "9780143107712".to_owned(), "23520065".to_owned())
+            .await
+            .unwrap();
+
+        let requests = ddb.requests();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            // other devices get the change from the incremental sync and their stale writes are rejected
+            let expression = request["UpdateExpression"].as_str().unwrap();
+            assert!(expression.contains("version :version"), "{expression}");
+            assert!(expression.contains("synced = :updated"), "{expression}");
+            assert_eq!(request["ExpressionAttributeValues"][":version"], json!({ "N": "1" }));
+            assert_eq!(
+                request["ExpressionAttributeValues"][":photo_ids"],
+                json!({ "SS": ["23520065"] })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn share_id_has_no_extension() {
+        // a record without a share ID, so the first photo sets it
+        let ddb = MockDdb::new(json!({ "Attributes": { fields::UID: { "S": "2be54aceee5f" } } }));
+        let record = serde_json::from_value(json!({
+            "eventTime": "2024-09-23T22:11:10Z",
+            "eventName": "ObjectCreated:Put",
+            "userIdentity": {},
+            "requestParameters": {},
+            "responseElements": {},
+            "s3": {
+                "bucket": {},
+                "object": { "key": "photos/2be54aceee5f-9780143107712-23520065.png" }
+            }
+        }))
+        .unwrap();
+        crate::process_record(record, &ddb.client()).await.unwrap();
+
+        let requests = ddb.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0]["ExpressionAttributeValues"][":photo_ids"],
+            json!({ "SS": ["23520065.png"] })
+        );
+        assert_eq!(
+            requests[1]["ExpressionAttributeValues"][":share"],
+            json!({ "S": "23520065" })
+        );
+    }
+}
@@ -2,10 +2,10 @@ use aws_lambda_events::{
     http::{method::Method, HeaderMap, HeaderValue},
     lambda_function_urls::{LambdaFunctionUrlRequest, LambdaFunctionUrlResponse},
 };
-use bookworm_types::lambda::init_tracing_subscriber;
-use bookworm_types::{ISBN_URL_PARAM_NAME, SHARE_ID_URL_PARAM_NAME};
+use bookworm_types::lambda::{get_request_id, init_tracing_subscriber, request_span};
+use bookworm_types::{ISBN_URL_PARAM_NAME, REQUEST_ID_HEADER, SHARE_ID_URL_PARAM_NAME};
 use lambda_runtime::{service_fn, Error, LambdaEvent, Runtime};
-use tracing::info;
+use tracing::{info, Instrument};
 
 mod share;
 
@@ -22,9 +22,28 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Runs the handler in a span with the request ID and echoes the ID back in the response header.
 pub(crate) async fn my_handler(
     event: LambdaEvent<LambdaFunctionUrlRequest>,
 ) -> Result<LambdaFunctionUrlResponse, Error> {
+    let request_id = get_request_id(
+        event
+            .payload
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok()),
+        &event.context.request_id,
+    );
+
+    let mut resp = handle_request(event).instrument(request_span(&request_id)).await?;
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        resp.headers.insert(REQUEST_ID_HEADER, v);
+    }
+
+    Ok(resp)
+}
+
+async fn handle_request(event: LambdaEvent<LambdaFunctionUrlRequest>) -> Result<LambdaFunctionUrlResponse, Error> {
     // info!("Received event: {:?}", event);
     let path = event.payload.raw_path.clone().unwrap_or("".to_string());
     info!("Path: {}", path);
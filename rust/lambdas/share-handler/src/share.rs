@@ -21,24 +21,17 @@ pub(crate) async fn get_photo_share_urls(isbn: u64, share_id: &str) -> Vec<Strin
         .expression_attribute_values(":isbn", AttributeValue::N(isbn.to_string()))
         .expression_attribute_names("#share", fields::SHARE_ID)
         .expression_attribute_values(":share", AttributeValue::S(share_id.to_string()))
+        .into_paginator()
+        .items()
         .send()
+        .try_collect()
         .await
     {
-        Ok(v) => match v.items {
-            // convert the items into books
-            Some(items) => {
-                // loop thru the records
-                items
-                    .iter()
-                    .filter_map(|item| item.get(fields::UID).map(|v| attr_s_to_string(v.clone())))
-                    .collect::<Vec<_>>()
-            }
-
-            None => {
-                info!("No books found for share_id {}, isbn: {}", share_id, isbn);
-                Vec::new()
-            }
-        },
+        // all pages are read to catch duplicates that may end up on different pages
+        Ok(items) => items
+            .iter()
+            .filter_map(|item| item.get(fields::UID).map(|v| attr_s_to_string(v.clone())))
+            .collect::<Vec<_>>(),
         Err(e) => {
             info!(
                 "Failed to get items for share_id: {}, isbn: {}, error: {:?}",
@@ -70,27 +63,20 @@ pub(crate) async fn get_photo_share_urls(isbn: u64, share_id: &str) -> Vec<Strin
         .expression_attribute_values(":user_id", AttributeValue::S(user_id.clone()))
         .expression_attribute_names("#isbn", fields::ISBN)
         .expression_attribute_values(":isbn", AttributeValue::N(isbn.to_string()))
+        .into_paginator()
+        .items()
         .send()
+        .try_collect()
         .await
     {
-        Ok(v) => match v.items {
-            // convert the items into books
-            Some(items) => {
-                // loop thru the records
-                items
-                    .iter()
-                    .filter_map(|item| match item.get(fields::PHOTO_IDS) {
-                        Some(AttributeValue::Ss(v)) => Some(v.to_owned()),
-                        _ => None,
-                    })
-                    .collect::<Vec<_>>()
-            }
-
-            None => {
-                info!("No books found for user_id {}, isbn: {}", user_id, isbn);
-                Vec::new()
-            }
-        },
+        // loop thru the records from all pages
+        Ok(items) => items
+            .iter()
+            .filter_map(|item| match item.get(fields::PHOTO_IDS) {
+                Some(AttributeValue::Ss(v)) => Some(v.to_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
         Err(e) => {
             info!("Failed to get books for {}: {:?}", user_id, e);
             return Vec::new();
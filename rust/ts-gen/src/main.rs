@@ -0,0 +1,576 @@
+//! Generates TypeScript declarations for the types sent between the wasm module and the UI
+//! from their Rust definitions, so that the UI does not have to mirror them by hand.
+//!
+//! Run from anywhere in the repo:
+//! - `cargo run -p ts-gen` writes `vue/src/wasm-types.ts`
+//! - `cargo run -p ts-gen -- --check` fails if the file is out of date
+//!
+//! Only the serde attributes used in this repo are supported.
+//! Anything else fails the generation rather than producing a wrong declaration.
+use anyhow::{bail, Context, Result};
+use quote::ToTokens;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use syn::{Attribute, Expr, Fields, GenericArgument, Item, Lit, LitStr, PathArguments, Type};
+
+/// Files with the types that cross the wasm boundary, relative to the repo root.
+const SOURCES: [&str; 10] = [
+    "rust/types/src/lib.rs",
+    "rust/types/src/book.rs",
+    "rust/types/src/google.rs",
+    "rust/wasm_mod/src/wasm_response.rs",
+    "rust/wasm_mod/src/backup.rs",
+    "rust/wasm_mod/src/integrity.rs",
+    "rust/wasm_mod/src/merge.rs",
+    "rust/wasm_mod/src/photos.rs",
+    "rust/wasm_mod/src/storage.rs",
+    "rust/wasm_mod/src/sync.rs",
+];
+
+/// Every message to the UI is a `WasmMessage` and `ReadStatus` is passed to the entry points.
+/// All the types they refer to are included.
+const ROOTS: [&str; 2] = ["WasmMessage", "ReadStatus"];
+
+/// The generated file, relative to the repo root.
+const OUTPUT: &str = "vue/src/wasm-types.ts";
+
+const HEADER: &str = "// Generated by `cargo run -p ts-gen` from the Rust types in `bookworm_types` and `wasm_mod`.
+// Do not edit. The file is checked by `cargo test` and `build-wasm.sh`.
+";
+
+fn main() -> Result<()> {
+    let check = std::env::args().any(|v| v == "--check");
+
+    let generated = generate_from_repo()?;
+    let output = repo_root().join(OUTPUT);
+
+    if check {
+        let current = std::fs::read_to_string(&output).unwrap_or_default();
+        if current != generated {
+            bail!("{OUTPUT} is out of date. Run `cargo run -p ts-gen` to update it.");
+        }
+        println!("{OUTPUT} is up to date");
+    } else {
+        std::fs::write(&output, generated).with_context(|| format!("Failed to write {OUTPUT}"))?;
+        println!("{OUTPUT} updated");
+    }
+
+    Ok(())
+}
+
+fn repo_root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../..")
+}
+
+/// Returns the contents of `OUTPUT` generated from `SOURCES`.
+fn generate_from_repo() -> Result<String> {
+    let mut sources = Vec::with_capacity(SOURCES.len());
+    for path in SOURCES {
+        let contents =
+            std::fs::read_to_string(repo_root().join(path)).with_context(|| format!("Failed to read {path}"))?;
+        sources.push((path, contents));
+    }
+
+    generate(&sources, &ROOTS)
+}
+
+/// A Rust type definition with a TS counterpart.
+enum Definition {
+    Struct(syn::ItemStruct),
+    Enum(syn::ItemEnum),
+    Alias(syn::ItemType),
+}
+
+/// The serde attributes of a container, a field or a variant that affect the JSON shape.
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    rename_all: Option<String>,
+    /// The value is never serialized.
+    skip: bool,
+    /// The value is not serialized in some cases, e.g. `skip_serializing_if = "Option::is_none"`.
+    optional: bool,
+    flatten: bool,
+}
+
+impl SerdeAttrs {
+    fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut serde = SerdeAttrs::default();
+        for attr in attrs.iter().filter(|v| v.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                let name = meta.path.to_token_stream().to_string();
+                match name.as_str() {
+                    "rename" => serde.rename = Some(meta.value()?.parse::<LitStr>()?.value()),
+                    "rename_all" => serde.rename_all = Some(meta.value()?.parse::<LitStr>()?.value()),
+                    "skip" | "skip_serializing" => serde.skip = true,
+                    "skip_serializing_if" => {
+                        meta.value()?.parse::<LitStr>()?;
+                        serde.optional = true;
+                    }
+                    "flatten" => serde.flatten = true,
+                    // deserialization only
+                    "default" => {
+                        if meta.input.peek(syn::Token![=]) {
+                            meta.value()?.parse::<LitStr>()?;
+                        }
+                    }
+                    "skip_deserializing" => {}
+                    _ => return Err(meta.error(format!("unsupported serde attribute `{name}`"))),
+                }
+                Ok(())
+            })?;
+        }
+
+        match serde.rename_all.as_deref() {
+            None | Some("camelCase") => Ok(serde),
+            Some(v) => bail!("unsupported rename_all = \"{v}\""),
+        }
+    }
+}
+
+/// Returns true if the item has `#[derive(Serialize)]`.
+fn is_serialize(attrs: &[Attribute]) -> bool {
+    attrs.iter().filter(|v| v.path().is_ident("derive")).any(|v| {
+        let mut found = false;
+        let _ = v.parse_nested_meta(|meta| {
+            found |= meta.path.is_ident("Serialize");
+            Ok(())
+        });
+        found
+    })
+}
+
+/// Returns the doc comment lines without the leading space.
+fn doc_lines(attrs: &[Attribute]) -> Vec<String> {
+    attrs
+        .iter()
+        .filter_map(|attr| match &attr.meta {
+            syn::Meta::NameValue(v) if v.path.is_ident("doc") => match &v.value {
+                Expr::Lit(syn::ExprLit { lit: Lit::Str(v), .. }) => Some(v.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|v| v.strip_prefix(' ').unwrap_or(&v).trim_end().to_owned())
+        .collect()
+}
+
+/// Formats the doc comment lines as a TS doc comment with the indentation.
+fn ts_doc(lines: &[String], indent: &str) -> String {
+    match lines {
+        [] => String::new(),
+        [line] => format!("{indent}/** {line} */\n"),
+        _ => {
+            let mut doc = format!("{indent}/**\n");
+            for line in lines {
+                if line.is_empty() {
+                    doc.push_str(&format!("{indent} *\n"));
+                } else {
+                    doc.push_str(&format!("{indent} * {line}\n"));
+                }
+            }
+            doc.push_str(&format!("{indent} */\n"));
+            doc
+        }
+    }
+}
+
+/// Applies serde `rename_all = "camelCase"` to a field or a variant name.
+fn camel_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut upper = false;
+    for (i, c) in name.chars().enumerate() {
+        if c == '_' {
+            upper = i > 0;
+        } else if upper {
+            result.extend(c.to_uppercase());
+            upper = false;
+        } else if i == 0 {
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Returns the name serde gives to the field or the variant.
+fn serde_name(ident: &syn::Ident, attrs: &SerdeAttrs, container: &SerdeAttrs) -> String {
+    if let Some(v) = &attrs.rename {
+        return v.clone();
+    }
+    let name = ident.to_string();
+    let name = name.strip_prefix("r#").unwrap_or(&name);
+    match container.rename_all {
+        Some(_) => camel_case(name),
+        None => name.to_owned(),
+    }
+}
+
+/// Returns the names of the type parameters, lifetimes excluded.
+fn type_params(generics: &syn::Generics) -> Vec<String> {
+    generics.type_params().map(|v| v.ident.to_string()).collect()
+}
+
+/// Formats the type parameters for a TS declaration, e.g. `<T>`.
+fn ts_params(params: &[String]) -> String {
+    match params.is_empty() {
+        true => String::new(),
+        false => format!("<{}>", params.join(", ")),
+    }
+}
+
+/// Returns `T` if the type is `Option<T>`.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let segment = match ty {
+        Type::Path(v) => v.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(v) => match v.args.first()? {
+            GenericArgument::Type(v) => Some(v),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Wraps a union into parentheses for use in an array or an intersection.
+fn parenthesize(ts: String) -> String {
+    match ts.contains(" | ") {
+        true => format!("({ts})"),
+        false => ts,
+    }
+}
+
+/// Converts the definitions reachable from the roots into TS declarations.
+struct Generator<'a> {
+    definitions: &'a HashMap<String, Definition>,
+    /// Definitions waiting to be converted, in the order they were first referenced.
+    queue: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+impl Generator<'_> {
+    /// Adds the definition to the output if it wasn't added before.
+    fn reference(&mut self, name: &str) {
+        if self.seen.insert(name.to_owned()) {
+            self.queue.push_back(name.to_owned());
+        }
+    }
+
+    /// Returns the TS type for the Rust type.
+    /// `params` are the type parameters of the enclosing definition.
+    fn ts_type(&mut self, ty: &Type, params: &[String]) -> Result<String> {
+        let path = match ty {
+            Type::Reference(v) => return self.ts_type(&v.elem, params),
+            Type::Tuple(v) if v.elems.is_empty() => return Ok("null".to_owned()),
+            Type::Path(v) if v.qself.is_none() => &v.path,
+            _ => bail!("unsupported type `{}`", ty.to_token_stream()),
+        };
+
+        let segment = path.segments.last().context("empty type path")?;
+        let name = segment.ident.to_string();
+
+        // serialized by chrono as RFC 3339, whatever the time zone type is
+        if name == "DateTime" {
+            return Ok("string".to_owned());
+        }
+
+        let args = match &segment.arguments {
+            PathArguments::AngleBracketed(v) => v
+                .args
+                .iter()
+                .filter_map(|v| match v {
+                    GenericArgument::Type(v) => Some(v),
+                    _ => None,
+                })
+                .map(|v| self.ts_type(v, params))
+                .collect::<Result<Vec<_>>>()?,
+            PathArguments::None => Vec::new(),
+            PathArguments::Parenthesized(_) => bail!("unsupported type `{}`", ty.to_token_stream()),
+        };
+
+        let ts = match (name.as_str(), args.as_slice()) {
+            ("String" | "str" | "char", []) => "string".to_owned(),
+            ("u8" | "u16" | "u32" | "u64" | "usize" | "i8" | "i16" | "i32" | "i64" | "isize" | "f32" | "f64", []) => {
+                "number".to_owned()
+            }
+            ("bool", []) => "boolean".to_owned(),
+            ("Option", [v]) => format!("{v} | null"),
+            ("Box", [v]) => v.clone(),
+            ("Vec", [v]) => format!("{}[]", parenthesize(v.clone())),
+            ("HashMap" | "BTreeMap", [_, v]) => format!("Record<string, {v}>"),
+            ("Result", [ok, err]) => format!("{{ Ok: {ok} }} | {{ Err: {err} }}"),
+            (v, []) if params.iter().any(|p| p == v) => v.to_owned(),
+            (v, _) if self.definitions.contains_key(v) => {
+                self.reference(v);
+                format!("{v}{}", ts_params(&args))
+            }
+            _ => bail!("unsupported type `{}`", ty.to_token_stream()),
+        };
+
+        Ok(ts)
+    }
+
+    /// Returns the TS object type for the fields, one per line with the indentation.
+    /// Flattened fields are returned separately as the types to intersect with.
+    fn ts_fields(
+        &mut self,
+        fields: &syn::FieldsNamed,
+        container: &SerdeAttrs,
+        params: &[String],
+        indent: &str,
+    ) -> Result<(String, Vec<String>)> {
+        let mut object = String::from("{\n");
+        let mut flattened = Vec::new();
+
+        for field in &fields.named {
+            let attrs = SerdeAttrs::parse(&field.attrs)?;
+            if attrs.skip {
+                continue;
+            }
+
+            let ident = field.ident.as_ref().context("unnamed field")?;
+
+            if attrs.flatten {
+                // a flattened `None` adds no fields
+                match option_inner(&field.ty) {
+                    Some(v) => flattened.push(format!("({} | {{}})", self.ts_type(v, params)?)),
+                    None => flattened.push(parenthesize(self.ts_type(&field.ty, params)?)),
+                }
+                continue;
+            }
+
+            // `Option` fields that are not serialized when empty are optional rather than nullable
+            let ts = match option_inner(&field.ty) {
+                Some(v) if attrs.optional => self.ts_type(v, params)?,
+                _ => self.ts_type(&field.ty, params)?,
+            };
+
+            let name = serde_name(ident, &attrs, container);
+            let question = if attrs.optional { "?" } else { "" };
+            object.push_str(&ts_doc(&doc_lines(&field.attrs), &format!("{indent}  ")));
+            object.push_str(&format!("{indent}  {name}{question}: {ts};\n"));
+        }
+
+        object.push_str(indent);
+        object.push('}');
+
+        Ok((object, flattened))
+    }
+
+    /// Returns the TS declaration for the definition.
+    fn declaration(&mut self, name: &str) -> Result<String> {
+        let definitions = self.definitions;
+        let declaration = match &definitions[name] {
+            Definition::Struct(v) => {
+                let container = SerdeAttrs::parse(&v.attrs)?;
+                let params = type_params(&v.generics);
+                let fields = match &v.fields {
+                    Fields::Named(v) => v,
+                    _ => bail!("only structs with named fields are supported"),
+                };
+                let (object, flattened) = self.ts_fields(fields, &container, &params, "")?;
+
+                let mut declaration = ts_doc(&doc_lines(&v.attrs), "");
+                if flattened.is_empty() {
+                    declaration.push_str(&format!("export interface {name}{} {object}\n", ts_params(&params)));
+                } else {
+                    declaration.push_str(&format!(
+                        "export type {name}{} = {object} & {};\n",
+                        ts_params(&params),
+                        flattened.join(" & ")
+                    ));
+                }
+                declaration
+            }
+
+            // serde's default externally tagged representation
+            Definition::Enum(v) => {
+                let container = SerdeAttrs::parse(&v.attrs)?;
+                let params = type_params(&v.generics);
+
+                let mut variants = Vec::with_capacity(v.variants.len());
+                for variant in &v.variants {
+                    let attrs = SerdeAttrs::parse(&variant.attrs)?;
+                    if attrs.skip {
+                        continue;
+                    }
+                    let tag = serde_name(&variant.ident, &attrs, &container);
+                    let ts = match &variant.fields {
+                        Fields::Unit => format!("\"{tag}\""),
+                        Fields::Unnamed(v) if v.unnamed.len() == 1 => {
+                            format!("{{ {tag}: {} }}", self.ts_type(&v.unnamed[0].ty, &params)?)
+                        }
+                        Fields::Named(v) => {
+                            let (object, _) = self.ts_fields(v, &SerdeAttrs::default(), &params, "  ")?;
+                            format!("{{ {tag}: {object} }}")
+                        }
+                        Fields::Unnamed(_) => bail!("tuple variants are not supported: {name}::{tag}"),
+                    };
+                    variants.push((doc_lines(&variant.attrs), ts));
+                }
+
+                let mut declaration = ts_doc(&doc_lines(&v.attrs), "");
+                declaration.push_str(&format!("export type {name}{} =\n", ts_params(&params)));
+                for (doc, ts) in variants {
+                    declaration.push_str(&ts_doc(&doc, "  "));
+                    declaration.push_str(&format!("  | {ts}\n"));
+                }
+                declaration.push_str("  ;\n");
+                declaration
+            }
+
+            Definition::Alias(v) => {
+                let params = type_params(&v.generics);
+                let ts = self.ts_type(&v.ty, &params)?;
+                let mut declaration = ts_doc(&doc_lines(&v.attrs), "");
+                declaration.push_str(&format!("export type {name}{} = {ts};\n", ts_params(&params)));
+                declaration
+            }
+        };
+
+        Ok(declaration)
+    }
+}
+
+/// Returns the TS declarations for the roots and everything they refer to.
+/// `sources` are pairs of file names and their contents.
+fn generate(sources: &[(&str, String)], roots: &[&str]) -> Result<String> {
+    let mut definitions = HashMap::new();
+    for (path, contents) in sources {
+        let file = syn::parse_file(contents).with_context(|| format!("Failed to parse {path}"))?;
+        for item in file.items {
+            let (name, definition) = match item {
+                Item::Struct(v) if is_serialize(&v.attrs) => (v.ident.to_string(), Definition::Struct(v)),
+                Item::Enum(v) if is_serialize(&v.attrs) => (v.ident.to_string(), Definition::Enum(v)),
+                Item::Type(v) => (v.ident.to_string(), Definition::Alias(v)),
+                _ => continue,
+            };
+            if definitions.insert(name.clone(), definition).is_some() {
+                bail!("{name} is defined more than once");
+            }
+        }
+    }
+
+    let mut generator = Generator {
+        definitions: &definitions,
+        queue: VecDeque::new(),
+        seen: HashSet::new(),
+    };
+    for root in roots {
+        if !definitions.contains_key(*root) {
+            bail!("{root} not found");
+        }
+        generator.reference(root);
+    }
+
+    let mut output = HEADER.to_owned();
+    while let Some(name) = generator.queue.pop_front() {
+        let declaration = generator
+            .declaration(&name)
+            .with_context(|| format!("Failed to convert {name}"))?;
+        output.push('\n');
+        output.push_str(&declaration);
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_file_is_current() {
+        let current = std::fs::read_to_string(repo_root().join(OUTPUT)).unwrap_or_default();
+        assert!(
+            current == generate_from_repo().unwrap(),
+            "{OUTPUT} is out of date. Run `cargo run -p ts-gen` to update it."
+        );
+    }
+
+    #[test]
+    fn serde_attributes() {
+        let source = r#"
+            /// A test struct.
+            #[derive(Serialize)]
+            #[serde(rename_all = "camelCase")]
+            pub struct Envelope<'a> {
+                pub request_id: &'a str,
+                #[serde(skip_serializing_if = "Option::is_none", default)]
+                pub last_error: Option<String>,
+                pub nullable: Option<u64>,
+                #[serde(skip_serializing_if = "Vec::is_empty")]
+                pub items: Vec<Kind>,
+                #[serde(skip)]
+                pub hidden: bool,
+                #[serde(flatten, skip_serializing_if = "Option::is_none")]
+                pub payload: Option<&'a Payload>,
+            }
+
+            #[derive(Serialize)]
+            pub enum Kind {
+                InvalidJson,
+                /// Not a book.
+                Other,
+            }
+
+            #[derive(Serialize)]
+            #[serde(rename_all = "camelCase")]
+            pub enum Payload {
+                LocalBook(Box<Option<Res<String>>>),
+            }
+
+            pub type Res<T> = std::result::Result<T, Kind>;
+
+            #[derive(Serialize)]
+            pub struct Unused {
+                pub value: String,
+            }
+        "#;
+
+        let ts = generate(&[("test.rs", source.to_owned())], &["Envelope"]).unwrap();
+
+        assert_eq!(
+            ts.strip_prefix(HEADER).unwrap(),
+            r#"
+/** A test struct. */
+export type Envelope = {
+  requestId: string;
+  lastError?: string;
+  nullable: number | null;
+  items?: Kind[];
+} & (Payload | {});
+
+export type Kind =
+  | "InvalidJson"
+  /** Not a book. */
+  | "Other"
+  ;
+
+export type Payload =
+  | { localBook: Res<string> | null }
+  ;
+
+export type Res<T> = { Ok: T } | { Err: Kind };
+"#
+        );
+    }
+
+    #[test]
+    fn unsupported_types_fail() {
+        let source = r#"
+            #[derive(Serialize)]
+            pub struct Book {
+                pub isbn: Isbn,
+            }
+        "#;
+
+        assert!(generate(&[("test.rs", source.to_owned())], &["Book"]).is_err());
+    }
+}
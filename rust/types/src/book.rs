@@ -7,7 +7,7 @@ use wasm_bindgen::prelude::*;
 /// Where the reader is with the book.
 /// Defaults to None.
 #[wasm_bindgen]
-#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ReadStatus {
     ToRead = 0,
     Read = 1,
@@ -36,6 +36,26 @@ impl FromStr for ReadStatus {
     }
 }
 
+/// When each of the fields shared with the cloud was last changed.
+/// Fields without a timestamp fall back onto `Book::timestamp_update` if they have a value.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldTimestamps {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authors: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_status: Option<DateTime<Utc>>,
+}
+
+impl FieldTimestamps {
+    /// Returns true if none of the fields have a timestamp.
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.authors.is_none() && self.read_status.is_none()
+    }
+}
+
 /// An internal representation of a book record.
 /// Stored in the local storage and in the cloud.
 /// This struct does not Default implementation to force thinking what attributes go where.
@@ -49,8 +69,22 @@ pub struct Book {
     #[serde(default)]
     pub timestamp_update: DateTime<Utc>,
     /// When the book was last sync'd.
+    /// Cloud records have it set to when the record was last written in the cloud DB.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub timestamp_sync: Option<DateTime<Utc>>,
+    /// When title, authors and read_status were last changed.
+    /// See `merge_fields()`.
+    #[serde(default, skip_serializing_if = "FieldTimestamps::is_empty")]
+    pub field_timestamps: FieldTimestamps,
+    /// The version of the cloud record this copy was last sync'd with.
+    /// The cloud DB rejects writes with a stale version, see `BookSyncResult::conflict`.
+    /// None if the book was never sync'd.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// When the book was deleted.
+    /// Only tombstone records have it. They have no other details and are never shown to the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_delete: Option<DateTime<Utc>>,
     /// Reading status, where the reader is with the book.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub read_status: Option<ReadStatus>,
@@ -87,6 +121,29 @@ impl Book {
         ((isbn.len() == 13 && isbn.starts_with("97")) || isbn.len() == 10) && isbn.parse::<u64>().is_ok()
     }
 
+    /// Converts a 10-digit ISBN into its 13-digit form with 978 prefix.
+    /// 13-digit ISBNs are returned as-is.
+    /// ISBN-10 stored as a number may have lost its leading zeros, so shorter numbers are also accepted.
+    /// Returns None if the number cannot be an ISBN.
+    pub fn to_isbn13(isbn: u64) -> Option<u64> {
+        match isbn {
+            9_780_000_000_000..=9_799_999_999_999 => Some(isbn),
+            1..=9_999_999_999 => {
+                // the ISBN-10 check digit is dropped and a new one is calculated for ISBN-13
+                let isbn = 978_000_000_000 + isbn / 10;
+                let sum = isbn
+                    .to_string()
+                    .chars()
+                    .filter_map(|v| v.to_digit(10))
+                    .enumerate()
+                    .map(|(i, v)| if i % 2 == 0 { v } else { v * 3 })
+                    .sum::<u32>();
+                Some(isbn * 10 + ((10 - sum % 10) % 10) as u64)
+            }
+            _ => None,
+        }
+    }
+
     /// Updates the sync timestamp to the current time
     /// and returns the updated Self.
     pub fn with_new_sync_timestamp(self) -> Self {
@@ -103,6 +160,27 @@ impl Book {
         book
     }
 
+    /// Returns true if the book was never sync'd with the cloud DB
+    /// or was updated locally after the last sync.
+    pub fn needs_sync(&self) -> bool {
+        match self.timestamp_sync {
+            Some(v) => v <= self.timestamp_update,
+            None => true,
+        }
+    }
+
+    /// Returns a tombstone record for the deleted book with timestamp_update=timestamp_delete=now.
+    pub fn new_tombstone(isbn: u64) -> Self {
+        let mut book = Book::new(isbn);
+        book.timestamp_delete = Some(book.timestamp_update);
+        book
+    }
+
+    /// Returns true if this is a tombstone record of a deleted book.
+    pub fn is_deleted(&self) -> bool {
+        self.timestamp_delete.is_some()
+    }
+
     /// Sets ISBN and timestamp_update=now fields.
     /// Use ::is_valid_isbn() to validate the value.
     pub fn new(isbn: u64) -> Self {
@@ -110,6 +188,9 @@ impl Book {
             isbn,
             timestamp_update: Utc::now(),
             timestamp_sync: None,
+            timestamp_delete: None,
+            version: None,
+            field_timestamps: FieldTimestamps::default(),
             read_status: None,
             cover: None,
             title: None,
@@ -123,17 +204,14 @@ impl Book {
 
     /// Adds a new photo to the list of photos and returns the updated Self.
     /// Photos are sorted by ID, which is a timestamp.
-    /// The share field is set to the photo ID if it's the first uploaded photo
-    /// and no share value exists.
+    /// The share field is set to the timestamp of the photo ID if it's the first uploaded photo
+    /// and no share value exists, see `share_id_from_photo_id`.
     /// Share value can be overwritten by the value from the cloud.
     pub fn with_new_photo(self, photo_id: String) -> Self {
         // share can only be set once
         let share_id = match self.share_id {
             Some(v) => Some(v),
-            None => match photo_id.parse::<u64>() {
-                Ok(n) => Some(n),
-                Err(_) => None,
-            },
+            None => crate::share_id_from_photo_id(&photo_id),
         };
 
         // add the photo to the list and sort in the chronological order
@@ -164,6 +242,7 @@ impl Book {
 
     /// Returns a list of photo URLs for the user and the ISBN based on the photo IDs.
     /// e.g. https://bookworm.im/photos/8cbf509d254774a13ede02ce246d39434950c93aa328407e7fef657d2bb6f737-9780143107712-23520065.jpg
+    /// The extension comes from the photo ID for formats other than JPEG, see `PhotoFormat`.
     pub fn hydrate_photos(user_id: &str, isbn: u64, photos: Vec<String>) -> Vec<String> {
         // build the front-end part of the URL
         let front_part = [
@@ -179,7 +258,7 @@ impl Book {
         // loop thru all photos to build the URLs
         photos
             .into_iter()
-            .map(|v| [front_part.to_owned(), v, crate::USER_PHOTOS_S3_SUFFIX.to_owned()].concat())
+            .map(|v| [front_part.to_owned(), crate::photo_file_name(&v)].concat())
             .collect()
     }
 
@@ -188,30 +267,240 @@ impl Book {
         self.title.is_none() || self.authors.is_none() || self.volume_info.is_none()
     }
 
-    /// Copies Title and Authors from the cloud book if there is no local data.
-    /// Copies the list of photos from the cloud.
-    /// Uses the latest timestamp_update out of the two.
-    /// Keeps the local status and all other details.
-    pub fn merge_from_cloud(&mut self, other: &Self) {
-        // compile a more complete version of the book
-        // since book details come from the same source the precedence should be given to the local data
+    /// Sets the reading status and records when it was changed.
+    pub fn set_read_status(&mut self, status: Option<ReadStatus>) {
+        let now = Utc::now();
+        self.read_status = status;
+        self.field_timestamps.read_status = Some(now);
+        self.timestamp_update = now;
+    }
 
-        self.timestamp_update = if self.timestamp_update > other.timestamp_update {
-            self.timestamp_update
-        } else {
-            other.timestamp_update
-        };
+    /// Merges the cloud copy of the book into this one with `merge_fields()`.
+    /// Copies the list of photos, the share ID and the version from the cloud.
+    /// Keeps all other local details.
+    /// Returns true if anything changed.
+    pub fn merge_from_cloud(&mut self, other: &Self) -> bool {
+        let before = (
+            self.title.clone(),
+            self.authors.clone(),
+            self.read_status,
+            self.field_timestamps,
+            self.timestamp_update,
+            self.photos.clone(),
+            self.share_id,
+            self.version,
+        );
+
+        self.merge_fields(other);
 
-        if self.title.is_none() {
-            self.title = other.title.clone()
-        };
-        if self.authors.is_none() {
-            self.authors = other.authors.clone()
-        };
         // photos in the cloud are always more authoritative than the local state
         self.photos = other.photos.clone();
         // this is set when the first photo is uploaded
         // the value persists even if the photo was deleted
         self.share_id = other.share_id;
+        // the next write has to be based on the cloud record
+        self.version = other.version;
+
+        before
+            != (
+                self.title.clone(),
+                self.authors.clone(),
+                self.read_status,
+                self.field_timestamps,
+                self.timestamp_update,
+                self.photos.clone(),
+                self.share_id,
+                self.version,
+            )
+    }
+
+    /// Resolves title, authors and read_status field by field: the latest change wins.
+    /// Ties are broken by the value, so merging copies in any order gives the same result.
+    /// Uses the latest timestamp_update out of the two.
+    pub fn merge_fields(&mut self, other: &Self) {
+        self.field_timestamps.title = merge_field(
+            &mut self.title,
+            self.field_timestamps.title,
+            self.timestamp_update,
+            &other.title,
+            other.field_timestamps.title,
+            other.timestamp_update,
+        );
+        self.field_timestamps.authors = merge_field(
+            &mut self.authors,
+            self.field_timestamps.authors,
+            self.timestamp_update,
+            &other.authors,
+            other.field_timestamps.authors,
+            other.timestamp_update,
+        );
+        self.field_timestamps.read_status = merge_field(
+            &mut self.read_status,
+            self.field_timestamps.read_status,
+            self.timestamp_update,
+            &other.read_status,
+            other.field_timestamps.read_status,
+            other.timestamp_update,
+        );
+
+        self.timestamp_update = self.timestamp_update.max(other.timestamp_update);
+    }
+
+    /// Returns true if title, authors and read_status are the same in both copies.
+    pub fn same_cloud_fields(&self, other: &Self) -> bool {
+        self.title == other.title && self.authors == other.authors && self.read_status == other.read_status
+    }
+}
+
+/// Last-writer-wins for a single field.
+/// A field with a value and no timestamp of its own was last changed at `timestamp_update` or earlier.
+/// An empty field with no timestamp was never set and loses to any other value.
+/// Returns the timestamp of the winning value, which is always set explicitly to keep the merge stable.
+fn merge_field<T: Clone + PartialOrd>(
+    value: &mut Option<T>,
+    timestamp: Option<DateTime<Utc>>,
+    timestamp_update: DateTime<Utc>,
+    other_value: &Option<T>,
+    other_timestamp: Option<DateTime<Utc>>,
+    other_timestamp_update: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let timestamp = timestamp.or(value.is_some().then_some(timestamp_update));
+    let other_timestamp = other_timestamp.or(other_value.is_some().then_some(other_timestamp_update));
+
+    if (other_timestamp, other_value) > (timestamp, &*value) {
+        *value = other_value.clone();
+        other_timestamp
+    } else {
+        timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// The number of random cases per property.
+    const CASES: usize = 2_000;
+
+    /// A few distinct timestamps to make ties likely.
+    fn random_time(rng: &mut fastrand::Rng) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_704_067_200 + rng.i64(0..4) * 60, 0).unwrap()
+    }
+
+    fn random_option<T>(rng: &mut fastrand::Rng, f: impl FnOnce(&mut fastrand::Rng) -> T) -> Option<T> {
+        if rng.bool() {
+            Some(f(rng))
+        } else {
+            None
+        }
+    }
+
+    fn random_book(rng: &mut fastrand::Rng) -> Book {
+        let mut book = Book::new(9780143107712);
+        book.timestamp_update = random_time(rng);
+        book.title = random_option(rng, |rng| ["Dune", "Emma", ""][rng.usize(0..3)].to_string());
+        book.authors = random_option(rng, |rng| vec![["Herbert", "Austen"][rng.usize(0..2)].to_string()]);
+        book.read_status = random_option(rng, |rng| {
+            [ReadStatus::ToRead, ReadStatus::Read, ReadStatus::Liked][rng.usize(0..3)]
+        });
+        book.field_timestamps = FieldTimestamps {
+            title: random_option(rng, random_time),
+            authors: random_option(rng, random_time),
+            read_status: random_option(rng, random_time),
+        };
+        book
+    }
+
+    fn merged(a: &Book, b: &Book) -> Book {
+        let mut a = a.clone();
+        a.merge_fields(b);
+        a
+    }
+
+    /// The parts of the book the merge is responsible for.
+    type MergedFields = (
+        Option<String>,
+        Option<Vec<String>>,
+        Option<ReadStatus>,
+        FieldTimestamps,
+        DateTime<Utc>,
+    );
+
+    fn fields(book: &Book) -> MergedFields {
+        (
+            book.title.clone(),
+            book.authors.clone(),
+            book.read_status,
+            book.field_timestamps,
+            book.timestamp_update,
+        )
+    }
+
+    #[test]
+    fn merge_is_commutative() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        for _ in 0..CASES {
+            let (a, b) = (random_book(&mut rng), random_book(&mut rng));
+            assert_eq!(fields(&merged(&a, &b)), fields(&merged(&b, &a)), "{a:?}\n{b:?}");
+        }
+    }
+
+    #[test]
+    fn merge_is_associative() {
+        let mut rng = fastrand::Rng::with_seed(2);
+        for _ in 0..CASES {
+            let (a, b, c) = (random_book(&mut rng), random_book(&mut rng), random_book(&mut rng));
+            assert_eq!(
+                fields(&merged(&merged(&a, &b), &c)),
+                fields(&merged(&a, &merged(&b, &c))),
+                "{a:?}\n{b:?}\n{c:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        let mut rng = fastrand::Rng::with_seed(3);
+        for _ in 0..CASES {
+            let (a, b) = (random_book(&mut rng), random_book(&mut rng));
+            let ab = merged(&a, &b);
+            assert_eq!(fields(&merged(&ab, &b)), fields(&ab), "{a:?}\n{b:?}");
+            assert_eq!(fields(&merged(&ab, &ab)), fields(&ab), "{a:?}\n{b:?}");
+        }
+    }
+
+    #[test]
+    fn latest_status_wins() {
+        let mut local = Book::new(9780143107712);
+        local.set_read_status(Some(ReadStatus::ToRead));
+        let mut cloud = local.clone();
+        cloud.set_read_status(Some(ReadStatus::Liked));
+
+        assert!(local.merge_from_cloud(&cloud));
+        assert_eq!(local.read_status, Some(ReadStatus::Liked));
+        assert!(!local.merge_from_cloud(&cloud));
+    }
+
+    #[test]
+    fn cleared_status_wins_if_newer() {
+        let mut local = Book::new(9780143107712);
+        local.set_read_status(Some(ReadStatus::Read));
+        let mut cloud = local.clone();
+        cloud.set_read_status(None);
+
+        local.merge_fields(&cloud);
+        assert_eq!(local.read_status, None);
+    }
+
+    #[test]
+    fn first_photo_sets_share_id() {
+        let book = Book::new(9780143107712).with_new_photo("23520065.png".to_owned());
+        assert_eq!(book.share_id, Some(23520065));
+        assert_eq!(book.photos, Some(vec!["23520065.png".to_owned()]));
+
+        // share can only be set once
+        let book = book.with_new_photo("23520066".to_owned());
+        assert_eq!(book.share_id, Some(23520065));
     }
 }
@@ -40,6 +40,18 @@ pub struct User {
 /// otherwise returns None.
 /// All errors are logged inside the function.
 pub fn get_user_details(token: &Option<crate::IdToken>) -> Option<User> {
+    decode_user_details(token, true)
+}
+
+/// Same as `get_user_details`, but accepts expired tokens.
+/// Only use it where the user ID is needed for partitioning the local data,
+/// e.g. local storage keys, and not for authorizing access to the server side.
+pub fn get_user_details_ignoring_expiry(token: &Option<crate::IdToken>) -> Option<User> {
+    decode_user_details(token, false)
+}
+
+/// The token decoding logic shared by `get_user_details` and `get_user_details_ignoring_expiry`.
+fn decode_user_details(token: &Option<crate::IdToken>, validate_exp: bool) -> Option<User> {
     let token = match token {
         Some(v) => v,
         None => {
@@ -78,7 +90,7 @@ pub fn get_user_details(token: &Option<crate::IdToken>) -> Option<User> {
     let validation = {
         let mut validation = Validation::new(header.alg);
         validation.set_audience(&[AUDIENCE]);
-        validation.validate_exp = true;
+        validation.validate_exp = validate_exp;
         validation
     };
 
@@ -6,9 +6,21 @@ pub const USER_BOOKS_TABLE_NAME: &str = "user_books";
 /// An index for finding the user ID for a particular share ID.
 pub const USER_BOOKS_SHARE_INDEX_NAME: &str = "share-isbn-index";
 
+/// An index for finding the records of a user written after a point in time.
+/// Partition key: `uid`, sort key: `synced`. All attributes are projected.
+pub const USER_BOOKS_SYNCED_INDEX_NAME: &str = "uid-synced-index";
+
 /// The list of field names in `USER_BOOKS_TABLE_NAME` table.
 pub mod user_books_table_fields {
     pub const AUTHORS: &str = "authors";
+    /// When the authors were last changed on the client.
+    pub const AUTHORS_UPDATED: &str = "authors_updated";
+    /// When the book was deleted by the user.
+    /// Only tombstone records have this field, see `TOMBSTONE_RETENTION_DAYS`.
+    pub const DELETED: &str = "deleted";
+    /// When DynamoDB removes the tombstone record, in Unix epoch seconds.
+    /// It is the TTL attribute of the table. Only tombstone records have this field.
+    pub const EXPIRES: &str = "expires";
     /// The user email address from the JWT.
     /// Should never be returned to the caller.
     pub const EMAIL: &str = "email";
@@ -18,33 +30,72 @@ pub mod user_books_table_fields {
     pub const PHOTO_IDS: &str = "photo_ids";
     /// Where the reader is with the book.
     pub const READ_STATUS: &str = "read_status";
+    /// When the read status was last changed on the client.
+    pub const READ_STATUS_UPDATED: &str = "read_status_updated";
+    /// When the record was last written to the table, in the server time.
+    /// It is the sort key of `USER_BOOKS_SYNCED_INDEX_NAME` for incremental syncs.
+    pub const SYNCED: &str = "synced";
     pub const TITLE: &str = "title";
+    /// When the title was last changed on the client.
+    pub const TITLE_UPDATED: &str = "title_updated";
     /// Partition key: user ID.
     pub const UID: &str = "uid";
-    /// When the record was last updated.
+    /// When the book was last updated on the client.
     pub const UPDATED: &str = "updated";
+    /// A number incremented with every write for optimistic concurrency.
+    /// Writes from clients with a different version are rejected.
+    pub const VERSION: &str = "version";
     /// A timestamp of the very first photo uploaded into the book
     /// `share` is a reserved keyword in DDB and must be escaped.
     pub const SHARE_ID: &str = "share";
 }
 
+/// The max length of a request ID accepted from the client, see `get_request_id`.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
 /// Initializes the tracing subscriber for CloudWatch or local logging.
 /// - CloudWatch: compact format with x-ray data at the end
 /// - Local: no time, ANSI color
+///
+/// Log lines written inside `request_span` include the request ID for matching them with the browser logs.
 #[cfg(not(target_arch = "wasm32"))]
 pub fn init_tracing_subscriber() {
-        // this init is required to enable CloudWatch error logging by the runtime
-        #[cfg(debug_assertions)] // a more compact format for local debugging
-        tracing_subscriber::fmt()
-            .without_time()
-            .with_max_level(LevelFilter::INFO)
-            .with_ansi(true)
-            .init();
-    
-        #[cfg(not(debug_assertions))]
-        tracing_subscriber::fmt() // CloudWatch-friendly format
-            .with_max_level(LevelFilter::INFO)
-            .with_ansi(false) 
-            .compact() // puts x-ray data at the end
-            .init();
-}
\ No newline at end of file
+    // this init is required to enable CloudWatch error logging by the runtime
+    #[cfg(debug_assertions)] // a more compact format for local debugging
+    tracing_subscriber::fmt()
+        .without_time()
+        .with_max_level(LevelFilter::INFO)
+        .with_ansi(true)
+        .init();
+
+    #[cfg(not(debug_assertions))]
+    tracing_subscriber::fmt() // CloudWatch-friendly format
+        .with_max_level(LevelFilter::INFO)
+        .with_ansi(false)
+        .compact() // puts x-ray data at the end
+        .init();
+}
+
+/// Returns the request ID the client sent in `REQUEST_ID_HEADER` or the ID of the lambda invocation if there is none.
+/// Values that are too long or have characters other than ASCII alphanumerics and `-` are replaced
+/// with the invocation ID to keep the logs clean.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get_request_id(header: Option<&str>, invocation_id: &str) -> String {
+    match header {
+        Some(v)
+            if !v.is_empty()
+                && v.len() <= MAX_REQUEST_ID_LEN
+                && v.chars().all(|v| v.is_ascii_alphanumeric() || v == '-') =>
+        {
+            v.to_owned()
+        }
+        _ => invocation_id.to_owned(),
+    }
+}
+
+/// Returns a span for the handler to run in, so that all its log lines have the request ID attached.
+/// See `get_request_id`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn request_span(request_id: &str) -> tracing::Span {
+    tracing::info_span!("request", id = request_id)
+}
@@ -1,4 +1,5 @@
-pub use book::{Book, ReadStatus};
+pub use book::{Book, FieldTimestamps, ReadStatus};
+pub use photo::{photo_file_name, photo_id_from_file_name, share_id_from_photo_id, PhotoFormat};
 use serde::{Deserialize, Serialize};
 
 mod book;
@@ -6,6 +7,7 @@ pub mod google;
 pub mod jwt;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod lambda;
+mod photo;
 
 /// All error handling in this crate is based on either retrying a request after some time
 /// or exiting gracefully.
@@ -24,12 +26,35 @@ pub type IdToken = String;
 /// The name of the authorisation header containing the ID token with the user email.
 pub const AUTH_HEADER: &str = "x-books-authorization";
 
+/// The name of the header with the ID of the request for matching the browser logs with the lambda logs.
+/// The client generates a new ID for every request. The lambdas echo it back in the response.
+pub const REQUEST_ID_HEADER: &str = "x-books-request-id";
+
 /// Value: `isbn`. The URL parameter name for ISBN.
 pub const ISBN_URL_PARAM_NAME: &str = "isbn";
 
 /// Value: `isbn`. The URL parameter name for ISBN.
 pub const SHARE_ID_URL_PARAM_NAME: &str = "share_id";
 
+/// Value: `since`. The URL parameter name for an RFC3339 timestamp
+/// to get only the records changed at or after that time.
+pub const SINCE_URL_PARAM_NAME: &str = "since";
+
+/// Value: `page`. The URL parameter name for the cursor of the next page of records,
+/// see `BooksPage::next_page`.
+pub const PAGE_URL_PARAM_NAME: &str = "page";
+
+/// Value: `count`. The URL parameter name for the number of presigned photo upload URLs
+/// to return in one response, see `MAX_PHOTOS_PER_UPLOAD`.
+pub const PHOTO_COUNT_URL_PARAM_NAME: &str = "count";
+
+/// The name of the URL param with the comma-separated content types of the photos, one per photo, e.g. `image/png`.
+/// The photos are presigned as JPEG if the param is missing.
+pub const PHOTO_TYPE_URL_PARAM_NAME: &str = "type";
+
+/// The max number of presigned photo upload URLs the client can request at once.
+pub const MAX_PHOTOS_PER_UPLOAD: usize = 10;
+
 /// The domain name that is allowed to use the ID token.
 /// Normally it would be our own domain name where all the server functions are hosted.
 pub const TRUSTED_URLS: &str = "https://bookworm.im";
@@ -55,12 +80,18 @@ pub const USER_PHOTOS_BUCKET_NAME: &str = "bookworm-photos";
 /// Value: `photos/`.
 pub const USER_PHOTOS_S3_PREFIX: &str = "photos/";
 
-/// The file type of the user photos: .jpg
+/// The file type of JPEG user photos: .jpg
+/// Other formats are listed in `PhotoFormat`.
 pub const USER_PHOTOS_S3_SUFFIX: &str = ".jpg";
 
+/// How long the cloud DB keeps the records of deleted books for other devices to pick up the deletion.
+/// The records are removed by DynamoDB TTL, see `user_books_table_fields::EXPIRES`.
+/// Devices that have not sync'd for longer than that have to download the full list.
+pub const TOMBSTONE_RETENTION_DAYS: i64 = 90;
+
 /// Timestamp for 1 Jan 2024.
 /// All photo timestamps have this part subtracted because it is constant.
-pub const TIMESTAMP_BASE:u64 = 1_704_067_200;
+pub const TIMESTAMP_BASE: u64 = 1_704_067_200;
 
 /// A list of book records.
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -96,3 +127,41 @@ impl Books {
         }
     }
 }
+
+/// The outcome of saving a single book sent to the sync endpoint as part of `Books`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BookSyncResult {
+    pub isbn: u64,
+    /// True if the book was saved in the cloud DB.
+    pub saved: bool,
+    /// The reason the book was not saved.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+    /// The version of the cloud record after the write, or the current version if there was a conflict.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<u64>,
+    /// The current cloud record if the book was not saved because it was based on a stale version.
+    /// The client should merge it into its copy and try again.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub conflict: Option<Box<Book>>,
+}
+
+/// A page of book records returned by the sync endpoint.
+/// It has the same format as `Books` with the cursor of the next page added.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BooksPage {
+    pub books: Vec<Book>,
+    /// An opaque cursor to request the next page with `PAGE_URL_PARAM_NAME`.
+    /// None if this is the last page.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub next_page: Option<String>,
+}
+
+/// The response of the sync endpoint to a `Books` payload with one result per ISBN.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BookSyncResults {
+    pub results: Vec<BookSyncResult>,
+}
@@ -0,0 +1,109 @@
+use crate::USER_PHOTOS_S3_SUFFIX;
+
+/// The image formats accepted for user photos.
+/// JPEG photos keep the original naming: the photo ID has no extension and the S3 key ends with `.jpg`,
+/// e.g. `23520065`. Photos in other formats have the extension in the photo ID, e.g. `23520065.png`.
+/// HEIC is not accepted because most browsers cannot display it and the originals carry the location in EXIF.
+/// The client converts HEIC photos to JPEG or rejects them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhotoFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl PhotoFormat {
+    /// The value for `Content-Type` header of the upload and of the S3 object.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PhotoFormat::Jpeg => "image/jpeg",
+            PhotoFormat::Png => "image/png",
+            PhotoFormat::Webp => "image/webp",
+        }
+    }
+
+    /// The file extension of the S3 object with the leading dot.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            PhotoFormat::Jpeg => USER_PHOTOS_S3_SUFFIX,
+            PhotoFormat::Png => ".png",
+            PhotoFormat::Webp => ".webp",
+        }
+    }
+
+    /// Returns the format for a MIME type, e.g. `image/png`.
+    /// The non-standard `image/jpg` is accepted as well.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type.trim().to_ascii_lowercase().as_str() {
+            "image/jpeg" | "image/jpg" => Some(PhotoFormat::Jpeg),
+            "image/png" => Some(PhotoFormat::Png),
+            "image/webp" => Some(PhotoFormat::Webp),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the photo ID for the file name part of the S3 key, e.g. `23520065.jpg` -> `23520065`
+/// or `23520065.png` -> `23520065.png`.
+/// Returns None if the name is not a timestamp with one of the `PhotoFormat` suffixes.
+pub fn photo_id_from_file_name(file_name: &str) -> Option<String> {
+    let (timestamp, suffix) = file_name.split_at(file_name.find('.')?);
+    if timestamp.is_empty() || !timestamp.bytes().all(|v| v.is_ascii_digit()) {
+        return None;
+    }
+
+    if suffix == USER_PHOTOS_S3_SUFFIX {
+        return Some(timestamp.to_owned());
+    }
+
+    // other formats keep the extension in the photo ID
+    [PhotoFormat::Png, PhotoFormat::Webp]
+        .iter()
+        .any(|v| v.suffix() == suffix)
+        .then(|| file_name.to_owned())
+}
+
+/// The reverse of `photo_id_from_file_name`: adds `.jpg` to the photo IDs without an extension.
+pub fn photo_file_name(photo_id: &str) -> String {
+    if photo_id.contains('.') {
+        photo_id.to_owned()
+    } else {
+        [photo_id, USER_PHOTOS_S3_SUFFIX].concat()
+    }
+}
+
+/// Returns the share ID for a photo ID, which is the timestamp without the extension,
+/// e.g. `23520065` or `23520065.png` -> `23520065`.
+pub fn share_id_from_photo_id(photo_id: &str) -> Option<u64> {
+    photo_id.split('.').next()?.parse::<u64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn photo_ids() {
+        assert_eq!(photo_id_from_file_name("23520065.jpg"), Some("23520065".to_owned()));
+        assert_eq!(photo_id_from_file_name("23520065.png"), Some("23520065.png".to_owned()));
+        assert_eq!(photo_id_from_file_name("23520065.gif"), None);
+        assert_eq!(photo_id_from_file_name("23520065.heic"), None);
+        assert_eq!(photo_id_from_file_name("photo.png"), None);
+        assert_eq!(photo_id_from_file_name("23520065"), None);
+
+        assert_eq!(photo_file_name("23520065"), "23520065.jpg");
+        assert_eq!(photo_file_name("23520065.webp"), "23520065.webp");
+
+        assert_eq!(share_id_from_photo_id("23520065"), Some(23520065));
+        assert_eq!(share_id_from_photo_id("23520065.png"), Some(23520065));
+        assert_eq!(share_id_from_photo_id("photo.png"), None);
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(PhotoFormat::from_content_type("image/jpg"), Some(PhotoFormat::Jpeg));
+        assert_eq!(PhotoFormat::from_content_type("image/WEBP"), Some(PhotoFormat::Webp));
+        assert_eq!(PhotoFormat::from_content_type("image/gif"), None);
+        assert_eq!(PhotoFormat::from_content_type("image/heic"), None);
+    }
+}
@@ -0,0 +1,409 @@
+use crate::book;
+use crate::books;
+use crate::platform::Platform;
+use crate::storage::{get_local_storage, KeyValueStore};
+use anyhow::{bail, Result};
+use bookworm_types::{Book, FieldTimestamps, IdToken};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::FileList;
+
+/// Identifies the backup file as ours.
+const LIBRARY_EXPORT_FORMAT: &str = "bookworm-library";
+
+/// Bump this value if the structure of the backup file changes.
+/// Older versions should still be importable.
+pub(crate) const LIBRARY_EXPORT_VERSION: u32 = 1;
+
+/// The contents of a library backup file.
+/// It is sent to the UI as `WasmResponse::LibraryExport` for saving into a file
+/// and is read back by `import_library()`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryExport {
+    /// Always `bookworm-library`.
+    pub format: String,
+    /// See `LIBRARY_EXPORT_VERSION`.
+    pub version: u32,
+    /// When the file was created.
+    pub exported: DateTime<Utc>,
+    /// Complete book records as they are stored locally, including photo IDs and Google Books data.
+    pub books: Vec<Book>,
+}
+
+/// The outcome of `import_library()`.
+/// Sent to the UI as `WasmResponse::LibraryImport`.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    /// True if the local library was cleared before the import.
+    pub replace: bool,
+    /// The number of books saved into the local storage.
+    pub imported: usize,
+    /// The number of books skipped because the local copy was newer.
+    pub skipped: usize,
+    /// Records that failed validation with the reason.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub invalid: Vec<String>,
+    /// Valid records that could not be saved into the local storage with the reason, e.g. a full disk.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<String>,
+    /// The number of local books removed because they were not in the backup. Only in replace mode.
+    pub removed: usize,
+    /// ISBNs of the local books with changes that had not reached the cloud when the library was replaced.
+    /// Those changes are lost and the UI should tell the user about them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unsynced: Vec<u64>,
+}
+
+/// The error of `import` if the file is not a valid backup, as opposed to a failure of the local storage.
+#[derive(Debug)]
+pub(crate) struct InvalidBackup(pub String);
+
+impl fmt::Display for InvalidBackup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid backup file: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidBackup {}
+
+/// Returns all book records of the user from the token in the backup file format.
+/// Records that would not be shown to the user are not exported.
+pub(crate) async fn export(runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<LibraryExport> {
+    let books = books::get(runtime, id_token).await?;
+    log!("Exporting {} books", books.books.len());
+
+    Ok(LibraryExport {
+        format: LIBRARY_EXPORT_FORMAT.to_owned(),
+        version: LIBRARY_EXPORT_VERSION,
+        exported: Utc::now(),
+        books: books.books,
+    })
+}
+
+/// Restores the library from the first file in the list, see `import_backup`.
+pub(crate) async fn import(
+    runtime: &impl Platform,
+    files: FileList,
+    replace: bool,
+    id_token: &Option<IdToken>,
+) -> Result<ImportReport> {
+    let file = match files.item(0) {
+        Some(v) => v,
+        None => return Err(InvalidBackup("no file to import".to_owned()).into()),
+    };
+
+    log!("Importing library from {} / {} bytes", file.name(), file.size());
+
+    let contents = match JsFuture::from(file.text()).await {
+        Ok(v) => match v.as_string() {
+            Some(v) => v,
+            None => return Err(InvalidBackup("not a text file".to_owned()).into()),
+        },
+        Err(e) => bail!("Failed to read the backup file: {:?}", e),
+    };
+
+    import_backup(runtime, &contents, replace, id_token).await
+}
+
+/// Restores the library from the contents of a backup file.
+/// - replace = true: the local books of the user are replaced with the imported ones
+/// - replace = false: the imported books are merged in, the most recently updated copy wins
+///
+/// The imported books are marked as not sync'd. In replace mode the local books that were sync'd
+/// and are not in the backup are replaced with tombstones, so the next sync deletes them in the cloud too,
+/// and the imported books replace the cloud copies, see `replacement`.
+/// Local changes that had not reached the cloud are lost, see `ImportReport::unsynced`.
+/// Returns `InvalidBackup` if the file cannot be imported. Nothing is changed in that case.
+async fn import_backup(
+    runtime: &impl Platform,
+    contents: &str,
+    replace: bool,
+    id_token: &Option<IdToken>,
+) -> Result<ImportReport> {
+    let backup = match serde_json::from_str::<LibraryExport>(contents) {
+        Ok(v) => v,
+        Err(e) => return Err(InvalidBackup(e.to_string()).into()),
+    };
+
+    if backup.format != LIBRARY_EXPORT_FORMAT {
+        return Err(InvalidBackup(format!("unknown format {}", backup.format)).into());
+    }
+    if backup.version > LIBRARY_EXPORT_VERSION {
+        return Err(InvalidBackup(format!("unsupported version {}", backup.version)).into());
+    }
+
+    let mut report = ImportReport {
+        replace,
+        ..Default::default()
+    };
+
+    // validate before changing anything, so a bad file does not wipe the library in replace mode
+    let mut imported_books = Vec::with_capacity(backup.books.len());
+    for book in backup.books {
+        match books::validate(&book.isbn.to_string(), &book) {
+            Ok(()) => imported_books.push(book),
+            Err(e) => {
+                log!("Invalid imported record: {e}");
+                report.invalid.push(e);
+            }
+        }
+    }
+
+    if imported_books.is_empty() {
+        return Err(InvalidBackup("no valid books".to_owned()).into());
+    }
+
+    let ls = get_local_storage(runtime, id_token).await?;
+
+    // local books to compare the imported ones against or to take the cloud version from
+    let local_books = books::get(runtime, id_token)
+        .await?
+        .books
+        .into_iter()
+        .map(|v| (v.isbn, v))
+        .collect::<HashMap<_, _>>();
+
+    if replace {
+        let imported_isbns = imported_books.iter().map(|v| v.isbn).collect::<HashSet<_>>();
+        for book in local_books.values() {
+            if book.needs_sync() {
+                report.unsynced.push(book.isbn);
+            }
+
+            // the imported copy overwrites the local one below
+            if imported_isbns.contains(&book.isbn) {
+                continue;
+            }
+
+            // only the books that reached the cloud need a tombstone to be deleted there
+            if book.version.is_some() {
+                if let Err(e) = book::delete(runtime, &book.isbn.to_string(), id_token).await {
+                    log!("Failed to delete {} before import: {:?}", book.isbn, e);
+                    continue;
+                }
+            } else if let Err(e) = ls.remove_item(&book.isbn.to_string()).await {
+                log!("Failed to remove {} before import: {:?}", book.isbn, e);
+                continue;
+            }
+            report.removed += 1;
+        }
+        report.unsynced.sort();
+    }
+
+    for book in imported_books {
+        let local_book = local_books.get(&book.isbn);
+        let book = if replace {
+            replacement(book, local_book.and_then(|v| v.version))
+        } else if local_book.is_some_and(|v| v.timestamp_update >= book.timestamp_update) {
+            report.skipped += 1;
+            continue;
+        } else {
+            book
+        };
+
+        let book = book.without_sync_timestamp();
+        match book::save(&book, runtime, id_token).await {
+            Ok(()) => report.imported += 1,
+            Err(e) => report.failed.push(format!("Failed to save {}: {:?}", book.isbn, e)),
+        }
+    }
+
+    log!(
+        "Imported: {}, skipped: {}, invalid: {}, failed: {}, removed: {}, unsynced: {}",
+        report.imported,
+        report.skipped,
+        report.invalid.len(),
+        report.failed.len(),
+        report.removed,
+        report.unsynced.len()
+    );
+
+    Ok(report)
+}
+
+/// Makes the imported copy of the book the latest change of all its fields.
+/// The version of the backup is likely stale, so the upload gets a version conflict if the cloud record
+/// changed after the export. The conflict is merged field by field and the imported fields win.
+/// `version` is the one of the local copy, which is the latest known version of the cloud record.
+fn replacement(book: Book, version: Option<u64>) -> Book {
+    let now = Utc::now();
+    let mut book = book;
+    book.version = version;
+    book.timestamp_update = now;
+    book.field_timestamps = FieldTimestamps {
+        title: Some(now),
+        authors: Some(now),
+        read_status: Some(now),
+    };
+    book
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::MockPlatform;
+    use chrono::TimeDelta;
+    use futures::executor::block_on;
+
+    const ISBN_A: u64 = 9780143107712;
+    const ISBN_B: u64 = 9781761186769;
+    const ISBN_C: u64 = 9781761186776;
+
+    fn token() -> Option<IdToken> {
+        Some("test-token".to_owned())
+    }
+
+    fn new_book(isbn: u64, title: &str) -> Book {
+        let mut book = Book::new(isbn);
+        book.title = Some(title.to_owned());
+        book
+    }
+
+    /// A book that was sync'd with the cloud and has not changed since.
+    fn synced_book(isbn: u64, title: &str) -> Book {
+        let mut book = new_book(isbn, title).with_new_sync_timestamp();
+        book.version = Some(1);
+        book
+    }
+
+    fn save_local(platform: &MockPlatform, book: &Book) {
+        platform.set_user_item(&book.isbn.to_string(), &serde_json::to_string(book).unwrap());
+    }
+
+    fn get_local(platform: &MockPlatform, isbn: u64) -> Option<Book> {
+        platform
+            .get_user_item(&isbn.to_string())
+            .map(|v| serde_json::from_str::<Book>(&v).unwrap())
+    }
+
+    fn backup(books: Vec<Book>) -> String {
+        serde_json::to_string(&LibraryExport {
+            format: LIBRARY_EXPORT_FORMAT.to_owned(),
+            version: LIBRARY_EXPORT_VERSION,
+            exported: Utc::now(),
+            books,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn merge_keeps_newer_local_books() {
+        let platform = MockPlatform::default();
+        save_local(&platform, &synced_book(ISBN_A, "Local"));
+        let mut old_book = new_book(ISBN_B, "Old local");
+        old_book.timestamp_update -= TimeDelta::days(1);
+        save_local(&platform, &old_book);
+
+        let mut stale_backup = new_book(ISBN_A, "Backup");
+        stale_backup.timestamp_update -= TimeDelta::days(1);
+        let contents = backup(vec![
+            stale_backup,
+            new_book(ISBN_B, "Backup"),
+            new_book(ISBN_C, "Backup"),
+        ]);
+        let report = block_on(import_backup(&platform, &contents, false, &token())).unwrap();
+
+        assert_eq!((report.imported, report.skipped, report.removed), (2, 1, 0));
+        assert!(report.unsynced.is_empty());
+        assert_eq!(get_local(&platform, ISBN_A).unwrap().title.as_deref(), Some("Local"));
+        assert_eq!(get_local(&platform, ISBN_B).unwrap().title.as_deref(), Some("Backup"));
+        assert!(get_local(&platform, ISBN_C).unwrap().needs_sync());
+    }
+
+    #[test]
+    fn replace_deletes_synced_books_in_the_cloud() {
+        let platform = MockPlatform::default();
+        save_local(&platform, &synced_book(ISBN_A, "Synced"));
+        save_local(&platform, &new_book(ISBN_B, "Never synced"));
+        save_local(&platform, &synced_book(ISBN_C, "Local"));
+
+        let contents = backup(vec![new_book(ISBN_C, "Backup")]);
+        let report = block_on(import_backup(&platform, &contents, true, &token())).unwrap();
+
+        assert!(report.replace);
+        assert_eq!((report.imported, report.skipped, report.removed), (1, 0, 2));
+        // the book that never reached the cloud is lost
+        assert_eq!(report.unsynced, vec![ISBN_B]);
+
+        // the sync'd book leaves a tombstone for the next sync to delete it in the cloud
+        assert!(get_local(&platform, ISBN_A).unwrap().is_deleted());
+        assert!(get_local(&platform, ISBN_B).is_none());
+        let book = get_local(&platform, ISBN_C).unwrap();
+        assert_eq!(book.title.as_deref(), Some("Backup"));
+        assert!(book.needs_sync());
+    }
+
+    #[test]
+    fn replace_wins_over_newer_cloud_changes() {
+        let platform = MockPlatform::default();
+        let mut local = synced_book(ISBN_A, "Local");
+        local.version = Some(3);
+        save_local(&platform, &local);
+
+        // the backup has an older version and the cloud copy was edited after the export
+        let mut exported = synced_book(ISBN_A, "Backup");
+        exported.timestamp_update -= TimeDelta::days(1);
+        let mut cloud = synced_book(ISBN_A, "Cloud");
+        cloud.version = Some(4);
+
+        let contents = backup(vec![exported]);
+        let report = block_on(import_backup(&platform, &contents, true, &token())).unwrap();
+        assert_eq!(report.imported, 1);
+
+        let mut book = get_local(&platform, ISBN_A).unwrap();
+        assert_eq!(book.version, Some(3));
+        assert!(book.needs_sync());
+
+        // the upload of version 3 gets a conflict and the merge keeps the imported fields
+        book.merge_from_cloud(&cloud);
+        assert_eq!(book.title.as_deref(), Some("Backup"));
+        assert_eq!(book.version, Some(4));
+    }
+
+    #[test]
+    fn invalid_files_change_nothing() {
+        let platform = MockPlatform::default();
+        save_local(&platform, &synced_book(ISBN_A, "Local"));
+
+        let mut wrong_format = serde_json::from_str::<LibraryExport>(&backup(vec![new_book(ISBN_B, "B")])).unwrap();
+        wrong_format.format = "something-else".to_owned();
+        let mut newer_version = wrong_format.clone();
+        newer_version.format = LIBRARY_EXPORT_FORMAT.to_owned();
+        newer_version.version = LIBRARY_EXPORT_VERSION + 1;
+        let mut no_title = new_book(ISBN_B, "B");
+        no_title.title = None;
+
+        for contents in [
+            "not json".to_owned(),
+            serde_json::to_string(&wrong_format).unwrap(),
+            serde_json::to_string(&newer_version).unwrap(),
+            backup(Vec::new()),
+            backup(vec![no_title]),
+        ] {
+            let e = block_on(import_backup(&platform, &contents, true, &token())).unwrap_err();
+            assert!(e.is::<InvalidBackup>(), "{e:?}");
+        }
+
+        assert_eq!(get_local(&platform, ISBN_A).unwrap().title.as_deref(), Some("Local"));
+        assert!(get_local(&platform, ISBN_B).is_none());
+    }
+
+    #[test]
+    fn invalid_records_are_reported() {
+        let platform = MockPlatform::default();
+        let mut no_title = new_book(ISBN_B, "B");
+        no_title.title = None;
+
+        let contents = backup(vec![new_book(ISBN_A, "A"), no_title]);
+        let report = block_on(import_backup(&platform, &contents, false, &token())).unwrap();
+
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.invalid.len(), 1);
+        assert!(get_local(&platform, ISBN_B).is_none());
+    }
+}
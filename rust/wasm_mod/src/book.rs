@@ -1,16 +1,18 @@
+use crate::books;
 use crate::google::get_book_data;
-use crate::utils::{get_local_storage, log};
+use crate::platform::Platform;
+use crate::storage::{get_local_storage, is_quota_error, KeyValueStore};
+use crate::wasm_response::WasmResponse;
 use anyhow::{bail, Result};
-use bookworm_types::{Book, ReadStatus};
-use chrono::Utc;
-use web_sys::Window;
+use bookworm_types::{Book, IdToken, ReadStatus};
 
 /// Adds a not to an existing book record, creates a new record if the ISBN is not found.
 /// The book record is stored in the local storage (front-end only access).
-/// Fails silently if the record cannot be stored.
-pub(crate) async fn save(book: &Book, runtime: &Window) -> Result<()> {
+/// If the storage is full, the oldest books are compacted and the save is retried once.
+/// The compaction report is sent to the UI as `WasmResponse::StorageUsage`.
+pub(crate) async fn save(book: &Book, runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<()> {
     // get the reference to the local storage
-    let ls = get_local_storage(runtime)?;
+    let ls = get_local_storage(runtime, id_token).await?;
 
     // replace the record in the database
     let key = book.isbn;
@@ -24,9 +26,13 @@ pub(crate) async fn save(book: &Book, runtime: &Window) -> Result<()> {
 
     // log!("Book to save: {value}");
 
-    match ls.set_item(&key.to_string(), &value) {
+    match ls.set_item(&key.to_string(), &value).await {
         Ok(()) => {
             log!("Book {key} saved in local storage");
+            return Ok(());
+        }
+        Err(e) if is_quota_error(&e) => {
+            log!("Storage quota exceeded while saving {key}");
         }
         Err(e) => {
             log!("Failed to save book {key} record: {:?}", e);
@@ -34,13 +40,32 @@ pub(crate) async fn save(book: &Book, runtime: &Window) -> Result<()> {
         }
     }
 
-    Ok(())
+    // make some room and try again
+    let usage = match books::compact(&ls, key.to_string().len() + value.len(), key).await {
+        Ok(v) => v,
+        Err(e) => {
+            log!("Failed to compact local storage: {:?}", e);
+            bail!("Book {key} not saved locally: storage is full");
+        }
+    };
+    runtime.report_progress(&WasmResponse::StorageUsage(Box::new(Some(Ok(usage)))));
+
+    match ls.set_item(&key.to_string(), &value).await {
+        Ok(()) => {
+            log!("Book {key} saved in local storage after compaction");
+            Ok(())
+        }
+        Err(e) => {
+            log!("Failed to save book {key} record after compaction: {:?}", e);
+            bail!("Book {key} not saved locally: storage is full");
+        }
+    }
 }
 
 /// Adds Google Books data to the book record.
 /// Returns an unchanged book if the call fails or no data was found.
 /// All errors are logged.
-pub(crate) async fn enhance_from_google_books(book: Book, runtime: &Window) -> Book {
+pub(crate) async fn enhance_from_google_books(book: Book, runtime: &impl Platform) -> Book {
     if book.volume_info.is_some() {
         log!("Insufficient Google Books data: {}", book.isbn);
         return book;
@@ -74,9 +99,14 @@ pub(crate) async fn enhance_from_google_books(book: Book, runtime: &Window) -> B
 /// Updates the status of a book record in the local storage.
 /// Returns the updated book details back.
 /// Returns an error if the book cannot be found in LS or in GoogleBooks.
-pub(crate) async fn update_status(runtime: &Window, isbn: u64, status: Option<ReadStatus>) -> Result<Book> {
+pub(crate) async fn update_status(
+    runtime: &impl Platform,
+    isbn: u64,
+    status: Option<ReadStatus>,
+    id_token: &Option<IdToken>,
+) -> Result<Book> {
     // get the book data
-    let book = match get(runtime, isbn).await? {
+    let book = match get(runtime, isbn, id_token).await? {
         Some(mut v) => {
             // exit if the previous status is the same as the new one
             // but I can't see how that may even happen if the UI behaves
@@ -86,8 +116,7 @@ pub(crate) async fn update_status(runtime: &Window, isbn: u64, status: Option<Re
             };
 
             // update the status
-            v.timestamp_update = Utc::now();
-            v.read_status = status;
+            v.set_read_status(status);
             v
         }
         None => {
@@ -95,48 +124,32 @@ pub(crate) async fn update_status(runtime: &Window, isbn: u64, status: Option<Re
         }
     };
 
-    // connect to the local storage
-    let ls = match runtime.local_storage() {
-        Ok(Some(v)) => v,
-        Err(e) => {
-            bail!("Failed to get local storage: {:?}", e);
-        }
-        _ => {
-            bail!("Local storage not available (OK(None))");
-        }
-    };
-
     // save the book record
-    match serde_json::to_string(&book) {
-        Ok(v) => match ls.set_item(&isbn.to_string(), &v) {
-            Ok(()) => log!("Book record updated"),
-            Err(e) => bail!("Failed to save book record: {:?}", e),
-        },
-        Err(e) => {
-            bail!("Failed to serialize book record for {isbn}: {:?}", e);
-        }
-    };
+    save(&book, runtime, id_token).await?;
 
     Ok(book)
 }
 
 /// Fetches a book record from the local storage by ISBN.
 /// if the book is not found in the local storage it fetches the book data from Google Books.
+/// Books compacted to free storage space are refetched the same way.
 /// - Error - something went wrong
 /// - None - the book was not found
-pub(crate) async fn get(runtime: &Window, isbn: u64) -> Result<Option<Book>> {
+pub(crate) async fn get(runtime: &impl Platform, isbn: u64, id_token: &Option<IdToken>) -> Result<Option<Book>> {
     // try to get the book from the local storage first
 
     // connect to the local storage
-    let ls = get_local_storage(runtime)?;
+    let ls = get_local_storage(runtime, id_token).await?;
 
     // get book details from LS by isbn or create a shell for populating it with data from other sources
-    let local_book = match ls.get_item(&isbn.to_string()) {
+    let local_book = match ls.get_item(&isbn.to_string()).await {
         Ok(Some(v)) => {
             log!("Found in local storage: {isbn}");
             // log!("{}",v);
 
             match serde_json::from_str::<Book>(&v) {
+                // a deleted book scanned again starts afresh
+                Ok(v) if v.is_deleted() => Book::new(isbn),
                 Ok(v) => v,
                 Err(e) => {
                     log!("Failed to parse local storage book record for {isbn}: {:?}", e);
@@ -166,27 +179,29 @@ pub(crate) async fn get(runtime: &Window, isbn: u64) -> Result<Option<Book>> {
 
     // store the book record in the local storage and sync with the cloud DB
     // TODO: add error handling
-    let _ = save(&book, runtime).await;
+    let _ = save(&book, runtime, id_token).await;
 
     Ok(Some(book))
 }
 
 /// Deletes the book from the local storage.
+/// Signed-in users get a tombstone record in its place until the deletion reaches the cloud DB,
+/// see `sync::delete_book`.
 /// Does nothing if the book is not found in the local storage.
-pub(crate) async fn delete(runtime: &Window, isbn: &str) -> Result<()> {
+pub(crate) async fn delete(runtime: &impl Platform, isbn: &str, id_token: &Option<IdToken>) -> Result<()> {
     // connect to the local storage
-    let ls = match runtime.local_storage() {
-        Ok(Some(v)) => v,
-        Err(e) => {
-            bail!("Failed to get local storage: {:?}", e);
-        }
-        _ => {
-            bail!("Local storage not available (OK(None))");
+    let ls = get_local_storage(runtime, id_token).await?;
+
+    if ls.is_user() {
+        if let Ok(isbn) = isbn.parse::<u64>() {
+            save(&Book::new_tombstone(isbn), runtime, id_token).await?;
+            log!("Book {isbn} replaced with a tombstone in local storage");
+            return Ok(());
         }
-    };
+    }
 
     // delete the book from LS by isbn
-    match ls.remove_item(isbn) {
+    match ls.remove_item(isbn).await {
         Ok(()) => log!("Book {isbn} removed from local storage"),
         Err(e) => {
             log!("Failed to remove local storage book record for {isbn}: {:?}", e);
@@ -196,3 +211,97 @@ pub(crate) async fn delete(runtime: &Window, isbn: &str) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{MockPlatform, MockResponse};
+    use futures::executor::block_on;
+
+    const ISBN: u64 = 9781761186769;
+    const VOLUMES: &str = include_str!("../data-samples/google-books-volume.json");
+    const QUOTA_ERROR: &str = include_str!("../data-samples/google-books-error-resp.json");
+
+    fn token() -> Option<IdToken> {
+        Some("test-token".to_owned())
+    }
+
+    #[test]
+    fn get_new_book_from_google() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 200, VOLUMES));
+
+        let book = block_on(get(&platform, ISBN, &token())).unwrap().unwrap();
+
+        assert_eq!(
+            book.title.as_deref(),
+            Some("Everything is Beautiful and Everything Hurts")
+        );
+        assert_eq!(book.authors, Some(vec!["Josie Shapiro".to_owned()]));
+        assert!(book.volume_info.is_some());
+
+        // the book is saved in the user namespace
+        let saved = serde_json::from_str::<Book>(&platform.get_user_item(&ISBN.to_string()).unwrap()).unwrap();
+        assert_eq!(saved.title, book.title);
+
+        // the second call is served from the local storage
+        let book = block_on(get(&platform, ISBN, &token())).unwrap().unwrap();
+        assert_eq!(
+            book.title.as_deref(),
+            Some("Everything is Beautiful and Everything Hurts")
+        );
+        assert_eq!(platform.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn get_new_book_without_google() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 429, QUOTA_ERROR));
+
+        let book = block_on(get(&platform, ISBN, &token())).unwrap().unwrap();
+
+        // the user still gets a record to add the details to later
+        assert_eq!(book.isbn, ISBN);
+        assert!(book.title.is_none());
+        assert!(platform.get_user_item(&ISBN.to_string()).is_some());
+    }
+
+    #[test]
+    fn get_deleted_book_starts_afresh() {
+        let platform = MockPlatform::default();
+        platform.set_user_item(
+            &ISBN.to_string(),
+            &serde_json::to_string(&Book::new_tombstone(ISBN)).unwrap(),
+        );
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 200, VOLUMES));
+
+        let book = block_on(get(&platform, ISBN, &token())).unwrap().unwrap();
+
+        assert!(!book.is_deleted());
+        assert!(book.title.is_some());
+    }
+
+    #[test]
+    fn update_status_records_field_timestamp() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 200, VOLUMES));
+
+        let book = block_on(update_status(&platform, ISBN, Some(ReadStatus::Read), &token())).unwrap();
+
+        assert_eq!(book.read_status, Some(ReadStatus::Read));
+        assert!(book.field_timestamps.read_status.is_some());
+        assert!(book.needs_sync());
+    }
+
+    #[test]
+    fn delete_leaves_tombstone_for_user() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 200, VOLUMES));
+        block_on(get(&platform, ISBN, &token())).unwrap();
+
+        block_on(delete(&platform, &ISBN.to_string(), &token())).unwrap();
+
+        let saved = serde_json::from_str::<Book>(&platform.get_user_item(&ISBN.to_string()).unwrap()).unwrap();
+        assert!(saved.is_deleted());
+    }
+}
@@ -1,51 +1,26 @@
-use crate::utils::log;
+use crate::platform::Platform;
+use crate::storage::{get_local_storage, KeyValueStore, StorageUsage, COMPACTION_HEADROOM_BYTES};
 use anyhow::{bail, Result};
-use bookworm_types::{Book, Books};
-use web_sys::Window;
+use bookworm_types::{Book, Books, IdToken};
 
 /// Returns a sorted array of all book records stored locally.
 /// Errors are logged.
-pub(crate) fn get(runtime: &Window) -> Result<Books> {
+pub(crate) async fn get(runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<Books> {
     // connect to the local storage
-    let ls = match runtime.local_storage() {
-        Ok(Some(v)) => v,
-        Err(e) => {
-            bail!("Failed to get local storage: {:?}", e);
-        }
-        _ => {
-            bail!("Local storage not available (OK(None))");
-        }
-    };
+    let ls = get_local_storage(runtime, id_token).await?;
 
-    // get the total number of records
-    let number_of_records = match ls.length() {
+    // get all the keys in one go
+    let keys = match ls.keys().await {
         Ok(v) => v,
         Err(e) => {
-            bail!("Failed to get local storage length: {:?}", e);
+            bail!("Failed to get local storage keys: {:?}", e);
         }
     };
 
     // init the books array to the max possible size
-    let mut books = Vec::with_capacity(number_of_records.try_into().unwrap_or_else(|_e| {
-        log!("Failed to convert local storage length {number_of_records} to usize. It's a bug.");
-        0
-    }));
-
-    // get one key at a time (inefficient, but the best we have with Local Storage)
-    for i in 0..number_of_records {
-        // get the key by index
-        let key = match ls.key(i) {
-            Ok(Some(v)) => v,
-            Ok(None) => {
-                log!("Key {i} not found in local storage");
-                continue;
-            }
-            Err(e) => {
-                log!("Failed to get key {i} from local storage: {:?}", e);
-                continue;
-            }
-        };
+    let mut books = Vec::with_capacity(keys.len());
 
+    for key in keys {
         // ignore non-ISBN keys
         if !key.starts_with("97") {
             log!("Non-ISBN key ignored: {key}");
@@ -53,7 +28,7 @@ pub(crate) fn get(runtime: &Window) -> Result<Books> {
         }
 
         // get value by key
-        let book = match ls.get_item(&key) {
+        let book = match ls.get_item(&key).await {
             Ok(Some(v)) => v,
             Ok(None) => {
                 log!("Value not found in local storage: {key}");
@@ -78,22 +53,10 @@ pub(crate) fn get(runtime: &Window) -> Result<Books> {
 
         // log!("{:?}", book);
 
-        // ignore books with no titles because it is likely to be a corrupted record
-        // from the format change or a bug
-        // the user will have no benefit from such records
-        // TODO: compare the ISBN inside Book and the key - they may differ
-        // TODO: delete these ignored records
-        match &book.title {
-            Some(v) => {
-                if v.is_empty() {
-                    log!("Empty title for {key}");
-                    continue;
-                }
-            }
-            None => {
-                log!("Empty title for {key}");
-                continue;
-            }
+        // see `integrity::check` for finding and repairing these and other broken records
+        if let Err(e) = validate(&key, &book) {
+            log!("{e}");
+            continue;
         }
 
         books.push(book);
@@ -106,3 +69,150 @@ pub(crate) fn get(runtime: &Window) -> Result<Books> {
 
     Ok(books)
 }
+
+/// Checks if a book record stored under `key` is fit to be shown to the user.
+/// The same rules apply to the local storage records and imported backups.
+/// Returns the reason if the record should be ignored.
+pub(crate) fn validate(key: &str, book: &Book) -> std::result::Result<(), String> {
+    // non-ISBN keys are not books
+    if !key.starts_with("97") {
+        return Err(format!("Non-ISBN key: {key}"));
+    }
+
+    // deleted books wait for the deletion to be sync'd
+    if book.is_deleted() {
+        return Err(format!("Deleted book: {key}"));
+    }
+
+    // ignore books with no titles because it is likely to be a corrupted record
+    // from the format change or a bug
+    // the user will have no benefit from such records
+    if book.title.as_ref().is_none_or(|v| v.is_empty()) {
+        return Err(format!("Empty title for {key}"));
+    }
+
+    Ok(())
+}
+
+/// Returns the number and the approximate size of all book records in the local storage.
+pub(crate) async fn get_usage(runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<StorageUsage> {
+    let ls = get_local_storage(runtime, id_token).await?;
+
+    let mut usage = StorageUsage::default();
+    for (key, value) in get_raw_records(&ls).await? {
+        usage.records += 1;
+        usage.bytes += key.len() + value.len();
+    }
+
+    Ok(usage)
+}
+
+/// Frees up at least `bytes_needed` + `COMPACTION_HEADROOM_BYTES` in the local storage
+/// by removing Google Books data and covers from the oldest books first.
+/// Titles, authors, statuses and photos are kept, so the book list does not change.
+/// The removed data is refetched by `book::get` next time the book is opened.
+/// The book with `keep_isbn` is not compacted because it is the one being saved.
+/// Returns the storage usage after the compaction with the list of compacted books.
+pub(crate) async fn compact(ls: &impl KeyValueStore, bytes_needed: usize, keep_isbn: u64) -> Result<StorageUsage> {
+    log!("Compacting local storage to free {bytes_needed} bytes");
+
+    // only books with something to remove are of interest
+    let mut books = Vec::new();
+    let mut usage = StorageUsage::default();
+    for (key, value) in get_raw_records(ls).await? {
+        usage.records += 1;
+        usage.bytes += key.len() + value.len();
+
+        if let Ok(book) = serde_json::from_str::<Book>(&value) {
+            if book.isbn != keep_isbn && (book.volume_info.is_some() || book.cover.is_some()) {
+                books.push((key, value.len(), book));
+            }
+        }
+    }
+
+    // the oldest books go first
+    books.sort_by_key(|(_, _, book)| book.timestamp_update);
+
+    let target = bytes_needed + COMPACTION_HEADROOM_BYTES;
+    for (key, old_len, mut book) in books {
+        if usage.freed_bytes >= target {
+            break;
+        }
+
+        book.volume_info = None;
+        book.cover = None;
+
+        let value = match serde_json::to_string(&book) {
+            Ok(v) => v,
+            Err(e) => {
+                log!("Failed to serialize book record for {key}: {:?}", e);
+                continue;
+            }
+        };
+
+        // a smaller record should always fit in
+        match ls.set_item(&key, &value).await {
+            Ok(()) => {
+                let freed = old_len.saturating_sub(value.len());
+                usage.freed_bytes += freed;
+                usage.bytes -= freed;
+                usage.compacted.push(book.isbn);
+            }
+            Err(e) => log!("Failed to save compacted book record for {key}: {:?}", e),
+        }
+    }
+
+    log!(
+        "Compacted {} books, freed {} bytes",
+        usage.compacted.len(),
+        usage.freed_bytes
+    );
+
+    if usage.freed_bytes < bytes_needed {
+        bail!(
+            "Not enough space in local storage: freed {} of {bytes_needed} bytes",
+            usage.freed_bytes
+        );
+    }
+
+    Ok(usage)
+}
+
+/// Returns all ISBN keys with their unparsed values.
+async fn get_raw_records(ls: &impl KeyValueStore) -> Result<Vec<(String, String)>> {
+    let keys = match ls.keys().await {
+        Ok(v) => v,
+        Err(e) => {
+            bail!("Failed to get local storage keys: {:?}", e);
+        }
+    };
+
+    let mut records = Vec::with_capacity(keys.len());
+    for key in keys {
+        // ignore non-ISBN keys
+        if !key.starts_with("97") {
+            continue;
+        }
+
+        match ls.get_item(&key).await {
+            Ok(Some(v)) => records.push((key, v)),
+            Ok(None) => log!("Value not found in local storage: {key}"),
+            Err(e) => log!("Failed to get value from local storage for {key}: {:?}", e),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Returns the ISBNs of the deleted books with tombstones waiting for the deletion to be sync'd.
+pub(crate) async fn get_deleted(runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<Vec<u64>> {
+    let ls = get_local_storage(runtime, id_token).await?;
+
+    Ok(get_raw_records(&ls)
+        .await?
+        .into_iter()
+        .filter_map(|(_, value)| serde_json::from_str::<Book>(&value).ok())
+        .filter(|v| v.is_deleted())
+        .map(|v| v.isbn)
+        .collect())
+}
@@ -0,0 +1,162 @@
+use anyhow::{bail, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use std::fmt;
+use std::io::Cursor;
+
+/// The longest side of an uploaded photo in pixels if the UI does not set it.
+pub(crate) const DEFAULT_MAX_PHOTO_SIZE: u32 = 1600;
+
+/// The JPEG quality of an uploaded photo if the UI does not set it.
+pub(crate) const DEFAULT_PHOTO_QUALITY: u8 = 80;
+
+/// How photos are re-encoded before the upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PhotoSettings {
+    /// The longest side of the photo in pixels. Smaller photos are not upscaled.
+    pub max_size: u32,
+    /// JPEG quality, 1..=100.
+    pub quality: u8,
+}
+
+impl PhotoSettings {
+    /// Uses the defaults for missing values and brings the values into their valid range.
+    pub fn new(max_size: Option<u32>, quality: Option<u8>) -> Self {
+        Self {
+            max_size: max_size.unwrap_or(DEFAULT_MAX_PHOTO_SIZE).max(1),
+            quality: quality.unwrap_or(DEFAULT_PHOTO_QUALITY).clamp(1, 100),
+        }
+    }
+}
+
+impl Default for PhotoSettings {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+/// The file is not an image or its format is not supported.
+/// The UI gets `WasmErrorCode::InvalidFile` for it.
+#[derive(Debug)]
+pub(crate) struct InvalidPhoto(pub String);
+
+impl fmt::Display for InvalidPhoto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsupported image: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPhoto {}
+
+/// Decodes a JPEG, PNG or WebP image, downscales it to fit `settings.max_size` and re-encodes it as JPEG.
+/// The output has no metadata at all, so the location and the camera details never leave the device.
+/// The EXIF orientation is applied to the pixels instead of being copied over.
+pub(crate) fn compress(bytes: &[u8], settings: PhotoSettings) -> Result<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+
+    if img.width() > settings.max_size || img.height() > settings.max_size {
+        img = img.resize(settings.max_size, settings.max_size, FilterType::Triangle);
+    }
+
+    // JPEG has no alpha channel
+    let img = img.into_rgb8();
+    let mut jpeg = Vec::new();
+    if let Err(e) = JpegEncoder::new_with_quality(&mut jpeg, settings.quality).encode_image(&img) {
+        bail!("Failed to encode the photo: {:?}", e);
+    }
+
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageEncoder, ImageFormat, RgbImage};
+
+    /// A little-endian TIFF header with a single IFD entry: orientation = 6 (rotate 90 clockwise).
+    const EXIF_ROTATE_90: [u8; 26] = [
+        0x49, 0x49, 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn jpeg(width: u32, height: u32, exif: Option<&[u8]>) -> Vec<u8> {
+        let img = RgbImage::from_pixel(width, height, image::Rgb([200, 100, 50]));
+        let mut bytes = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut bytes, 90);
+        if let Some(exif) = exif {
+            encoder.set_exif_metadata(exif.to_vec()).unwrap();
+        }
+        encoder
+            .write_image(&img, width, height, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        bytes
+    }
+
+    fn dimensions(bytes: &[u8]) -> (u32, u32) {
+        let img = image::load_from_memory_with_format(bytes, ImageFormat::Jpeg).unwrap();
+        (img.width(), img.height())
+    }
+
+    #[test]
+    fn settings_are_clamped() {
+        assert_eq!(
+            PhotoSettings::default(),
+            PhotoSettings {
+                max_size: DEFAULT_MAX_PHOTO_SIZE,
+                quality: DEFAULT_PHOTO_QUALITY
+            }
+        );
+        assert_eq!(
+            PhotoSettings::new(Some(0), Some(200)),
+            PhotoSettings {
+                max_size: 1,
+                quality: 100
+            }
+        );
+    }
+
+    #[test]
+    fn large_photo_is_downscaled() {
+        let settings = PhotoSettings::new(Some(100), None);
+        assert_eq!(
+            dimensions(&compress(&jpeg(400, 200, None), settings).unwrap()),
+            (100, 50)
+        );
+        // small photos keep their size
+        assert_eq!(dimensions(&compress(&jpeg(40, 20, None), settings).unwrap()), (40, 20));
+    }
+
+    #[test]
+    fn metadata_is_dropped_and_orientation_applied() {
+        let original = jpeg(40, 20, Some(&EXIF_ROTATE_90));
+        assert!(original.windows(4).any(|v| v == b"Exif"));
+
+        let compressed = compress(&original, PhotoSettings::default()).unwrap();
+
+        assert!(!compressed.windows(4).any(|v| v == b"Exif"));
+        assert_eq!(dimensions(&compressed), (20, 40));
+    }
+
+    #[test]
+    fn png_is_converted() {
+        let mut png = Vec::new();
+        RgbImage::from_pixel(10, 10, image::Rgb([0, 0, 0]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let compressed = compress(&png, PhotoSettings::default()).unwrap();
+
+        assert_eq!(image::guess_format(&compressed).unwrap(), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn not_an_image_fails() {
+        assert!(compress(b"not an image", PhotoSettings::default()).is_err());
+    }
+}
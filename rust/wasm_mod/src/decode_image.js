@@ -0,0 +1,19 @@
+// This function is imported into utils.rs for decoding images that the Rust decoders do not support,
+// e.g. HEIC photos in Safari.
+// Resolves to the pixels re-encoded as PNG in a Uint8Array, or null if the browser cannot decode the image.
+// The PNG has no metadata. The EXIF orientation is applied to the pixels by createImageBitmap.
+// OffscreenCanvas is used because it is available in both the UI thread and web workers.
+export async function decode_image_with_browser(file) {
+  try {
+    const bitmap = await createImageBitmap(file);
+    const canvas = new OffscreenCanvas(bitmap.width, bitmap.height);
+    canvas.getContext('2d').drawImage(bitmap, 0, 0);
+    bitmap.close();
+
+    const blob = await canvas.convertToBlob({ type: 'image/png' });
+    return new Uint8Array(await blob.arrayBuffer());
+  } catch (error) {
+    console.log("The browser cannot decode the image:", error);
+    return null;
+  }
+}
@@ -9,13 +9,12 @@
 ///
 //
 use crate::http_req::{execute_http_request, HttpMethod};
-use crate::utils::log;
+use crate::platform::Platform;
 use crate::{Result, RetryAfter};
 use bookworm_types::google::Volumes;
-use web_sys::Window;
 
 /// Fetches book data from Google Books API
-pub(crate) async fn get_book_data(isbn: u64, runtime: &Window) -> Result<Volumes> {
+pub(crate) async fn get_book_data(isbn: u64, runtime: &impl Platform) -> Result<Volumes> {
     log!("Querying google books for: {isbn}");
 
     let url = format!("https://www.googleapis.com/books/v1/volumes?q=isbn:{isbn}");
@@ -29,7 +28,75 @@ pub(crate) async fn get_book_data(isbn: u64, runtime: &Window) -> Result<Volumes
         Err(e) => {
             log!("Failed to get book data for {isbn}");
             log!("{:?}", e);
-            Err(e)
+            Err(e.retry_after)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{MockPlatform, MockResponse};
+    use futures::executor::block_on;
+
+    /// A response to `volumes?q=isbn:9781761186769`
+    const VOLUMES: &str = include_str!("../data-samples/google-books-volume.json");
+    /// A response to any query after the daily quota is used up.
+    const QUOTA_ERROR: &str = include_str!("../data-samples/google-books-error-resp.json");
+    /// The same volume wrapped into an old `WasmResponse` format.
+    const WRAPPED_VOLUMES: &str = include_str!("../data-samples/googleBooks-Ok.json");
+
+    #[test]
+    fn volumes_fixture() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 200, VOLUMES));
+
+        let volumes = block_on(get_book_data(9781761186769, &platform)).unwrap();
+
+        assert_eq!(volumes.total_items, 1);
+        assert_eq!(
+            volumes.items[0].volume_info.title,
+            "Everything is Beautiful and Everything Hurts"
+        );
+        assert_eq!(volumes.items[0].volume_info.authors, vec!["Josie Shapiro"]);
+    }
+
+    #[test]
+    fn wrapped_volumes_fixture() {
+        let wrapped = serde_json::from_str::<serde_json::Value>(WRAPPED_VOLUMES).unwrap();
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new(
+            "GET",
+            "isbn:9781761186769",
+            200,
+            &wrapped["googleBooks"]["Ok"].to_string(),
+        ));
+
+        let volumes = block_on(get_book_data(9781761186769, &platform)).unwrap();
+
+        assert_eq!(volumes.items.len(), 1);
+        assert_eq!(
+            volumes.items[0].volume_info.title,
+            "Everything is Beautiful and Everything Hurts"
+        );
+    }
+
+    #[test]
+    fn quota_error_fixture() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 429, QUOTA_ERROR));
+
+        let result = block_on(get_book_data(9781761186769, &platform));
+
+        // no Retry-After header, so the caller picks the delay
+        assert_eq!(result.unwrap_err(), RetryAfter::Seconds(0));
+    }
+
+    #[test]
+    fn no_network() {
+        let platform = MockPlatform::default();
+
+        assert!(block_on(get_book_data(9781761186769, &platform)).is_err());
+        assert_eq!(platform.requests.borrow().len(), 1);
+    }
+}
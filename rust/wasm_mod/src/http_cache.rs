@@ -0,0 +1,167 @@
+use bookworm_types::{jwt, IdToken};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// How many responses are kept in memory. The least recently used response is evicted first.
+const MAX_ENTRIES: usize = 100;
+
+thread_local! {
+    /// Responses to GET requests with their validators, see `ResponseCache`.
+    static CACHE: RefCell<ResponseCache> = RefCell::new(ResponseCache::default());
+}
+
+/// A GET response body with the validators the server sent with it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CachedResponse {
+    /// The value of `ETag` header, sent back as `If-None-Match`.
+    pub etag: Option<String>,
+    /// The value of `Last-Modified` header, sent back as `If-Modified-Since`.
+    pub last_modified: Option<String>,
+    /// The response body as text.
+    pub body: String,
+}
+
+impl CachedResponse {
+    /// Returns the conditional request headers for revalidating the response.
+    pub(crate) fn conditional_headers(&self) -> Vec<(&'static str, &str)> {
+        let mut headers = Vec::with_capacity(2);
+        if let Some(v) = &self.etag {
+            headers.push(("If-None-Match", v.as_str()));
+        }
+        if let Some(v) = &self.last_modified {
+            headers.push(("If-Modified-Since", v.as_str()));
+        }
+        headers
+    }
+}
+
+/// An in-memory cache of GET responses that can be revalidated with a conditional request.
+/// The server replies with 304 and no body if the cached response is still current.
+/// Only responses with `ETag` or `Last-Modified` are kept. The cache is lost when the page is reloaded.
+#[derive(Debug, Default)]
+struct ResponseCache {
+    /// The responses by `cache_key` with the time of the last use.
+    entries: HashMap<String, (u64, CachedResponse)>,
+    /// Incremented on every use to tell which entry was used least recently.
+    clock: u64,
+}
+
+impl ResponseCache {
+    /// Returns a copy of the response and marks it as recently used.
+    fn get(&mut self, key: &str) -> Option<CachedResponse> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|(used, v)| {
+            *used = clock;
+            v.clone()
+        })
+    }
+
+    /// Adds or replaces the response, evicting the least recently used one if the cache is full.
+    /// Responses without validators replace nothing and are not stored because they cannot be revalidated.
+    fn insert(&mut self, key: String, response: CachedResponse) {
+        if response.etag.is_none() && response.last_modified.is_none() {
+            self.entries.remove(&key);
+            return;
+        }
+
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&key) {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(k, _)| k.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        self.entries.insert(key, (self.clock, response));
+    }
+}
+
+/// Returns the cache key for a GET request to the URL.
+/// Responses to requests with a token are only shared by requests made for the same user.
+/// Returns None if the response should not be cached because the token cannot be read.
+pub(crate) fn cache_key(url: &str, id_token: &Option<IdToken>) -> Option<String> {
+    match id_token {
+        // an expired token still identifies whose data it is
+        Some(_) => jwt::get_user_details_ignoring_expiry(id_token).map(|v| [&v.id, " ", url].concat()),
+        None => Some(url.to_owned()),
+    }
+}
+
+/// Returns the cached response for the key, if any.
+pub(crate) fn get(key: &str) -> Option<CachedResponse> {
+    CACHE.with(|v| v.borrow_mut().get(key))
+}
+
+/// Caches the response if it has validators or removes the stale one if it doesn't.
+pub(crate) fn insert(key: String, response: CachedResponse) {
+    CACHE.with(|v| v.borrow_mut().insert(key, response));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(etag: Option<&str>, body: &str) -> CachedResponse {
+        CachedResponse {
+            etag: etag.map(|v| v.to_owned()),
+            last_modified: None,
+            body: body.to_owned(),
+        }
+    }
+
+    #[test]
+    fn conditional_headers() {
+        let mut cached = response(Some(r#""abc""#), "{}");
+        assert_eq!(cached.conditional_headers(), vec![("If-None-Match", r#""abc""#)]);
+
+        cached.last_modified = Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned());
+        assert_eq!(
+            cached.conditional_headers(),
+            vec![
+                ("If-None-Match", r#""abc""#),
+                ("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT")
+            ]
+        );
+    }
+
+    #[test]
+    fn responses_without_validators_are_not_kept() {
+        let mut cache = ResponseCache::default();
+        cache.insert("a".to_owned(), response(Some("1"), "old"));
+        cache.insert("a".to_owned(), response(None, "new"));
+
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = ResponseCache::default();
+        for i in 0..MAX_ENTRIES {
+            cache.insert(i.to_string(), response(Some("1"), ""));
+        }
+
+        // 0 was used last, so 1 is the oldest
+        assert!(cache.get("0").is_some());
+        cache.insert("new".to_owned(), response(Some("1"), ""));
+
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert!(cache.get("0").is_some());
+        assert!(cache.get("1").is_none());
+        assert!(cache.get("new").is_some());
+    }
+
+    #[test]
+    fn anonymous_key() {
+        assert_eq!(
+            cache_key("https://example.com", &None),
+            Some("https://example.com".to_owned())
+        );
+        // test tokens are not signed, so the user is unknown
+        assert_eq!(cache_key("https://example.com", &Some("test-token".to_owned())), None);
+    }
+}
@@ -1,9 +1,15 @@
+use crate::http_cache::{self, CachedResponse};
+use crate::platform::Platform;
+use crate::utils::{sleep, Runtime};
 use crate::{Result, RetryAfter};
-use bookworm_types::IdToken;
+use bookworm_types::{IdToken, REQUEST_ID_HEADER};
+use chrono::{DateTime, Utc};
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::fmt;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{Request, RequestInit, RequestMode, Response, Window};
+use web_sys::{AbortController, Request, RequestInit, RequestMode, Response};
 
 /// The name of the authorisation header containing the ID token with the user email.
 pub const AUTH_HEADER: &str = "x-books-authorization";
@@ -11,6 +17,13 @@ pub const AUTH_HEADER: &str = "x-books-authorization";
 /// Normally it would be our own domain name where all the server functions are hosted.
 pub const TRUSTED_URLS: &str = "https://bookworm.im";
 
+thread_local! {
+    /// The sequence number of the last request ID, see `new_request_id`.
+    static REQUEST_SEQ: Cell<u64> = const { Cell::new(0) };
+    /// The part of the request IDs that is the same for all requests from this instance of the module.
+    static SESSION_ID: i64 = Utc::now().timestamp_millis();
+}
+
 /// The HTTP methods that are supported by the app.
 pub(crate) enum HttpMethod<P> {
     Get,
@@ -19,7 +32,132 @@ pub(crate) enum HttpMethod<P> {
     Delete,
 }
 
-/// Prepares and executes an HTTP request.
+impl<P> HttpMethod<P> {
+    /// Returns true if repeating the request has the same effect as sending it once.
+    /// Only these requests are retried automatically.
+    fn is_idempotent(&self) -> bool {
+        !matches!(self, HttpMethod::Post(_))
+    }
+}
+
+/// How long to wait for a response and how to retry a failed request.
+/// Use `execute_http_request_with_policy` to pick a policy other than `RetryPolicy::DEFAULT`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// The request is aborted if the response does not arrive in full within this time.
+    /// Set to 0 to wait for as long as the browser does.
+    pub timeout_ms: u32,
+    /// How many times a failed idempotent request is repeated.
+    /// A request is only repeated after a network error, a timeout, 429 or 5xx.
+    pub max_retries: u32,
+    /// The delay before the first retry. It doubles with every retry and gets a random jitter of up to 50%.
+    pub base_delay_ms: u32,
+    /// A `Retry-After` longer than this is returned to the caller as `RetryAfter::Seconds` instead of waiting.
+    pub max_delay_ms: u32,
+}
+
+impl RetryPolicy {
+    /// Good for most requests made while the user waits for the result.
+    pub(crate) const DEFAULT: RetryPolicy = RetryPolicy {
+        timeout_ms: 15_000,
+        max_retries: 2,
+        base_delay_ms: 500,
+        max_delay_ms: 10_000,
+    };
+
+    /// A single attempt for requests the caller retries on its own, e.g. from the outbox.
+    pub(crate) const NO_RETRY: RetryPolicy = RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::DEFAULT
+    };
+
+    /// Returns the delay before the retry number `attempt`, starting from 1.
+    fn backoff_ms(&self, attempt: u32) -> u32 {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay_ms);
+        // the jitter spreads the retries of many clients hitting the same problem
+        delay + (delay as f64 * 0.5 * js_sys::Math::random()) as u32
+    }
+}
+
+/// The error of `execute_http_request` with the ID of the failed request.
+/// The ID matches the error with the server logs, so it goes into the error details for the UI.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpError {
+    pub retry_after: RetryAfter,
+    pub request_id: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.retry_after {
+            RetryAfter::Seconds(_) => "the network or the server is unavailable",
+            RetryAfter::Never => "the request was rejected",
+            RetryAfter::Conflict(_) => "the record was changed on another device",
+            RetryAfter::Cancelled => "the request was cancelled",
+        };
+        write!(f, "{reason}. Request ID: {}", self.request_id)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Same as `execute_http_request_with_policy` with `RetryPolicy::DEFAULT`.
+pub(super) async fn execute_http_request<P, R>(
+    url: &str,
+    method: HttpMethod<P>,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> std::result::Result<Option<R>, HttpError>
+where
+    P: serde::Serialize,
+    R: for<'de> serde::Deserialize<'de>,
+{
+    execute_http_request_with_policy(url, method, RetryPolicy::DEFAULT, runtime, id_token).await
+}
+
+/// Executes an HTTP request on the platform, see `fetch` for details.
+/// Every call gets a new request ID that is sent to our own server side in `REQUEST_ID_HEADER`
+/// and returned with the error on failure, see `HttpError`.
+pub(super) async fn execute_http_request_with_policy<P, R>(
+    url: &str,
+    method: HttpMethod<P>,
+    policy: RetryPolicy,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> std::result::Result<Option<R>, HttpError>
+where
+    P: serde::Serialize,
+    R: for<'de> serde::Deserialize<'de>,
+{
+    let request_id = new_request_id();
+
+    match runtime.http(url, method, policy, &request_id, id_token).await {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            log!("HTTP request {request_id} failed: {url}, {:?}", e);
+            Err(HttpError {
+                retry_after: e,
+                request_id,
+            })
+        }
+    }
+}
+
+/// Returns a new ID for correlating the request with the server logs.
+/// The IDs are made of the time the module was loaded and a sequence number,
+/// which makes them unique enough for finding the request in the logs of the user.
+fn new_request_id() -> String {
+    let seq = REQUEST_SEQ.with(|v| {
+        v.set(v.get() + 1);
+        v.get()
+    });
+    format!("{:x}-{seq}", SESSION_ID.with(|v| *v))
+}
+
+/// Prepares and executes an HTTP request in the browser, retrying it as set in the policy.
 /// ## Types
 /// * R - Response type, always required
 /// * P - Payload type, may be omitted
@@ -28,18 +166,66 @@ pub(crate) enum HttpMethod<P> {
 /// * POST - if payload is provided
 ///
 /// Do not include the id_token for URLs other than our own server side.
-pub(super) async fn execute_http_request<P, R>(
+///
+/// GET responses with `ETag` or `Last-Modified` headers are kept in `http_cache` and revalidated
+/// by the next request to the same URL. A 304 response returns the cached body.
+/// ## Errors
+/// * `RetryAfter::Seconds` - the request may succeed later, after the number of seconds from the `Retry-After` header
+///   or 0 if there was no header and the caller should pick its own delay
+/// * `RetryAfter::Conflict` - the server rejected a write based on a stale version of the record
+/// * `RetryAfter::Never` - the request would fail again
+pub(crate) async fn fetch<P, R>(
     url: &str,
     method: HttpMethod<P>,
-    runtime: &Window,
+    policy: RetryPolicy,
+    request_id: &str,
+    runtime: &Runtime,
     id_token: &Option<IdToken>,
 ) -> Result<Option<R>>
 where
     P: serde::Serialize,
     R: for<'de> serde::Deserialize<'de>,
 {
-    // log!("execute_get_request entered");
+    let max_retries = if method.is_idempotent() { policy.max_retries } else { 0 };
+
+    let mut attempt = 0;
+    loop {
+        match send_http_request(url, &method, policy.timeout_ms, request_id, runtime, id_token).await {
+            Err(RetryAfter::Seconds(v)) if attempt < max_retries => {
+                attempt += 1;
+                let delay_ms = if v > 0 {
+                    v.saturating_mul(1000).min(u32::MAX as i64) as u32
+                } else {
+                    policy.backoff_ms(attempt)
+                };
+
+                // the caller decides what to do about long delays
+                if delay_ms > policy.max_delay_ms {
+                    log!("Retry-After is too long for {url}: {v}s");
+                    return Err(RetryAfter::Seconds(v));
+                }
+
+                log!("Retry {attempt} of {max_retries} in {delay_ms}ms: {url}");
+                sleep(runtime, delay_ms).await;
+            }
+            result => return result,
+        }
+    }
+}
 
+/// Sends the request once. See `fetch` for details.
+async fn send_http_request<P, R>(
+    url: &str,
+    method: &HttpMethod<P>,
+    timeout_ms: u32,
+    request_id: &str,
+    runtime: &Runtime,
+    id_token: &Option<IdToken>,
+) -> Result<Option<R>>
+where
+    P: serde::Serialize,
+    R: for<'de> serde::Deserialize<'de>,
+{
     // check if the target URL is for the bookworm domain and reset the token if it is not
     // ideally, this function should not even get the token if the URL is not trusted
     // it's an additional safety measure
@@ -57,7 +243,7 @@ where
     opts.set_mode(RequestMode::Cors);
 
     // set HTTP method, add the payload and get a copy of it for later
-    let payload = match &method {
+    let payload = match method {
         HttpMethod::Get => {
             opts.set_method("GET");
             None
@@ -73,7 +259,6 @@ where
                 Err(e) => {
                     log!("Failed to serialize POST payload");
                     log!("{:?}", e);
-                    // TODO: may be worth a retry
                     return Err(RetryAfter::Never);
                 }
             }
@@ -89,7 +274,6 @@ where
                 Err(e) => {
                     log!("Failed to serialize PUT payload");
                     log!("{:?}", e);
-                    // TODO: may be worth a retry
                     return Err(RetryAfter::Never);
                 }
             }
@@ -102,13 +286,34 @@ where
 
     // log!("{url}");
 
+    // a GET response cached earlier is revalidated instead of being downloaded again
+    let cache_key = match method {
+        HttpMethod::Get => http_cache::cache_key(url, id_token),
+        _ => None,
+    };
+    let cached = cache_key.as_deref().and_then(http_cache::get);
+
+    // the request is aborted when the timer fires and the timer is cleared when the function returns
+    let _timeout = match timeout_ms {
+        0 => None,
+        _ => match RequestTimeout::start(runtime, timeout_ms) {
+            Ok(v) => {
+                opts.set_signal(Some(&v.controller.signal()));
+                Some(v)
+            }
+            Err(e) => {
+                log!("Failed to set request timeout: {:?}", e);
+                None
+            }
+        },
+    };
+
     // create the request
     let request = match Request::new_with_str_and_init(url, &opts) {
         Ok(v) => v,
         Err(e) => {
             log!("HTTP Request creation failed");
             log!("{:?}", e);
-            // TODO: may be worth a retry
             return Err(RetryAfter::Never);
         }
     };
@@ -123,6 +328,18 @@ where
         let _ = request.headers().set(AUTH_HEADER, id_token);
     }
 
+    // other domains may reject requests with unknown headers
+    if url.starts_with(TRUSTED_URLS) {
+        let _ = request.headers().set(REQUEST_ID_HEADER, request_id);
+    }
+
+    // the server replies with 304 and no body if the cached response is still current
+    if let Some(cached) = &cached {
+        for (name, value) in cached.conditional_headers() {
+            let _ = request.headers().set(name, value);
+        }
+    }
+
     // payload-related headers
     if let Some(payload) = payload {
         // only set the content type if there is POST payload
@@ -144,12 +361,12 @@ where
     // unwrap the response
     let resp = match resp {
         Ok(v) => v,
+        // network errors and timeouts end up here
         Err(e) => {
             log!("HTTP request failed");
             log!("{url}");
             log!("{:?}", e);
-            // TODO: may be worth a retry
-            return Err(RetryAfter::Never);
+            return Err(RetryAfter::Seconds(0));
         }
     };
 
@@ -161,7 +378,6 @@ where
         log!("HTTP response in not of type Response");
         log!("{url}");
         log!("{:?}", resp);
-        // TODO: may be worth a retry
         return Err(RetryAfter::Never);
     };
 
@@ -172,7 +388,6 @@ where
             log!("Cannot typecast response to Response");
             log!("{url}");
             log!("{:?}", e);
-            // TODO: may be worth a retry
             return Err(RetryAfter::Never);
         }
     };
@@ -182,10 +397,37 @@ where
 
     // return an error if the status is anything but success
     let status = resp.status();
-    log!("HTTP status: {status}");
-    if !(200..300).contains(&status) {
-        log!("HTTP request failed: {:?}", resp);
-        return Err(RetryAfter::Never);
+    log!("HTTP status for request {request_id}: {status}");
+
+    // the body of a conflict response is the current version of the record
+    if status == 409 {
+        let body = match resp.text() {
+            Ok(v) => JsFuture::from(v).await.ok().and_then(|v| v.as_string()),
+            Err(_) => None,
+        };
+        log!("HTTP conflict: {url}");
+        return match body {
+            Some(v) => Err(RetryAfter::Conflict(v)),
+            None => Err(RetryAfter::Never),
+        };
+    }
+
+    if status == 304 {
+        return match cached {
+            Some(v) => {
+                log!("HTTP cache hit: {url}");
+                parse_body(&v.body, url)
+            }
+            None => {
+                log!("HTTP 304 without a cached response: {url}");
+                Err(RetryAfter::Never)
+            }
+        };
+    }
+
+    if let Err(e) = check_status(status, resp.headers().get("Retry-After").ok().flatten().as_deref()) {
+        log!("HTTP request failed: {url}, {:?}", e);
+        return Err(e);
     }
 
     // return success if there is no response body
@@ -195,36 +437,53 @@ where
 
     // Read the response stream to completion.
     // In theory, the stream may still be open and the op may take some time to complete
-    let resp_body = match resp.json() {
+    let resp_body = match resp.text() {
         Ok(v) => JsFuture::from(v).await,
         Err(e) => {
             log!("Cannot convert response to Future for {url}: {:?}", e);
-            // TODO: may be worth a retry
             return Err(RetryAfter::Never);
         }
     };
 
-    // log!("Resp as Response JSON:");
-    // log!("resp: {:?}", resp);
-    // log!("body: {:?}", resp_body);
-    // log!("HTTP request completed");
-
     // Unwrap the response and handle the error
-    let resp_body = match resp_body {
-        Ok(v) => v,
+    // the body may be cut short by a network error or the timeout
+    let resp_body = match resp_body.map(|v| v.as_string()) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            log!("HTTP response body is not text: {url}");
+            return Err(RetryAfter::Never);
+        }
         Err(e) => {
             log!("HTTP request failed: {url}");
             log!("{:?}", e);
-            // TODO: may be worth a retry
-            return Err(RetryAfter::Never);
+            return Err(RetryAfter::Seconds(0));
         }
     };
 
-    // log!("Resp as string:");
-    // log!("{:?}", resp);
-
     // return a rust struct
-    match serde_wasm_bindgen::from_value::<R>(resp_body) {
+    let result = parse_body(&resp_body, url);
+
+    // keep the response for the next request to the same URL if the server sent validators with it
+    if let (Some(key), Ok(_)) = (cache_key, &result) {
+        http_cache::insert(
+            key,
+            CachedResponse {
+                etag: resp.headers().get("ETag").ok().flatten(),
+                last_modified: resp.headers().get("Last-Modified").ok().flatten(),
+                body: resp_body,
+            },
+        );
+    }
+
+    result
+}
+
+/// Deserializes the JSON response body into a rust struct.
+fn parse_body<R>(body: &str, url: &str) -> Result<Option<R>>
+where
+    R: for<'de> serde::Deserialize<'de>,
+{
+    match serde_json::from_str::<R>(body) {
         Ok(v) => Ok(Some(v)),
         Err(e) => {
             log!("Cannot deser HTTP response into rust struct");
@@ -234,3 +493,135 @@ where
         }
     }
 }
+
+/// Aborts a fetch request if it takes longer than the timeout.
+/// The timer is cleared when the value is dropped.
+struct RequestTimeout {
+    runtime: Runtime,
+    controller: AbortController,
+    handle: i32,
+    /// Must live until the timer fires or is cleared.
+    _on_timeout: Closure<dyn FnMut()>,
+}
+
+impl RequestTimeout {
+    fn start(runtime: &Runtime, timeout_ms: u32) -> std::result::Result<Self, JsValue> {
+        let controller = AbortController::new()?;
+
+        let timeout_controller = controller.clone();
+        let on_timeout = Closure::once(move || {
+            log!("HTTP request timed out after {timeout_ms}ms");
+            timeout_controller.abort();
+        });
+        let handle = runtime.set_timeout(
+            on_timeout.as_ref().unchecked_ref(),
+            timeout_ms.min(i32::MAX as u32) as i32,
+        )?;
+
+        Ok(Self {
+            runtime: runtime.clone(),
+            controller,
+            handle,
+            _on_timeout: on_timeout,
+        })
+    }
+}
+
+impl Drop for RequestTimeout {
+    fn drop(&mut self) {
+        self.runtime.clear_timeout(self.handle);
+    }
+}
+
+/// Converts the HTTP status of a response into an error for the caller.
+/// Returns Ok for 2xx statuses.
+/// 409 needs the response body and has to be handled before calling this function.
+pub(crate) fn check_status(status: u16, retry_after: Option<&str>) -> Result<()> {
+    match status {
+        200..=299 => Ok(()),
+        // throttling and server errors are likely to go away
+        429 | 503 => Err(RetryAfter::Seconds(
+            retry_after
+                .and_then(|v| parse_retry_after(v, Utc::now()))
+                .unwrap_or_default(),
+        )),
+        500 | 502 | 504 => Err(RetryAfter::Seconds(0)),
+        _ => Err(RetryAfter::Never),
+    }
+}
+
+/// Converts the value of `Retry-After` header into the number of seconds from `now`.
+/// The header is either a number of seconds or an HTTP date.
+/// Returns None if the value is invalid.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<i64> {
+    let value = value.trim();
+
+    if let Ok(v) = value.parse::<i64>() {
+        return Some(v.max(0));
+    }
+
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|v| (v.with_timezone(&Utc) - now).num_seconds().max(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::MockPlatform;
+    use futures::executor::block_on;
+
+    #[test]
+    fn status_to_retry_after() {
+        assert_eq!(check_status(200, None), Ok(()));
+        assert_eq!(check_status(204, None), Ok(()));
+        assert_eq!(check_status(429, Some("30")), Err(RetryAfter::Seconds(30)));
+        assert_eq!(check_status(503, None), Err(RetryAfter::Seconds(0)));
+        assert_eq!(check_status(503, Some("soon")), Err(RetryAfter::Seconds(0)));
+        assert_eq!(check_status(502, Some("30")), Err(RetryAfter::Seconds(0)));
+        assert_eq!(check_status(400, None), Err(RetryAfter::Never));
+        assert_eq!(check_status(403, Some("30")), Err(RetryAfter::Never));
+    }
+
+    #[test]
+    fn request_ids_are_unique() {
+        let first = new_request_id();
+        let second = new_request_id();
+
+        assert_ne!(first, second);
+        assert_eq!(first.split_once('-').unwrap().0, second.split_once('-').unwrap().0);
+    }
+
+    #[test]
+    fn failed_request_returns_request_id() {
+        let platform = MockPlatform::default();
+
+        let error = block_on(execute_http_request::<(), ()>(
+            "https://example.com",
+            HttpMethod::Get,
+            &platform,
+            &None,
+        ))
+        .unwrap_err();
+
+        assert_eq!(error.retry_after, RetryAfter::Seconds(0));
+        assert_eq!(error.request_id, platform.requests.borrow()[0].request_id);
+        assert!(error
+            .to_string()
+            .ends_with(&format!("Request ID: {}", error.request_id)));
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(parse_retry_after("120", now), Some(120));
+        assert_eq!(parse_retry_after(" 5 ", now), Some(5));
+        assert_eq!(parse_retry_after("-5", now), Some(0));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now), Some(120));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now), Some(0));
+        assert_eq!(parse_retry_after("tomorrow", now), None);
+    }
+}
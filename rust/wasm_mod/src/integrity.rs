@@ -0,0 +1,530 @@
+use crate::book;
+use crate::platform::Platform;
+use crate::storage::{get_local_storage, KeyValueStore};
+use anyhow::{bail, Result};
+use bookworm_types::{Book, IdToken};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Records that cannot be repaired are moved under this prefix.
+/// They are not ISBNs, so `books::get` ignores them.
+pub(crate) const QUARANTINE_KEY_PREFIX: &str = "quarantine-";
+
+/// What is wrong with a local storage record.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum IssueKind {
+    /// The value is not a valid `Book` JSON.
+    InvalidJson,
+    /// The ISBN inside the record differs from the storage key.
+    IsbnMismatch,
+    /// The record has no title and is not shown to the user,
+    /// e.g. because Google Books was not available when the book was scanned.
+    MissingTitle,
+    /// The same book is stored under its ISBN-10 and ISBN-13 keys.
+    DuplicateIsbn,
+    /// Different ISBNs with the same title and authors, e.g. a paperback and a hardcover.
+    DuplicateEdition,
+}
+
+/// What the repair does about the issue.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum IssueAction {
+    /// The record is updated in place.
+    Fix,
+    /// The record is moved under `QUARANTINE_KEY_PREFIX`.
+    Quarantine,
+    /// The record is merged into the other copy of the same book and quarantined.
+    Merge,
+    /// The details are fetched from Google Books again. The record is kept as it is if that fails.
+    Refetch,
+    /// Nothing can be done automatically. The issue is for the user to decide.
+    None,
+}
+
+/// A single problem found in the local storage.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryIssue {
+    /// The local storage key of the record.
+    pub key: String,
+    pub kind: IssueKind,
+    /// What the repair does or would do about it.
+    pub action: IssueAction,
+    /// True if the action was applied successfully.
+    pub fixed: bool,
+    /// A human-readable explanation, e.g. the parser error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+/// The result of `check_local_library` sent to the UI as `WasmResponse::LibraryCheck`.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryReport {
+    /// The number of book records checked.
+    pub checked: usize,
+    /// True if the check was run with repairs enabled.
+    pub repair: bool,
+    pub issues: Vec<LibraryIssue>,
+}
+
+impl LibraryReport {
+    fn push(&mut self, key: &str, kind: IssueKind, action: IssueAction, details: Option<String>) {
+        log!("Library issue {:?} for {key}: {:?}", kind, details);
+        self.issues.push(LibraryIssue {
+            key: key.to_owned(),
+            kind,
+            action,
+            fixed: false,
+            details,
+        });
+    }
+
+    /// Marks the last added issue as fixed.
+    fn fixed(&mut self, fixed: bool) {
+        if let Some(v) = self.issues.last_mut() {
+            v.fixed = fixed;
+        }
+    }
+}
+
+/// A record that parsed into a book.
+struct Record {
+    key: String,
+    value: String,
+    book: Book,
+    /// The book has to be saved under its ISBN-13 key.
+    changed: bool,
+    /// The book has no title and its details have to be fetched again.
+    missing_title: bool,
+}
+
+/// Checks all book records in the local storage for problems that `books::get` silently skips over.
+/// Fixes or quarantines the broken records if `repair` is true, otherwise only reports them.
+/// Books with no title are never quarantined because Google Books may have been down when they were scanned.
+/// Their details are fetched again instead.
+/// Duplicate editions are never changed because the user may have both.
+pub(crate) async fn check(runtime: &impl Platform, repair: bool, id_token: &Option<IdToken>) -> Result<LibraryReport> {
+    let ls = get_local_storage(runtime, id_token).await?;
+
+    let keys = match ls.keys().await {
+        Ok(v) => v,
+        Err(e) => {
+            bail!("Failed to get local storage keys: {:?}", e);
+        }
+    };
+
+    let mut report = LibraryReport {
+        repair,
+        ..Default::default()
+    };
+
+    // valid records indexed by their ISBN-13
+    let mut records: HashMap<u64, Record> = HashMap::with_capacity(keys.len());
+    // duplicates merged into other records, to be quarantined
+    let mut merged = Vec::new();
+
+    for key in keys {
+        // only numeric keys are books, quarantined records and anything else is skipped
+        let isbn = match key.parse::<u64>().ok().and_then(Book::to_isbn13) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let value = match ls.get_item(&key).await {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                log!("Value not found in local storage: {key}");
+                continue;
+            }
+            Err(e) => {
+                log!("Failed to get value from local storage for {key}: {:?}", e);
+                continue;
+            }
+        };
+
+        report.checked += 1;
+
+        let mut book = match serde_json::from_str::<Book>(&value) {
+            Ok(v) => v,
+            Err(e) => {
+                report.push(
+                    &key,
+                    IssueKind::InvalidJson,
+                    IssueAction::Quarantine,
+                    Some(e.to_string()),
+                );
+                if repair {
+                    report.fixed(quarantine(&ls, &key, &value).await);
+                }
+                continue;
+            }
+        };
+
+        // tombstones have no details and are removed once the deletion is sync'd
+        if book.is_deleted() {
+            continue;
+        }
+
+        // books with no titles are not shown to the user, but the rest of the record is still valid
+        let missing_title = !has_title(&book);
+        if missing_title {
+            report.push(&key, IssueKind::MissingTitle, IssueAction::Refetch, None);
+        }
+
+        // the key is what the book is looked up by, so it takes precedence over the ISBN inside
+        let mut changed = false;
+        if book.isbn != isbn || key != isbn.to_string() {
+            report.push(
+                &key,
+                IssueKind::IsbnMismatch,
+                IssueAction::Fix,
+                Some(format!("Record ISBN: {}, expected: {isbn}", book.isbn)),
+            );
+            book.isbn = isbn;
+            changed = true;
+        }
+
+        let record = Record {
+            key,
+            value,
+            book,
+            changed,
+            missing_title,
+        };
+
+        // the same book may be stored under ISBN-10 and ISBN-13 keys
+        let record = match records.remove(&isbn) {
+            Some(other) => {
+                let (mut keep, drop) = if other.book.timestamp_update >= record.book.timestamp_update {
+                    (other, record)
+                } else {
+                    (record, other)
+                };
+
+                report.push(
+                    &drop.key,
+                    IssueKind::DuplicateIsbn,
+                    IssueAction::Merge,
+                    Some(format!("Same book as {}", keep.key)),
+                );
+                merge_duplicate(&mut keep.book, &drop.book);
+                keep.changed = true;
+                keep.missing_title = !has_title(&keep.book);
+                merged.push(drop);
+                keep
+            }
+            None => record,
+        };
+
+        records.insert(isbn, record);
+    }
+
+    // different editions of the same book
+    let mut editions: HashMap<String, Vec<u64>> = HashMap::new();
+    // books with no titles would all look like editions of each other
+    for record in records.values().filter(|v| !v.missing_title) {
+        editions
+            .entry(edition_key(&record.book))
+            .or_default()
+            .push(record.book.isbn);
+    }
+    for isbns in editions.values().filter(|v| v.len() > 1) {
+        for isbn in isbns {
+            let others = isbns
+                .iter()
+                .filter(|v| *v != isbn)
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>();
+            report.push(
+                &isbn.to_string(),
+                IssueKind::DuplicateEdition,
+                IssueAction::None,
+                Some(format!("Same title and authors as {}", others.join(", "))),
+            );
+        }
+    }
+
+    if !repair {
+        return Ok(report);
+    }
+
+    // the merged duplicates are kept for the record, but out of the way
+    // this has to go first because a duplicate may occupy the key of the merged record
+    for record in merged {
+        let quarantined = quarantine(&ls, &record.key, &record.value).await;
+        mark_fixed(&mut report, &record.key, IssueKind::DuplicateIsbn, quarantined);
+    }
+
+    // the cloud copies of quarantined books have to be downloaded again
+    if report
+        .issues
+        .iter()
+        .any(|v| v.action == IssueAction::Quarantine && v.fixed)
+    {
+        crate::sync::reset_sync_cursor(&ls).await;
+    }
+
+    // the books saved while Google Books was down get another chance
+    for record in records.values_mut().filter(|v| v.missing_title) {
+        let book = book::enhance_from_google_books(record.book.clone(), runtime).await;
+        if has_title(&book) {
+            record.book = book;
+            record.changed = true;
+        }
+    }
+
+    // save fixed, merged and refetched books under their ISBN-13 keys
+    for record in records.values().filter(|v| v.changed) {
+        let saved = book::save(&record.book, runtime, id_token).await.is_ok();
+        if saved && record.key != record.book.isbn.to_string() {
+            if let Err(e) = ls.remove_item(&record.key).await {
+                log!("Failed to remove {} after moving it: {:?}", record.key, e);
+            }
+        }
+        mark_fixed(&mut report, &record.key, IssueKind::IsbnMismatch, saved);
+        mark_fixed(
+            &mut report,
+            &record.key,
+            IssueKind::MissingTitle,
+            saved && has_title(&record.book),
+        );
+    }
+
+    Ok(report)
+}
+
+/// Returns true if the book has a title to show to the user.
+fn has_title(book: &Book) -> bool {
+    book.title.as_ref().is_some_and(|v| !v.is_empty())
+}
+
+/// Copies the details missing from `book` over from its `duplicate`.
+fn merge_duplicate(book: &mut Book, duplicate: &Book) {
+    if book.title.is_none() {
+        book.title = duplicate.title.clone();
+    }
+    if book.authors.is_none() {
+        book.authors = duplicate.authors.clone();
+    }
+    if book.read_status.is_none() {
+        book.read_status = duplicate.read_status;
+    }
+    if book.volume_info.is_none() {
+        book.volume_info = duplicate.volume_info.clone();
+        book.cover = duplicate.cover.clone();
+    }
+    if book.photos.is_none() {
+        book.photos = duplicate.photos.clone();
+    }
+    if book.share_id.is_none() {
+        book.share_id = duplicate.share_id;
+    }
+    // the merged record has not been sync'd
+    book.timestamp_sync = None;
+}
+
+/// Returns a normalized title + authors string for finding different editions of the same book.
+fn edition_key(book: &Book) -> String {
+    let mut authors = book
+        .authors
+        .iter()
+        .flatten()
+        .map(|v| v.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    authors.sort();
+
+    [
+        book.title.as_deref().unwrap_or_default().trim().to_lowercase(),
+        authors.join(","),
+    ]
+    .join("|")
+}
+
+/// Moves the record under `QUARANTINE_KEY_PREFIX`.
+/// Returns true on success. Errors are logged.
+async fn quarantine(ls: &impl KeyValueStore, key: &str, value: &str) -> bool {
+    let quarantine_key = [QUARANTINE_KEY_PREFIX, key].concat();
+    if let Err(e) = ls.set_item(&quarantine_key, value).await {
+        log!("Failed to quarantine {key}: {:?}", e);
+        return false;
+    }
+
+    match ls.remove_item(key).await {
+        Ok(()) => {
+            log!("Quarantined {key}");
+            true
+        }
+        Err(e) => {
+            log!("Failed to remove {key} after quarantining: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Sets the `fixed` flag on the issue of the given kind for the key.
+fn mark_fixed(report: &mut LibraryReport, key: &str, kind: IssueKind, fixed: bool) {
+    if let Some(v) = report.issues.iter_mut().find(|v| v.key == key && v.kind == kind) {
+        v.fixed = fixed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{MockPlatform, MockResponse};
+    use futures::executor::block_on;
+
+    const ISBN: u64 = 9780143107712;
+    /// The ISBN-10 of `ISBN`.
+    const ISBN10: &str = "0143107712";
+    /// The ISBN of the book in `VOLUMES`.
+    const GOOGLE_ISBN: u64 = 9781761186769;
+    const VOLUMES: &str = include_str!("../data-samples/google-books-volume.json");
+
+    fn token() -> Option<IdToken> {
+        Some("test-token".to_owned())
+    }
+
+    fn book(isbn: u64, title: Option<&str>) -> Book {
+        let mut book = Book::new(isbn);
+        book.title = title.map(|v| v.to_owned());
+        book.authors = Some(vec!["Author".to_owned()]);
+        book
+    }
+
+    fn store(platform: &MockPlatform, key: &str, book: &Book) {
+        platform.set_user_item(key, &serde_json::to_string(book).unwrap());
+    }
+
+    fn stored(platform: &MockPlatform, key: &str) -> Option<Book> {
+        platform
+            .get_user_item(key)
+            .map(|v| serde_json::from_str::<Book>(&v).unwrap())
+    }
+
+    /// Returns the only issue of the kind in the report.
+    fn issue(report: &LibraryReport, kind: IssueKind) -> &LibraryIssue {
+        let issues = report.issues.iter().filter(|v| v.kind == kind).collect::<Vec<_>>();
+        assert_eq!(issues.len(), 1, "{:?}", report.issues);
+        issues[0]
+    }
+
+    #[test]
+    fn invalid_json_is_quarantined() {
+        let platform = MockPlatform::default();
+        platform.set_user_item(&ISBN.to_string(), "{not json");
+
+        let report = block_on(check(&platform, false, &token())).unwrap();
+        let v = issue(&report, IssueKind::InvalidJson);
+        assert_eq!((v.action, v.fixed), (IssueAction::Quarantine, false));
+        assert!(platform.get_user_item(&ISBN.to_string()).is_some());
+
+        let report = block_on(check(&platform, true, &token())).unwrap();
+        let v = issue(&report, IssueKind::InvalidJson);
+        assert_eq!((v.action, v.fixed), (IssueAction::Quarantine, true));
+        assert!(platform.get_user_item(&ISBN.to_string()).is_none());
+        assert_eq!(
+            platform
+                .get_user_item(&[QUARANTINE_KEY_PREFIX, &ISBN.to_string()].concat())
+                .as_deref(),
+            Some("{not json")
+        );
+    }
+
+    #[test]
+    fn isbn_mismatch_is_fixed() {
+        let platform = MockPlatform::default();
+        store(&platform, &ISBN.to_string(), &book(GOOGLE_ISBN, Some("Title")));
+
+        let report = block_on(check(&platform, false, &token())).unwrap();
+        let v = issue(&report, IssueKind::IsbnMismatch);
+        assert_eq!((v.action, v.fixed), (IssueAction::Fix, false));
+        assert_eq!(stored(&platform, &ISBN.to_string()).unwrap().isbn, GOOGLE_ISBN);
+
+        let report = block_on(check(&platform, true, &token())).unwrap();
+        let v = issue(&report, IssueKind::IsbnMismatch);
+        assert_eq!((v.action, v.fixed), (IssueAction::Fix, true));
+        assert_eq!(stored(&platform, &ISBN.to_string()).unwrap().isbn, ISBN);
+    }
+
+    #[test]
+    fn missing_title_is_refetched() {
+        let platform = MockPlatform::default();
+        store(&platform, &GOOGLE_ISBN.to_string(), &book(GOOGLE_ISBN, None));
+
+        let report = block_on(check(&platform, false, &token())).unwrap();
+        let v = issue(&report, IssueKind::MissingTitle);
+        assert_eq!((v.action, v.fixed), (IssueAction::Refetch, false));
+        assert!(platform.requests.borrow().is_empty());
+
+        platform.respond(MockResponse::new("GET", "isbn:9781761186769", 200, VOLUMES));
+        let report = block_on(check(&platform, true, &token())).unwrap();
+        let v = issue(&report, IssueKind::MissingTitle);
+        assert_eq!((v.action, v.fixed), (IssueAction::Refetch, true));
+        assert_eq!(
+            stored(&platform, &GOOGLE_ISBN.to_string()).unwrap().title.as_deref(),
+            Some("Everything is Beautiful and Everything Hurts")
+        );
+    }
+
+    #[test]
+    fn missing_title_is_kept_if_google_fails() {
+        let platform = MockPlatform::default();
+        store(&platform, &GOOGLE_ISBN.to_string(), &book(GOOGLE_ISBN, None));
+
+        // no response is queued, so the Google Books request fails
+        let report = block_on(check(&platform, true, &token())).unwrap();
+
+        let v = issue(&report, IssueKind::MissingTitle);
+        assert_eq!((v.action, v.fixed), (IssueAction::Refetch, false));
+        assert!(stored(&platform, &GOOGLE_ISBN.to_string()).is_some());
+        assert!(platform
+            .get_user_item(&[QUARANTINE_KEY_PREFIX, &GOOGLE_ISBN.to_string()].concat())
+            .is_none());
+    }
+
+    #[test]
+    fn duplicate_isbn_is_merged() {
+        let platform = MockPlatform::default();
+        let mut isbn10 = book(ISBN, Some("Title"));
+        isbn10.timestamp_update -= chrono::TimeDelta::days(1);
+        isbn10.photos = Some(vec!["1727129470".to_owned()]);
+        store(&platform, ISBN10, &isbn10);
+        store(&platform, &ISBN.to_string(), &book(ISBN, Some("Title")));
+
+        let report = block_on(check(&platform, false, &token())).unwrap();
+        let v = issue(&report, IssueKind::DuplicateIsbn);
+        assert_eq!((v.key.as_str(), v.action, v.fixed), (ISBN10, IssueAction::Merge, false));
+        assert!(platform.get_user_item(ISBN10).is_some());
+
+        let report = block_on(check(&platform, true, &token())).unwrap();
+        let v = issue(&report, IssueKind::DuplicateIsbn);
+        assert_eq!((v.key.as_str(), v.action, v.fixed), (ISBN10, IssueAction::Merge, true));
+        assert!(platform.get_user_item(ISBN10).is_none());
+        assert!(platform
+            .get_user_item(&[QUARANTINE_KEY_PREFIX, ISBN10].concat())
+            .is_some());
+        // the details only the older copy had are kept
+        assert_eq!(stored(&platform, &ISBN.to_string()).unwrap().photos, isbn10.photos);
+    }
+
+    #[test]
+    fn duplicate_editions_are_only_reported() {
+        let platform = MockPlatform::default();
+        store(&platform, &ISBN.to_string(), &book(ISBN, Some("Title")));
+        store(&platform, &GOOGLE_ISBN.to_string(), &book(GOOGLE_ISBN, Some(" title ")));
+        let before = platform.storage.borrow().clone();
+
+        for repair in [false, true] {
+            let report = block_on(check(&platform, repair, &token())).unwrap();
+
+            assert_eq!(report.issues.len(), 2);
+            assert!(report
+                .issues
+                .iter()
+                .all(|v| v.kind == IssueKind::DuplicateEdition && v.action == IssueAction::None && !v.fixed));
+            assert_eq!(*platform.storage.borrow(), before);
+        }
+    }
+}
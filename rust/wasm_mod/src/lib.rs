@@ -1,18 +1,37 @@
+use backup::InvalidBackup;
+pub use backup::{ImportReport, LibraryExport};
 use bookworm_types::{jwt, Books, IdToken, ReadStatus};
+use compress::{InvalidPhoto, PhotoSettings};
 pub use http_req::AUTH_HEADER;
-use sync::{sync_book, sync_books};
-use utils::get_runtime;
+pub use integrity::{IssueAction, IssueKind, LibraryIssue, LibraryReport};
+pub use merge::MergeOffer;
+use outbox::Mutation;
+use photos::UploadCancelled;
+pub use photos::UploadProgress;
+use platform::Platform;
+pub use storage::StorageUsage;
+use sync::sync_books;
+pub use sync::SyncStatus;
+use utils::{abort_uploads, get_runtime};
 use wasm_bindgen::prelude::*;
-use wasm_response::{report_progress, WasmResponse, WasmResult};
+use wasm_response::{LastMessage, WasmError, WasmErrorCode, WasmResponse, WasmResult};
 use web_sys::FileList;
 
 #[macro_use]
 pub(crate) mod utils;
+mod backup;
 mod book;
 mod books;
+mod compress;
 pub mod google;
+mod http_cache;
 mod http_req;
+mod integrity;
+mod merge;
+mod outbox;
 mod photos;
+mod platform;
+mod storage;
 mod sync;
 pub mod wasm_response;
 
@@ -22,6 +41,11 @@ pub mod wasm_response;
 pub enum RetryAfter {
     Seconds(i64),
     Never,
+    /// The server rejected a write based on a stale version of the record.
+    /// Contains the response body with the current record to merge before retrying.
+    Conflict(String),
+    /// The user cancelled the operation, e.g. a photo upload. It must not be repeated without asking.
+    Cancelled,
 }
 
 /// The result type that should be used in place of std::Result
@@ -31,8 +55,12 @@ pub type Result<T> = std::result::Result<T, RetryAfter>;
 /// The main entry point for the UI thread to request book data.
 /// Multiple responses are sent back via `progress.js` to the UI thread.
 /// See `fn report_progress()` for more details.
+///
+/// All entry points take a `request_id` from the UI. It is included in every message sent
+/// for the call, and the final message of the call is marked with `last`, see `WasmMessage`.
 #[wasm_bindgen]
-pub async fn get_book_data(isbn: String, id_token: Option<IdToken>, share_id: Option<String>) {
+pub async fn get_book_data(request_id: String, isbn: String, id_token: Option<IdToken>, share_id: Option<String>) {
+    let _last = LastMessage(&request_id);
     log!("Getting book data for ISBN: {isbn}, share ID: {:?}", share_id);
 
     let isbn = match isbn.parse::<u64>() {
@@ -44,7 +72,7 @@ pub async fn get_book_data(isbn: String, id_token: Option<IdToken>, share_id: Op
     };
 
     // need the runtime for the global context and fetch
-    let runtime = match get_runtime().await {
+    let runtime = match get_runtime(&request_id).await {
         Ok(v) => v,
 
         // this would be a bug
@@ -55,7 +83,7 @@ pub async fn get_book_data(isbn: String, id_token: Option<IdToken>, share_id: Op
     };
 
     // get the book details from either the local storage or the Google Books API
-    let resp = match book::get(&runtime, isbn).await {
+    let resp = match book::get(&runtime, isbn, &id_token).await {
         Ok(Some(v)) => {
             // log!("{:?}", v);
             // hydrate the book for the front-end
@@ -75,14 +103,15 @@ pub async fn get_book_data(isbn: String, id_token: Option<IdToken>, share_id: Op
         Err(e) => {
             log!("Sending an error msg to UI");
             // log!("{:?}", e);
-            WasmResponse::LocalBook(Box::new(Some(WasmResult::Err(format!("{:?}", e)))))
+            let e = WasmError::new(WasmErrorCode::Storage.or_network(&e), &e);
+            WasmResponse::LocalBook(Box::new(Some(WasmResult::Err(e))))
         }
     };
 
     // log!("Book data below:");
     // log!("{:?}", resp);
 
-    report_progress(resp.to_string());
+    runtime.report_progress(&resp);
 
     // get additional photos for the book if there is a share ID
     if let Some(share_id) = share_id {
@@ -109,32 +138,34 @@ pub async fn get_book_data(isbn: String, id_token: Option<IdToken>, share_id: Op
                         None => {
                             book.photos = Some(photo_urls);
                             log!("Photos added to the book (set): {:?}", book.photos);
-
                         }
                     }
 
                     // send the response back to the UI thread
                     let resp = WasmResponse::LocalBook(Box::new(Some(WasmResult::Ok(book))));
-                    report_progress(resp.to_string());
+                    runtime.report_progress(&resp);
                 }
             }
         }
     }
 
-    let _ = sync_book(isbn, &runtime, &id_token).await;
+    outbox::send(&[Mutation::Sync { isbn }], &runtime, &id_token).await;
 }
 
 /// Returns the list of previously scanned books from the local storage.
+/// If `with_cloud_sync` is true, the list is then merged with the cloud DB and unsync'd local books are uploaded.
+/// A failed sync is reported after the local list as `WasmResponse::LocalBooks` with an error.
 /// See `fn report_progress()` for more details.
 #[wasm_bindgen]
-pub async fn get_scanned_books(id_token: Option<IdToken>, with_cloud_sync: bool) {
+pub async fn get_scanned_books(request_id: String, id_token: Option<IdToken>, with_cloud_sync: bool) {
+    let _last = LastMessage(&request_id);
     log!(
         "Getting the list of books from local storage. Sync: {}",
         with_cloud_sync
     );
 
     // need the runtime for the global context and fetch
-    let runtime = match get_runtime().await {
+    let runtime = match get_runtime(&request_id).await {
         Ok(v) => v,
 
         // if this happened it would be a bug
@@ -145,7 +176,7 @@ pub async fn get_scanned_books(id_token: Option<IdToken>, with_cloud_sync: bool)
     };
 
     // get the list of books from the local storage
-    let local_books = books::get(&runtime);
+    let local_books = books::get(&runtime, &id_token).await;
 
     // get Books from local storage and wrap them into a response struct
     let resp = match &local_books {
@@ -157,7 +188,10 @@ pub async fn get_scanned_books(id_token: Option<IdToken>, with_cloud_sync: bool)
         Err(e) => {
             log!("Failed to get list of books");
             log!("{:?}", e);
-            WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(format!("{:?}", e)))))
+            WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(WasmError::new(
+                WasmErrorCode::Storage,
+                e,
+            )))))
         }
     };
 
@@ -165,7 +199,14 @@ pub async fn get_scanned_books(id_token: Option<IdToken>, with_cloud_sync: bool)
     // log!("{:?}", resp);
 
     // send the response back to the UI thread
-    report_progress(resp.to_string());
+    runtime.report_progress(&resp);
+
+    // books scanned before logging in can be added to the account
+    match merge::get_offer(&runtime, &id_token).await {
+        Ok(Some(v)) => runtime.report_progress(&WasmResponse::MergeOffer(Box::new(Some(WasmResult::Ok(v))))),
+        Ok(None) => {}
+        Err(e) => log!("Failed to check the anonymous library: {:?}", e),
+    }
 
     if !with_cloud_sync {
         log!("Skipping cloud sync");
@@ -178,6 +219,11 @@ pub async fn get_scanned_books(id_token: Option<IdToken>, with_cloud_sync: bool)
         Err(_) => Books { books: Vec::new() },
     };
 
+    // send the changes that failed to reach the cloud earlier
+    if let Err(e) = outbox::replay(&runtime, &id_token).await {
+        log!("Failed to replay the outbox: {:?}", e);
+    }
+
     // try to sync the books with the cloud DB
     let resp = match sync_books(local_books, &runtime, &id_token).await {
         Ok(Some(v)) => {
@@ -189,21 +235,28 @@ pub async fn get_scanned_books(id_token: Option<IdToken>, with_cloud_sync: bool)
             log!("No new books from the cloud DB");
             return;
         }
-        Err(_) => {
+        Err(e) => {
+            // the error is also reported to the UI in `WasmResponse::SyncStatus`
             log!("Getting list of cloud DB books failed");
-            // TODO: send a report back to the UI
-            return;
+            let e = WasmError::new(WasmErrorCode::Storage.or_network(&e), &e);
+            WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(e))))
         }
     };
 
     // send the updated list of books to the UI
-    report_progress(resp.to_string());
+    runtime.report_progress(&resp);
 }
 
 /// Updates the status of a book in the local storage.
 /// Returns `WasmResponse::LocalBook::Ok` in a message if successful.
 #[wasm_bindgen]
-pub async fn update_book_status(isbn: String, status: Option<ReadStatus>, id_token: Option<IdToken>) {
+pub async fn update_book_status(
+    request_id: String,
+    isbn: String,
+    status: Option<ReadStatus>,
+    id_token: Option<IdToken>,
+) {
+    let _last = LastMessage(&request_id);
     log!("Updating book status in local storage");
 
     let isbn = match isbn.parse::<u64>() {
@@ -215,7 +268,7 @@ pub async fn update_book_status(isbn: String, status: Option<ReadStatus>, id_tok
     };
 
     // need the runtime for the global context and fetch
-    let runtime = match get_runtime().await {
+    let runtime = match get_runtime(&request_id).await {
         Ok(v) => v,
 
         // if this happened it would be a bug
@@ -226,7 +279,7 @@ pub async fn update_book_status(isbn: String, status: Option<ReadStatus>, id_tok
     };
 
     // get Books from local storage and wrap them into a response struct
-    let resp = match book::update_status(&runtime, isbn, status).await {
+    let resp = match book::update_status(&runtime, isbn, status, &id_token).await {
         Ok(v) => {
             log!("Book status updated");
             // hydrate the book for the front-end
@@ -240,7 +293,8 @@ pub async fn update_book_status(isbn: String, status: Option<ReadStatus>, id_tok
         Err(e) => {
             log!("Failed to update book status");
             log!("{:?}", e);
-            WasmResponse::LocalBook(Box::new(Some(WasmResult::Err(format!("{:?}", e)))))
+            let e = WasmError::new(WasmErrorCode::Storage.or_network(&e), &e);
+            WasmResponse::LocalBook(Box::new(Some(WasmResult::Err(e))))
         }
     };
 
@@ -248,19 +302,20 @@ pub async fn update_book_status(isbn: String, status: Option<ReadStatus>, id_tok
     // log!("{:?}", resp);
 
     // send the response back to the UI thread
-    report_progress(resp.to_string());
+    runtime.report_progress(&resp);
 
-    let _ = sync_book(isbn, &runtime, &id_token).await;
+    outbox::send(&[Mutation::Sync { isbn }], &runtime, &id_token).await;
 }
 
 /// Deletes a book from the local storage.
 /// Returns error or success via an async message.
 #[wasm_bindgen]
-pub async fn delete_book(isbn: String, id_token: Option<IdToken>) {
+pub async fn delete_book(request_id: String, isbn: String, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
     log!("Deleting book from local storage");
 
     // need the runtime for the global context and fetch
-    let runtime = match get_runtime().await {
+    let runtime = match get_runtime(&request_id).await {
         Ok(v) => v,
 
         // if this happened it would be a bug
@@ -271,15 +326,19 @@ pub async fn delete_book(isbn: String, id_token: Option<IdToken>) {
     };
 
     // get Books from local storage and wrap them into a response struct
-    let resp = match book::delete(&runtime, &isbn).await {
+    let (resp, deleted) = match book::delete(&runtime, &isbn, &id_token).await {
         Ok(_) => {
             log!("Book deleted");
-            WasmResponse::Deleted(Box::new(Some(WasmResult::Ok(isbn.clone()))))
+            (
+                WasmResponse::Deleted(Box::new(Some(WasmResult::Ok(isbn.clone())))),
+                true,
+            )
         }
         Err(e) => {
             log!("Failed to delete book {isbn}");
             log!("{:?}", e);
-            WasmResponse::Deleted(Box::new(Some(WasmResult::Err(format!("{:?}", e)))))
+            let e = WasmError::new(WasmErrorCode::Storage.or_network(&e), &e);
+            (WasmResponse::Deleted(Box::new(Some(WasmResult::Err(e)))), false)
         }
     };
 
@@ -287,16 +346,33 @@ pub async fn delete_book(isbn: String, id_token: Option<IdToken>) {
     // log!("{:?}", resp);
 
     // send the response back to the UI thread
-    report_progress(resp.to_string());
+    runtime.report_progress(&resp);
 
-    // TODO: handle possible errors
-    let _ = crate::sync::delete_book(&isbn, &runtime, &id_token).await;
+    // the deletion is retried from the outbox if it fails
+    match isbn.parse::<u64>() {
+        Ok(isbn) if deleted => outbox::send(&[Mutation::Delete { isbn }], &runtime, &id_token).await,
+        Ok(_) => {}
+        Err(e) => log!("Failed to parse ISBN {isbn}. It's a bug. {:?}", e),
+    }
 }
 
-/// Uploads a file to S3.
+/// Uploads the selected files to S3, a few at a time, and adds all new photos to the book in one save.
+/// The photos are downscaled to `max_size` pixels on the longest side and re-encoded as JPEG at `quality` (1..=100)
+/// without any metadata. The defaults are used if the values are not set.
+/// Files that fail to upload are kept in the outbox and retried later.
+/// The progress is reported in `WasmResponse::UploadProgress` messages and the upload can be
+/// cancelled with `cancel_upload()`.
 /// Returns error or success via an async message.
 #[wasm_bindgen]
-pub async fn upload_pic(isbn: String, files: FileList, id_token: Option<IdToken>) {
+pub async fn upload_pic(
+    request_id: String,
+    isbn: String,
+    files: FileList,
+    max_size: Option<u32>,
+    quality: Option<u8>,
+    id_token: Option<IdToken>,
+) {
+    let _last = LastMessage(&request_id);
     log!("Uploading an image to S3");
 
     let isbn = match isbn.parse::<u64>() {
@@ -308,7 +384,7 @@ pub async fn upload_pic(isbn: String, files: FileList, id_token: Option<IdToken>
     };
 
     // need the runtime for the global context and fetch
-    let runtime = match get_runtime().await {
+    let runtime = match get_runtime(&request_id).await {
         Ok(v) => v,
 
         // if this happened it would be a bug
@@ -319,8 +395,9 @@ pub async fn upload_pic(isbn: String, files: FileList, id_token: Option<IdToken>
     };
 
     // get Books from local storage and wrap them into a response struct
-    let resp = match photos::upload(&runtime, isbn, files, &id_token).await {
-        Some(v) => {
+    let settings = PhotoSettings::new(max_size, quality);
+    let resp = match photos::upload(&runtime, isbn, files, settings, &id_token).await {
+        Ok(v) => {
             log!("Photos uploaded");
             // hydrate the book for the front-end
             let v = if let Some(user) = jwt::get_user_details(&id_token) {
@@ -330,9 +407,16 @@ pub async fn upload_pic(isbn: String, files: FileList, id_token: Option<IdToken>
             };
             WasmResponse::LocalBook(Box::new(Some(WasmResult::Ok(v))))
         }
-        None => {
+        Err(e) => {
             log!("Photo upload failed for {isbn}");
-            WasmResponse::LocalBook(Box::new(Some(WasmResult::Err("Failed to upload photo".to_string()))))
+            let code = if e.is::<UploadCancelled>() {
+                WasmErrorCode::Cancelled
+            } else if e.is::<InvalidPhoto>() {
+                WasmErrorCode::InvalidFile
+            } else {
+                WasmErrorCode::Upload
+            };
+            WasmResponse::LocalBook(Box::new(Some(WasmResult::Err(WasmError::new(code, &e)))))
         }
     };
 
@@ -340,5 +424,318 @@ pub async fn upload_pic(isbn: String, files: FileList, id_token: Option<IdToken>
     // log!("{:?}", resp);
 
     // send the response back to the UI thread
-    report_progress(resp.to_string());
+    runtime.report_progress(&resp);
+}
+
+/// Aborts the photo uploads of an earlier call, e.g. `upload_pic`, identified by its request ID.
+/// The cancelled call replies with `WasmErrorCode::Cancelled` and its photos are not retried.
+/// Uploads retried from the outbox are cancelled with the request ID of the call that replays them.
+#[wasm_bindgen]
+pub async fn cancel_upload(request_id: String, upload_request_id: String) {
+    let _last = LastMessage(&request_id);
+
+    let aborted = abort_uploads(&upload_request_id);
+    log!("Uploads cancelled for {upload_request_id}: {aborted}");
+}
+
+/// Copies book records from the local storage into IndexedDB.
+/// Web workers have no access to the local storage, so this has to be called once from the UI thread
+/// before moving the module into a dedicated worker. Existing IndexedDB records are kept.
+/// Returns `WasmResponse::LocalBooks` with the list of books from the local storage if successful.
+#[wasm_bindgen]
+pub async fn migrate_local_storage(request_id: String, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Copying local storage to IndexedDB");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let resp = match storage::copy_local_storage_to_idb(&runtime).await {
+        Ok(v) => {
+            log!("Books copied to IndexedDB: {v}");
+            match books::get(&runtime, &id_token).await {
+                Ok(v) => WasmResponse::LocalBooks(Box::new(Some(WasmResult::Ok(v.lean_copy())))),
+                Err(e) => {
+                    let e = WasmError::new(WasmErrorCode::Storage, &e);
+                    WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(e))))
+                }
+            }
+        }
+        Err(e) => {
+            log!("Failed to copy local storage to IndexedDB");
+            log!("{:?}", e);
+            WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(WasmError::new(
+                WasmErrorCode::Storage,
+                &e,
+            )))))
+        }
+    };
+
+    // send the response back to the UI thread
+    runtime.report_progress(&resp);
+}
+
+/// Reports the number of books and how much space they take in the local storage.
+/// Only the books of the user from the token are counted.
+/// Returns `WasmResponse::StorageUsage` in a message.
+#[wasm_bindgen]
+pub async fn get_storage_usage(request_id: String, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Getting local storage usage");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let resp = match books::get_usage(&runtime, &id_token).await {
+        Ok(v) => {
+            log!("Storage usage: {} books, {} bytes", v.records, v.bytes);
+            WasmResponse::StorageUsage(Box::new(Some(WasmResult::Ok(v))))
+        }
+        Err(e) => {
+            log!("Failed to get storage usage");
+            log!("{:?}", e);
+            WasmResponse::StorageUsage(Box::new(Some(WasmResult::Err(WasmError::new(
+                WasmErrorCode::Storage,
+                &e,
+            )))))
+        }
+    };
+
+    // send the response back to the UI thread
+    runtime.report_progress(&resp);
+}
+
+/// Checks the book records in the local storage for unparseable JSON, ISBN mismatches,
+/// missing titles and duplicates.
+/// Broken records are fixed or quarantined if `repair` is true, otherwise they are only reported.
+/// Only the books of the user from the token are checked.
+/// Returns `WasmResponse::LibraryCheck` in a message.
+#[wasm_bindgen]
+pub async fn check_local_library(request_id: String, repair: bool, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Checking local library. Repair: {repair}");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let resp = match integrity::check(&runtime, repair, &id_token).await {
+        Ok(v) => {
+            log!("Library checked: {} records, {} issues", v.checked, v.issues.len());
+            WasmResponse::LibraryCheck(Box::new(Some(WasmResult::Ok(v))))
+        }
+        Err(e) => {
+            log!("Failed to check local library");
+            log!("{:?}", e);
+            WasmResponse::LibraryCheck(Box::new(Some(WasmResult::Err(WasmError::new(
+                WasmErrorCode::Storage,
+                &e,
+            )))))
+        }
+    };
+
+    // send the response back to the UI thread
+    runtime.report_progress(&resp);
+}
+
+/// Adds the books scanned without logging in to the account of the user from the token
+/// if `accept` is true, or leaves them in the anonymous library if it is false.
+/// It is a reply to `WasmResponse::MergeOffer`. The offer is only repeated for books scanned without logging in later.
+/// Returns `WasmResponse::LocalBooks` with the user's library in a message.
+#[wasm_bindgen]
+pub async fn merge_anonymous_library(request_id: String, accept: bool, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Merging anonymous library: {accept}");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let merged = match merge::merge(&runtime, accept, &id_token).await {
+        Ok(v) => v,
+        Err(e) => {
+            log!("Failed to merge anonymous library");
+            log!("{:?}", e);
+            let e = WasmError::new(WasmErrorCode::Storage, &e);
+            let resp = WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(e))));
+            runtime.report_progress(&resp);
+            return;
+        }
+    };
+
+    // send the updated list of books to the UI
+    let resp = match books::get(&runtime, &id_token).await {
+        Ok(v) => WasmResponse::LocalBooks(Box::new(Some(WasmResult::Ok(v.lean_copy())))),
+        Err(e) => WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(WasmError::new(
+            WasmErrorCode::Storage,
+            &e,
+        ))))),
+    };
+    runtime.report_progress(&resp);
+
+    // the merged books are new to the cloud
+    let mutations = merged
+        .into_iter()
+        .map(|isbn| Mutation::Sync { isbn })
+        .collect::<Vec<_>>();
+    outbox::send(&mutations, &runtime, &id_token).await;
+}
+
+/// Reports whether the library of the user from the token is in sync with the cloud.
+/// Returns `WasmResponse::SyncStatus` in a message.
+#[wasm_bindgen]
+pub async fn get_sync_status(request_id: String, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Getting sync status");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    sync::report_sync_status(None, &runtime, &id_token).await;
+}
+
+/// Sends the changes that failed to reach the cloud earlier, e.g. while the device was offline.
+/// The UI should call it when the browser goes back online.
+/// The changes are also sent by every entry point that makes a change and by `get_scanned_books`.
+#[wasm_bindgen]
+pub async fn replay_outbox(request_id: String, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Replaying the outbox");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = outbox::replay(&runtime, &id_token).await {
+        log!("Failed to replay the outbox: {:?}", e);
+    }
+}
+
+/// Exports all local books of the user from the token, or the anonymous books if there is no token.
+/// Returns `WasmResponse::LibraryExport` in a message. The UI saves it as a JSON file.
+#[wasm_bindgen]
+pub async fn export_library(request_id: String, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Exporting local library");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let resp = match backup::export(&runtime, &id_token).await {
+        Ok(v) => WasmResponse::LibraryExport(Box::new(Some(WasmResult::Ok(v)))),
+        Err(e) => {
+            log!("Failed to export local library");
+            log!("{:?}", e);
+            WasmResponse::LibraryExport(Box::new(Some(WasmResult::Err(WasmError::new(
+                WasmErrorCode::Storage,
+                &e,
+            )))))
+        }
+    };
+
+    // send the response back to the UI thread
+    runtime.report_progress(&resp);
+}
+
+/// Restores the local library from a file created by `export_library()`.
+/// The local books are replaced if `replace` is true, otherwise the newer copy of each book is kept.
+/// Replacing also deletes the sync'd books that are not in the file from the cloud on the next sync.
+/// Returns `WasmResponse::LibraryImport` followed by `WasmResponse::LocalBooks` in messages.
+#[wasm_bindgen]
+pub async fn import_library(request_id: String, files: FileList, replace: bool, id_token: Option<IdToken>) {
+    let _last = LastMessage(&request_id);
+    log!("Importing local library. Replace: {replace}");
+
+    // need the runtime for the global context and fetch
+    let runtime = match get_runtime(&request_id).await {
+        Ok(v) => v,
+
+        // if this happened it would be a bug
+        Err(e) => {
+            log!("Failed to get runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let resp = match backup::import(&runtime, files, replace, &id_token).await {
+        Ok(v) => WasmResponse::LibraryImport(Box::new(Some(WasmResult::Ok(v)))),
+        Err(e) => {
+            log!("Failed to import local library");
+            log!("{:?}", e);
+            // only a bad file is reported as invalid, the rest are failures of the local storage
+            let code = if e.is::<InvalidBackup>() {
+                WasmErrorCode::InvalidFile
+            } else {
+                WasmErrorCode::Storage
+            };
+            let e = WasmError::new(code, &e);
+            let resp = WasmResponse::LibraryImport(Box::new(Some(WasmResult::Err(e))));
+            runtime.report_progress(&resp);
+            return;
+        }
+    };
+    runtime.report_progress(&resp);
+
+    // send the updated list of books to the UI
+    let resp = match books::get(&runtime, &id_token).await {
+        Ok(v) => WasmResponse::LocalBooks(Box::new(Some(WasmResult::Ok(v.lean_copy())))),
+        Err(e) => WasmResponse::LocalBooks(Box::new(Some(WasmResult::Err(WasmError::new(
+            WasmErrorCode::Storage,
+            &e,
+        ))))),
+    };
+    runtime.report_progress(&resp);
 }
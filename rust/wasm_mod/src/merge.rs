@@ -0,0 +1,259 @@
+use crate::book;
+use crate::books;
+use crate::platform::Platform;
+use crate::storage::{get_anonymous_storage, get_local_storage, KeyValueStore};
+use anyhow::{bail, Result};
+use bookworm_types::{Book, Books, IdToken};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// A key in the user namespace with the user's decision on merging the anonymous library.
+/// Value: `MergeDecision` as JSON.
+const MERGE_DECISION_KEY: &str = "anonymous-merge";
+
+/// What the user decided about the anonymous books that were offered for merging.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct MergeDecision {
+    /// True if the books were merged into the account, false if the user declined.
+    accepted: bool,
+    /// The anonymous books the user chose to keep out of the account.
+    /// Merged books leave the anonymous library, so any book found there later,
+    /// including one that failed to merge, is offered again.
+    isbns: HashSet<u64>,
+}
+
+/// Books scanned without logging in that can be added to the user's account.
+/// Sent to the UI as `WasmResponse::MergeOffer`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeOffer {
+    /// A lean copy of the anonymous books for the UI to show what would be merged.
+    pub books: Books,
+}
+
+/// Returns the anonymous library if it has books the signed-in user has not decided about yet.
+/// Returns None if there is no user, no anonymous books or the user already decided about all of them.
+pub(crate) async fn get_offer(runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<Option<MergeOffer>> {
+    let user_ls = get_local_storage(runtime, id_token).await?;
+    if !user_ls.is_user() {
+        return Ok(None);
+    }
+
+    let anonymous_books = books::get(runtime, &None).await?;
+    if anonymous_books.books.is_empty() {
+        return Ok(None);
+    }
+
+    // the offer is only repeated if more books were scanned without logging in
+    let decision = get_decision(&user_ls).await;
+    if anonymous_books.books.iter().all(|v| decision.isbns.contains(&v.isbn)) {
+        log!("Anonymous library merge already decided: {}", decision.accepted);
+        return Ok(None);
+    }
+
+    log!("Anonymous books to offer for merging: {}", anonymous_books.books.len());
+    Ok(Some(MergeOffer {
+        books: anonymous_books.lean_copy(),
+    }))
+}
+
+/// Moves the anonymous books into the namespace of the user from the token if `accept` is true.
+/// A declined offer is recorded for the current anonymous books, so they are not offered again.
+/// Books that exist in both libraries keep the account's photos and take the latest change to each field.
+/// Returns the ISBNs of the merged books. They are not sync'd yet.
+pub(crate) async fn merge(runtime: &impl Platform, accept: bool, id_token: &Option<IdToken>) -> Result<Vec<u64>> {
+    let user_ls = get_local_storage(runtime, id_token).await?;
+    if !user_ls.is_user() {
+        bail!("Cannot merge the anonymous library without a valid token");
+    }
+
+    let anonymous_books = books::get(runtime, &None).await?.books;
+    let decision = MergeDecision {
+        accepted: accept,
+        isbns: match accept {
+            true => HashSet::new(),
+            false => anonymous_books.iter().map(|v| v.isbn).collect(),
+        },
+    };
+    let mut merged = Vec::new();
+
+    if accept {
+        let anonymous_ls = get_anonymous_storage(runtime).await?;
+
+        for anonymous_book in anonymous_books {
+            let isbn = anonymous_book.isbn;
+
+            // the user may already have the same book in the account
+            let user_book = match user_ls.get_item(&isbn.to_string()).await {
+                // a deleted account copy is replaced by the anonymous one
+                Ok(Some(v)) => serde_json::from_str::<Book>(&v).ok().filter(|v| !v.is_deleted()),
+                _ => None,
+            };
+
+            let book = match user_book {
+                Some(user_book) => merge_books(user_book, anonymous_book),
+                None => anonymous_book,
+            };
+
+            // the book is new to the account and has to be sync'd
+            let book = book.without_sync_timestamp();
+            if let Err(e) = book::save(&book, runtime, id_token).await {
+                log!("Failed to merge {isbn} into the account: {:?}", e);
+                continue;
+            }
+
+            if let Err(e) = anonymous_ls.remove_item(&isbn.to_string()).await {
+                log!("Failed to remove merged {isbn} from the anonymous library: {:?}", e);
+            }
+
+            merged.push(isbn);
+        }
+
+        log!("Merged {} anonymous books into the account", merged.len());
+    }
+
+    match serde_json::to_string(&decision) {
+        Ok(v) => {
+            if let Err(e) = user_ls.set_item(MERGE_DECISION_KEY, &v).await {
+                log!("Failed to save anonymous merge decision: {:?}", e);
+            }
+        }
+        Err(e) => log!("Failed to serialize anonymous merge decision: {:?}", e),
+    }
+
+    Ok(merged)
+}
+
+/// Returns the last merge decision of the user or an empty one if there is none.
+/// Invalid values, including the plain `merged` and `declined` of older versions, are logged
+/// and ignored, so the anonymous books are offered again.
+async fn get_decision(user_ls: &impl KeyValueStore) -> MergeDecision {
+    match user_ls.get_item(MERGE_DECISION_KEY).await {
+        Ok(Some(v)) => match serde_json::from_str::<MergeDecision>(&v) {
+            Ok(v) => v,
+            Err(e) => {
+                log!("Invalid anonymous merge decision {v}: {:?}", e);
+                MergeDecision::default()
+            }
+        },
+        Ok(None) => MergeDecision::default(),
+        Err(e) => {
+            log!("Failed to get anonymous merge decision: {:?}", e);
+            MergeDecision::default()
+        }
+    }
+}
+
+/// Merges the anonymous copy of a book into the account copy.
+/// Photos and the share ID only exist in the account, so they are always kept.
+/// Title, authors and status are merged field by field.
+fn merge_books(user_book: Book, anonymous_book: Book) -> Book {
+    let mut book = user_book;
+
+    // the latest change to each field wins
+    book.merge_fields(&anonymous_book);
+
+    if book.volume_info.is_none() {
+        book.volume_info = anonymous_book.volume_info;
+        book.cover = anonymous_book.cover;
+    }
+
+    book
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::MockPlatform;
+    use futures::executor::block_on;
+
+    const ISBN_A: u64 = 9780143107712;
+    const ISBN_B: u64 = 9781761186769;
+
+    fn token() -> Option<IdToken> {
+        Some("test-token".to_owned())
+    }
+
+    fn new_book(isbn: u64, title: &str) -> Book {
+        let mut book = Book::new(isbn);
+        book.title = Some(title.to_owned());
+        book
+    }
+
+    fn save_anonymous(platform: &MockPlatform, book: &Book) {
+        platform
+            .storage
+            .borrow_mut()
+            .insert(book.isbn.to_string(), serde_json::to_string(book).unwrap());
+    }
+
+    fn offered(platform: &MockPlatform) -> Option<Vec<u64>> {
+        block_on(get_offer(platform, &token()))
+            .unwrap()
+            .map(|v| v.books.books.iter().map(|v| v.isbn).collect())
+    }
+
+    #[test]
+    fn offer_needs_user_and_books() {
+        let platform = MockPlatform::default();
+        assert_eq!(offered(&platform), None);
+
+        save_anonymous(&platform, &new_book(ISBN_A, "Anonymous"));
+        assert_eq!(offered(&platform), Some(vec![ISBN_A]));
+        assert!(block_on(get_offer(&platform, &None)).unwrap().is_none());
+    }
+
+    #[test]
+    fn declined_offer_is_repeated_for_new_books() {
+        let platform = MockPlatform::default();
+        save_anonymous(&platform, &new_book(ISBN_A, "Anonymous"));
+
+        assert!(block_on(merge(&platform, false, &token())).unwrap().is_empty());
+
+        // the declined book stays anonymous and is not offered again
+        assert!(platform.storage.borrow().contains_key(&ISBN_A.to_string()));
+        assert!(platform.get_user_item(&ISBN_A.to_string()).is_none());
+        assert_eq!(offered(&platform), None);
+
+        // a book scanned without logging in later renews the offer
+        save_anonymous(&platform, &new_book(ISBN_B, "Scanned later"));
+        let offer = offered(&platform).unwrap();
+        assert_eq!(offer.len(), 2);
+        assert!(offer.contains(&ISBN_B));
+    }
+
+    #[test]
+    fn accepted_offer_merges_books() {
+        let platform = MockPlatform::default();
+        let user_book = new_book(ISBN_A, "Account").with_new_sync_timestamp();
+        let user_book = user_book.with_new_photo("1727129470".to_owned());
+        platform.set_user_item(&ISBN_A.to_string(), &serde_json::to_string(&user_book).unwrap());
+        save_anonymous(&platform, &new_book(ISBN_A, "Anonymous"));
+        save_anonymous(&platform, &new_book(ISBN_B, "Anonymous only"));
+
+        let mut merged = block_on(merge(&platform, true, &token())).unwrap();
+        merged.sort();
+
+        assert_eq!(merged, vec![ISBN_A, ISBN_B]);
+        let book = serde_json::from_str::<Book>(&platform.get_user_item(&ISBN_A.to_string()).unwrap()).unwrap();
+        assert_eq!(book.photos, user_book.photos);
+        assert!(book.needs_sync());
+        assert!(platform.get_user_item(&ISBN_B.to_string()).is_some());
+        assert!(!platform.storage.borrow().contains_key(&ISBN_B.to_string()));
+        assert_eq!(offered(&platform), None);
+
+        // the same book scanned again without logging in is offered again
+        save_anonymous(&platform, &new_book(ISBN_B, "Scanned again"));
+        assert_eq!(offered(&platform), Some(vec![ISBN_B]));
+    }
+
+    #[test]
+    fn old_decision_is_ignored() {
+        let platform = MockPlatform::default();
+        save_anonymous(&platform, &new_book(ISBN_A, "Anonymous"));
+        platform.set_user_item(MERGE_DECISION_KEY, "declined");
+
+        assert_eq!(offered(&platform), Some(vec![ISBN_A]));
+    }
+}
@@ -0,0 +1,762 @@
+use crate::photos::{self, Photo};
+use crate::platform::Platform;
+use crate::storage::{get_local_storage, FileStore, KeyValueStore};
+use crate::sync;
+use crate::RetryAfter;
+use anyhow::{bail, Result};
+use bookworm_types::{IdToken, PhotoFormat};
+use chrono::{DateTime, Duration, Utc};
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::HashSet;
+use web_sys::File;
+
+/// A key in the user namespace with the list of changes waiting to be sent to the cloud.
+/// Value: `Outbox` as JSON.
+const OUTBOX_KEY: &str = "outbox";
+
+/// Photos waiting in the outbox are stored in the file storage under this prefix + the entry ID.
+const OUTBOX_PHOTO_KEY_PREFIX: &str = "outbox-photo-";
+
+/// The delay before the first retry. It doubles with every failed attempt.
+const RETRY_BASE_DELAY_SECS: i64 = 5;
+
+/// The longest delay between retries.
+const RETRY_MAX_DELAY_SECS: i64 = 3600;
+
+/// A change is dropped after this many failed attempts.
+/// The book records stay unsync'd locally and are sent by the next full sync.
+const MAX_ATTEMPTS: u32 = 10;
+
+thread_local! {
+    /// Entry points run concurrently, but only one of them should be sending the outbox at a time.
+    static REPLAYING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Every change of the outbox is a load, modify and save with storage calls in between,
+/// so concurrent entry points would overwrite each other's entries without taking turns.
+/// It is not held while waiting for the network.
+static OUTBOX_LOCK: Mutex<()> = Mutex::new(());
+
+/// A change made locally that has to reach the cloud.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub(crate) enum Mutation {
+    /// Send the local copy of the book, e.g. a new book or a status change.
+    /// The latest copy is read from the local storage when the change is sent.
+    Sync { isbn: u64 },
+    /// Delete the book in the cloud. The local copy is a tombstone by this time.
+    Delete { isbn: u64 },
+    /// Upload the photo stored under `OUTBOX_PHOTO_KEY_PREFIX` + entry ID.
+    Photo { isbn: u64 },
+}
+
+impl Mutation {
+    fn isbn(&self) -> u64 {
+        match self {
+            Mutation::Sync { isbn } | Mutation::Delete { isbn } | Mutation::Photo { isbn } => *isbn,
+        }
+    }
+}
+
+/// A queued change with its retry state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// Unique within the outbox.
+    id: u64,
+    mutation: Mutation,
+    /// The number of failed attempts so far.
+    attempts: u32,
+    /// The entry is not sent before this time.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    not_before: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|v| v <= now)
+    }
+}
+
+/// Changes waiting to be sent to the cloud in the order they were made.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct Outbox {
+    /// The ID for the next entry.
+    next_id: u64,
+    entries: Vec<Entry>,
+}
+
+impl Outbox {
+    /// Adds the change to the end of the queue unless it is already covered by a queued change.
+    /// Returns the IDs of the entries that were dropped as redundant.
+    /// - a sync reads the latest local copy, so one queued sync per book is enough
+    /// - a delete makes the queued syncs and photos of the book pointless
+    fn push(&mut self, mutation: Mutation) -> Vec<u64> {
+        let isbn = mutation.isbn();
+        let mut dropped = Vec::new();
+
+        match mutation {
+            Mutation::Sync { .. } => {
+                // any queued sync comes after the last queued delete, see below
+                if self.entries.iter().any(|v| v.mutation == mutation) {
+                    return dropped;
+                }
+            }
+            Mutation::Delete { .. } => {
+                self.entries.retain(|v| {
+                    let redundant = v.mutation.isbn() == isbn && !matches!(v.mutation, Mutation::Delete { .. });
+                    if redundant {
+                        dropped.push(v.id);
+                    }
+                    !redundant
+                });
+                if self.entries.iter().any(|v| v.mutation == mutation) {
+                    return dropped;
+                }
+            }
+            Mutation::Photo { .. } => {}
+        }
+
+        self.entries.push(Entry {
+            id: self.next_id,
+            mutation,
+            attempts: 0,
+            not_before: None,
+        });
+        self.next_id += 1;
+
+        dropped
+    }
+
+    /// Returns the entries that can be sent now in the order they were added.
+    /// An entry waiting for a retry holds back the later entries of the same book.
+    fn due(&self, now: DateTime<Utc>) -> Vec<Entry> {
+        let mut waiting = HashSet::new();
+        self.entries
+            .iter()
+            .filter(|v| {
+                let isbn = v.mutation.isbn();
+                if waiting.contains(&isbn) || !v.is_due(now) {
+                    waiting.insert(isbn);
+                    return false;
+                }
+                true
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the entries that were sent and schedules a retry for the ones that failed.
+    /// Returns the IDs of the entries that were dropped after too many attempts.
+    fn settle(&mut self, sent: &[u64], failed: &[(u64, RetryAfter)], now: DateTime<Utc>) -> Vec<u64> {
+        self.entries.retain(|v| !sent.contains(&v.id));
+
+        let mut dropped = Vec::new();
+        for (id, reason) in failed {
+            if let Some(entry) = self.entries.iter_mut().find(|v| v.id == *id) {
+                entry.attempts += 1;
+                entry.not_before = Some(now + retry_delay(entry.attempts, reason));
+                if entry.attempts >= MAX_ATTEMPTS {
+                    log!(
+                        "Outbox entry dropped after {} attempts: {:?}",
+                        entry.attempts,
+                        entry.mutation
+                    );
+                    dropped.push(entry.id);
+                }
+            }
+        }
+        self.entries.retain(|v| !dropped.contains(&v.id));
+
+        dropped
+    }
+}
+
+/// Returns how long to wait before the next attempt.
+/// The server's `Retry-After` is used if there is one, otherwise the delay grows exponentially.
+fn retry_delay(attempts: u32, reason: &RetryAfter) -> Duration {
+    match reason {
+        RetryAfter::Seconds(v) if *v > 0 => Duration::seconds(*v),
+        _ => {
+            let exponent = attempts.saturating_sub(1).min(20);
+            Duration::seconds((RETRY_BASE_DELAY_SECS << exponent).min(RETRY_MAX_DELAY_SECS))
+        }
+    }
+}
+
+/// Adds the changes to the outbox and tries to send everything that is due.
+/// Nothing is queued for anonymous users because their books are not sync'd.
+/// All errors are logged.
+pub(crate) async fn send(mutations: &[Mutation], runtime: &impl Platform, id_token: &Option<IdToken>) {
+    if let Err(e) = enqueue(mutations, runtime, id_token).await {
+        log!("Failed to add changes to the outbox: {:?}", e);
+    }
+
+    if let Err(e) = replay(runtime, id_token).await {
+        log!("Failed to replay the outbox: {:?}", e);
+    }
+}
+
+/// Adds the changes to the outbox without sending them.
+async fn enqueue(mutations: &[Mutation], runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<()> {
+    let ls = get_local_storage(runtime, id_token).await?;
+    if !ls.is_user() {
+        log!("No user. Outbox skipped.");
+        return Ok(());
+    }
+
+    let lock = OUTBOX_LOCK.lock().await;
+    let mut outbox = load(&ls).await?;
+    let mut dropped = Vec::new();
+    for mutation in mutations {
+        dropped.extend(outbox.push(mutation.clone()));
+    }
+    save(&ls, &outbox).await?;
+    drop(lock);
+
+    remove_photos(&dropped, runtime, id_token).await;
+
+    Ok(())
+}
+
+/// Saves the photo in the file storage and adds its upload to the outbox.
+/// Only the photos of signed-in users can be uploaded.
+pub(crate) async fn enqueue_photo(
+    isbn: u64,
+    file: &File,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> Result<()> {
+    let ls = get_local_storage(runtime, id_token).await?;
+    if !ls.is_user() {
+        bail!("Cannot queue a photo upload without a valid token");
+    }
+
+    let files = runtime.file_storage(id_token).await?;
+
+    // the ID is reserved before the file is saved under it, so concurrent uploads get different keys
+    // the lock is held until the file is saved, so that a replay never sees the entry without its file
+    let _lock = OUTBOX_LOCK.lock().await;
+    let mut outbox = load(&ls).await?;
+    outbox.push(Mutation::Photo { isbn });
+    let id = outbox.next_id - 1;
+    save(&ls, &outbox).await?;
+
+    if let Err(e) = files.set_file(&photo_key(id), file).await {
+        outbox.entries.retain(|v| v.id != id);
+        if let Err(e) = save(&ls, &outbox).await {
+            log!("Failed to remove the photo entry {id}: {:?}", e);
+        }
+        bail!("Failed to save the photo for {isbn}: {:?}", e);
+    }
+
+    log!("Photo upload for {isbn} added to the outbox");
+
+    Ok(())
+}
+
+/// Sends the changes from the outbox in the order they were made.
+/// Entries waiting for a retry are skipped along with the later changes to the same book.
+/// Stops at the first failure because the network is likely to be down.
+/// Consecutive syncs are sent in a single request.
+/// The progress and the outcome are reported to the UI as `WasmResponse::SyncStatus`.
+/// Returns an error if some of the changes could not be sent.
+pub(crate) async fn replay(runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<()> {
+    // nothing to do if the user is not logged in
+    if id_token.is_none() {
+        log!("No token. Outbox replay skipped.");
+        return Ok(());
+    }
+
+    let ls = get_local_storage(runtime, id_token).await?;
+    if !ls.is_user() {
+        return Ok(());
+    }
+
+    if REPLAYING.with(|v| v.replace(true)) {
+        log!("Outbox replay already in progress");
+        return Ok(());
+    }
+
+    sync::start_sync(runtime, id_token).await;
+    let result = replay_due(&ls, runtime, id_token).await;
+    REPLAYING.with(|v| v.set(false));
+    sync::finish_sync(
+        result.as_ref().map_err(|e| format!("{:#}", e)).copied(),
+        runtime,
+        id_token,
+    )
+    .await;
+
+    result
+}
+
+/// Returns the number of changes waiting in the outbox.
+/// Errors are logged and counted as an empty outbox.
+pub(crate) async fn pending(ls: &impl KeyValueStore) -> usize {
+    match load(ls).await {
+        Ok(v) => v.entries.len(),
+        Err(e) => {
+            log!("{:?}", e);
+            0
+        }
+    }
+}
+
+/// The body of `replay` that runs with the replay flag set.
+async fn replay_due(ls: &impl KeyValueStore, runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<()> {
+    let lock = OUTBOX_LOCK.lock().await;
+    let mut due = load(ls).await?.due(Utc::now()).into_iter().peekable();
+    drop(lock);
+
+    while let Some(entry) = due.next() {
+        let mut sent = Vec::new();
+        let mut failed = Vec::new();
+
+        match entry.mutation {
+            Mutation::Sync { .. } => {
+                // the following syncs of other books go into the same request
+                let mut batch = vec![entry];
+                while let Some(v) = due.next_if(|v| matches!(v.mutation, Mutation::Sync { .. })) {
+                    batch.push(v);
+                }
+                let isbns = batch.iter().map(|v| v.mutation.isbn()).collect::<Vec<_>>();
+
+                match sync::push_books(&isbns, runtime, id_token).await {
+                    Ok(v) => {
+                        for entry in batch {
+                            match v.iter().find(|(isbn, _)| *isbn == entry.mutation.isbn()) {
+                                Some((_, reason)) => failed.push((entry.id, reason.clone())),
+                                None => sent.push(entry.id),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log!("Failed to sync books from the outbox: {:?}", e);
+                        failed.extend(batch.into_iter().map(|v| (v.id, RetryAfter::Never)));
+                    }
+                }
+            }
+            Mutation::Delete { isbn } => match sync::delete_book(&isbn.to_string(), runtime, id_token).await {
+                Ok(()) => sent.push(entry.id),
+                Err(e) => failed.push((entry.id, e)),
+            },
+            // a cancelled upload is settled like a sent one because the user does not want it retried
+            Mutation::Photo { isbn } => match upload_queued_photo(entry.id, isbn, runtime, id_token).await {
+                Ok(()) | Err(RetryAfter::Cancelled) => {
+                    remove_photos(&[entry.id], runtime, id_token).await;
+                    sent.push(entry.id);
+                }
+                Err(e) => failed.push((entry.id, e)),
+            },
+        }
+
+        // the outbox is reloaded because more changes may have been added while waiting for the network
+        let lock = OUTBOX_LOCK.lock().await;
+        let mut outbox = load(ls).await?;
+        let dropped = outbox.settle(&sent, &failed, Utc::now());
+        save(ls, &outbox).await?;
+        drop(lock);
+
+        remove_photos(&dropped, runtime, id_token).await;
+
+        if !failed.is_empty() {
+            log!("Outbox replay stopped with {} entries left", outbox.entries.len());
+            bail!(
+                "Failed to send {} changes to the cloud. They will be retried later.",
+                failed.len()
+            );
+        }
+    }
+
+    log!("Outbox replayed, entries left: {}", pending(ls).await);
+
+    Ok(())
+}
+
+/// Uploads a photo saved by `enqueue_photo`.
+/// A photo that is no longer in the file storage is considered sent because there is nothing to retry.
+async fn upload_queued_photo(
+    id: u64,
+    isbn: u64,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> crate::Result<()> {
+    let file = match runtime.file_storage(id_token).await {
+        Ok(files) => match files.get_file(&photo_key(id)).await {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                log!("Queued photo for {isbn} not found");
+                return Ok(());
+            }
+            Err(e) => {
+                log!("Failed to get queued photo for {isbn}: {:?}", e);
+                return Err(RetryAfter::Never);
+            }
+        },
+        Err(e) => {
+            log!("Failed to get file storage: {:?}", e);
+            return Err(RetryAfter::Never);
+        }
+    };
+
+    // the outbox only has the files made by `prepare_photo`, which are always JPEG
+    let photo = Photo {
+        file,
+        format: PhotoFormat::Jpeg,
+    };
+    photos::upload_photo(runtime, isbn, &photo, id_token).await.map(|_| ())
+}
+
+/// Removes the photos of the outbox entries with the given IDs from the file storage.
+/// Entries of other kinds have no files, so removing them does nothing.
+/// Errors are logged.
+async fn remove_photos(ids: &[u64], runtime: &impl Platform, id_token: &Option<IdToken>) {
+    if ids.is_empty() {
+        return;
+    }
+
+    let files = match runtime.file_storage(id_token).await {
+        Ok(v) => v,
+        Err(e) => {
+            log!("Failed to get file storage: {:?}", e);
+            return;
+        }
+    };
+
+    for id in ids {
+        if let Err(e) = files.remove_item(&photo_key(*id)).await {
+            log!("Failed to remove queued photo {id}: {:?}", e);
+        }
+    }
+}
+
+/// Returns the outbox of the user or an empty one if there is none.
+/// An unparseable outbox is logged and replaced with an empty one.
+async fn load(ls: &impl KeyValueStore) -> Result<Outbox> {
+    match ls.get_item(OUTBOX_KEY).await {
+        Ok(Some(v)) => match serde_json::from_str::<Outbox>(&v) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                log!("Invalid outbox ignored: {:?}", e);
+                Ok(Outbox::default())
+            }
+        },
+        Ok(None) => Ok(Outbox::default()),
+        Err(e) => bail!("Failed to get the outbox: {:?}", e),
+    }
+}
+
+/// Saves the outbox in the user namespace.
+async fn save(ls: &impl KeyValueStore, outbox: &Outbox) -> Result<()> {
+    let value = match serde_json::to_string(outbox) {
+        Ok(v) => v,
+        Err(e) => bail!("Failed to serialize the outbox: {:?}", e),
+    };
+
+    match ls.set_item(OUTBOX_KEY, &value).await {
+        Ok(()) => Ok(()),
+        Err(e) => bail!("Failed to save the outbox: {:?}", e),
+    }
+}
+
+/// Returns the file storage key for the photo of the outbox entry.
+fn photo_key(id: u64) -> String {
+    [OUTBOX_PHOTO_KEY_PREFIX, &id.to_string()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{MockPlatform, MockResponse, MOCK_FILE};
+    use bookworm_types::{Book, BookSyncResult, SYNC_HTML_ENDPOINT_URL};
+    use futures::executor::block_on;
+    use futures::future::join;
+    use wasm_bindgen::{JsCast, JsValue};
+
+    const ISBN_A: u64 = 9780143107712;
+    const ISBN_B: u64 = 9781761186769;
+    const ISBN_C: u64 = 9781761186776;
+
+    fn token() -> Option<IdToken> {
+        Some("test-token".to_owned())
+    }
+
+    /// Saves an unsync'd book in the user namespace and queues a cloud response that accepts it.
+    fn save_local(platform: &MockPlatform, isbn: u64) {
+        let mut book = Book::new(isbn);
+        book.title = Some("Title".to_owned());
+        platform.set_user_item(&isbn.to_string(), &serde_json::to_string(&book).unwrap());
+
+        let result = BookSyncResult {
+            isbn,
+            saved: true,
+            error: None,
+            version: Some(1),
+            conflict: None,
+        };
+        platform.respond(MockResponse::new(
+            "POST",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &serde_json::to_string(&result).unwrap(),
+        ));
+    }
+
+    fn load_outbox(platform: &MockPlatform) -> Outbox {
+        serde_json::from_str(&platform.get_user_item(OUTBOX_KEY).unwrap()).unwrap()
+    }
+
+    /// Returns the method and the ISBN from the URL or the payload of every request.
+    fn sent(platform: &MockPlatform) -> Vec<(&'static str, u64)> {
+        platform
+            .requests
+            .borrow()
+            .iter()
+            .map(|v| {
+                let isbn = [ISBN_A, ISBN_B, ISBN_C]
+                    .into_iter()
+                    .find(|isbn| {
+                        let isbn = isbn.to_string();
+                        v.url.contains(&isbn) || v.body.as_ref().is_some_and(|v| v.contains(&isbn))
+                    })
+                    .unwrap();
+                (v.method, isbn)
+            })
+            .collect()
+    }
+
+    fn kinds(outbox: &Outbox) -> Vec<Mutation> {
+        outbox.entries.iter().map(|v| v.mutation.clone()).collect()
+    }
+
+    #[test]
+    fn push_coalesces_syncs() {
+        let mut outbox = Outbox::default();
+
+        outbox.push(Mutation::Sync { isbn: ISBN_A });
+        outbox.push(Mutation::Photo { isbn: ISBN_A });
+        outbox.push(Mutation::Sync { isbn: ISBN_A });
+        outbox.push(Mutation::Sync { isbn: ISBN_B });
+
+        assert_eq!(
+            kinds(&outbox),
+            vec![
+                Mutation::Sync { isbn: ISBN_A },
+                Mutation::Photo { isbn: ISBN_A },
+                Mutation::Sync { isbn: ISBN_B }
+            ]
+        );
+    }
+
+    #[test]
+    fn push_delete_drops_book_changes() {
+        let mut outbox = Outbox::default();
+        outbox.push(Mutation::Sync { isbn: ISBN_A });
+        outbox.push(Mutation::Photo { isbn: ISBN_A });
+        outbox.push(Mutation::Sync { isbn: ISBN_B });
+
+        let dropped = outbox.push(Mutation::Delete { isbn: ISBN_A });
+        assert_eq!(dropped, vec![0, 1]);
+        assert!(outbox.push(Mutation::Delete { isbn: ISBN_A }).is_empty());
+
+        // the book can be scanned again after the deletion
+        outbox.push(Mutation::Sync { isbn: ISBN_A });
+
+        assert_eq!(
+            kinds(&outbox),
+            vec![
+                Mutation::Sync { isbn: ISBN_B },
+                Mutation::Delete { isbn: ISBN_A },
+                Mutation::Sync { isbn: ISBN_A }
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_entry_holds_back_book() {
+        let now = Utc::now();
+        let mut outbox = Outbox::default();
+        outbox.push(Mutation::Delete { isbn: ISBN_A });
+        outbox.push(Mutation::Sync { isbn: ISBN_B });
+        outbox.push(Mutation::Sync { isbn: ISBN_A });
+
+        let dropped = outbox.settle(&[1], &[(0, RetryAfter::Seconds(0))], now);
+        assert!(dropped.is_empty());
+
+        // the sync of A waits for the deletion to go through first
+        assert!(outbox.due(now).is_empty());
+
+        let retry_at = now + Duration::seconds(RETRY_BASE_DELAY_SECS);
+        assert_eq!(outbox.entries[0].not_before, Some(retry_at));
+        assert_eq!(outbox.due(retry_at).len(), 2);
+    }
+
+    #[test]
+    fn settle_drops_after_max_attempts() {
+        let now = Utc::now();
+        let mut outbox = Outbox::default();
+        outbox.push(Mutation::Sync { isbn: ISBN_A });
+
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(outbox.settle(&[], &[(0, RetryAfter::Never)], now).is_empty());
+        }
+
+        assert_eq!(outbox.settle(&[], &[(0, RetryAfter::Never)], now), vec![0]);
+        assert!(outbox.entries.is_empty());
+    }
+
+    #[test]
+    fn retry_delay_uses_retry_after() {
+        assert_eq!(retry_delay(1, &RetryAfter::Seconds(120)), Duration::seconds(120));
+        assert_eq!(
+            retry_delay(1, &RetryAfter::Seconds(0)),
+            Duration::seconds(RETRY_BASE_DELAY_SECS)
+        );
+        assert_eq!(
+            retry_delay(3, &RetryAfter::Never),
+            Duration::seconds(RETRY_BASE_DELAY_SECS * 4)
+        );
+        assert_eq!(
+            retry_delay(30, &RetryAfter::Never),
+            Duration::seconds(RETRY_MAX_DELAY_SECS)
+        );
+    }
+
+    #[test]
+    fn replay_keeps_order() {
+        let platform = MockPlatform::default();
+        save_local(&platform, ISBN_A);
+        platform.respond(MockResponse::new("DELETE", &ISBN_B.to_string(), 204, ""));
+        save_local(&platform, ISBN_C);
+        let mutations = [
+            Mutation::Sync { isbn: ISBN_A },
+            Mutation::Delete { isbn: ISBN_B },
+            Mutation::Sync { isbn: ISBN_C },
+        ];
+        block_on(enqueue(&mutations, &platform, &token())).unwrap();
+
+        block_on(replay(&platform, &token())).unwrap();
+
+        // the delete in between keeps the syncs in separate requests
+        assert_eq!(
+            sent(&platform),
+            vec![("POST", ISBN_A), ("DELETE", ISBN_B), ("POST", ISBN_C)]
+        );
+        assert!(load_outbox(&platform).entries.is_empty());
+    }
+
+    #[test]
+    fn failed_replay_backs_off() {
+        let platform = MockPlatform::default();
+        let mutations = [Mutation::Delete { isbn: ISBN_A }, Mutation::Sync { isbn: ISBN_B }];
+        block_on(enqueue(&mutations, &platform, &token())).unwrap();
+
+        // no network: the replay stops at the first failure
+        let started = Utc::now();
+        assert!(block_on(replay(&platform, &token())).is_err());
+        assert_eq!(sent(&platform), vec![("DELETE", ISBN_A)]);
+        let outbox = load_outbox(&platform);
+        assert_eq!(outbox.entries.len(), 2);
+        assert_eq!(outbox.entries[0].attempts, 1);
+        assert!(outbox.entries[0].not_before.unwrap() >= started + Duration::seconds(RETRY_BASE_DELAY_SECS));
+
+        // the failed deletion waits for its retry, the sync of another book does not
+        save_local(&platform, ISBN_B);
+        block_on(replay(&platform, &token())).unwrap();
+        assert_eq!(sent(&platform), vec![("DELETE", ISBN_A), ("POST", ISBN_B)]);
+        let outbox = load_outbox(&platform);
+        assert_eq!(kinds(&outbox), vec![Mutation::Delete { isbn: ISBN_A }]);
+    }
+
+    #[test]
+    fn failed_replay_waits_for_retry_after() {
+        let platform = MockPlatform::default();
+        platform.respond(MockResponse {
+            retry_after: Some("120".to_owned()),
+            ..MockResponse::new("DELETE", &ISBN_A.to_string(), 503, "")
+        });
+        block_on(enqueue(&[Mutation::Delete { isbn: ISBN_A }], &platform, &token())).unwrap();
+
+        let started = Utc::now();
+        assert!(block_on(replay(&platform, &token())).is_err());
+
+        let not_before = load_outbox(&platform).entries[0].not_before.unwrap();
+        assert!(not_before >= started + Duration::seconds(120));
+        assert!(not_before <= Utc::now() + Duration::seconds(120));
+    }
+
+    #[test]
+    fn concurrent_enqueues_keep_all_entries() {
+        let platform = MockPlatform::default();
+        // the mock storage never reads the file
+        let file = JsValue::UNDEFINED.unchecked_into::<File>();
+
+        let (a, b) = block_on(join(
+            enqueue_photo(ISBN_A, &file, &platform, &token()),
+            enqueue_photo(ISBN_B, &file, &platform, &token()),
+        ));
+        a.unwrap();
+        b.unwrap();
+        let (a, b) = block_on(join(
+            enqueue(&[Mutation::Sync { isbn: ISBN_A }], &platform, &token()),
+            enqueue(&[Mutation::Delete { isbn: ISBN_C }], &platform, &token()),
+        ));
+        a.unwrap();
+        b.unwrap();
+
+        let outbox = load_outbox(&platform);
+        assert_eq!(
+            kinds(&outbox),
+            vec![
+                Mutation::Photo { isbn: ISBN_A },
+                Mutation::Photo { isbn: ISBN_B },
+                Mutation::Sync { isbn: ISBN_A },
+                Mutation::Delete { isbn: ISBN_C }
+            ]
+        );
+
+        // every photo has its own file
+        assert_eq!(outbox.entries[0].id, 0);
+        assert_eq!(outbox.entries[1].id, 1);
+        assert_eq!(platform.get_user_item(&photo_key(0)).as_deref(), Some(MOCK_FILE));
+        assert_eq!(platform.get_user_item(&photo_key(1)).as_deref(), Some(MOCK_FILE));
+    }
+
+    #[test]
+    fn cancelled_photo_is_not_retried() {
+        let platform = MockPlatform {
+            upload_cancelled: true,
+            ..Default::default()
+        };
+        save_local(&platform, ISBN_A);
+        let presigned_url = "https://s3.us-east-1.amazonaws.com/bookworm-photos/photos/2be54aceee5f-9780143107712-1727129470.jpg?x-id=PutObject";
+        platform.respond(MockResponse::new(
+            "PUT",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &serde_json::to_string(&[presigned_url]).unwrap(),
+        ));
+        let file = JsValue::UNDEFINED.unchecked_into::<File>();
+        block_on(enqueue_photo(ISBN_A, &file, &platform, &token())).unwrap();
+
+        block_on(replay(&platform, &token())).unwrap();
+
+        assert!(load_outbox(&platform).entries.is_empty());
+        assert!(platform.get_user_item(&photo_key(0)).is_none());
+    }
+
+    #[test]
+    fn outbox_format() {
+        let mut outbox = Outbox::default();
+        outbox.push(Mutation::Photo { isbn: ISBN_A });
+
+        assert_eq!(
+            serde_json::to_string(&outbox).unwrap(),
+            r#"{"nextId":1,"entries":[{"id":0,"mutation":{"kind":"photo","isbn":9780143107712},"attempts":0}]}"#
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use crate::http_req::{fetch, HttpMethod, RetryPolicy};
+use crate::photos::UploadProgress;
+use crate::storage::{get_file_storage, open_local_storage, FileStore, KeyValueStore, LocalStore};
+use crate::utils::{upload_file, Runtime, UploadOutcome};
+use crate::wasm_response::{report_progress, WasmMessage, WasmResponse};
+use anyhow::Result;
+use bookworm_types::IdToken;
+use wasm_bindgen::closure::Closure;
+use web_sys::File;
+
+/// Everything the app logic needs from the browser: HTTP, storage and a way to message the UI.
+/// `Runtime` is the browser implementation.
+/// The logic only depends on this trait, so it runs natively in tests with `mock::MockPlatform`.
+pub(crate) trait Platform {
+    /// The book store, see `storage::get_local_storage`.
+    type Store: KeyValueStore;
+    /// The store for photos waiting in the outbox, see `storage::get_file_storage`.
+    type Files: FileStore;
+
+    /// Executes an HTTP request, see `http_req::execute_http_request_with_policy`.
+    async fn http<P, R>(
+        &self,
+        url: &str,
+        method: HttpMethod<P>,
+        policy: RetryPolicy,
+        request_id: &str,
+        id_token: &Option<IdToken>,
+    ) -> crate::Result<Option<R>>
+    where
+        P: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de>;
+
+    /// Returns the book store scoped to the user from the token or the anonymous store if there is no valid token.
+    async fn local_storage(&self, id_token: &Option<IdToken>) -> Result<Self::Store>;
+
+    /// Returns the file store scoped to the user from the token.
+    async fn file_storage(&self, id_token: &Option<IdToken>) -> Result<Self::Files>;
+
+    /// Uploads a photo of the book to S3 using a signed URL.
+    /// The progress is reported to the UI as `WasmResponse::UploadProgress`.
+    /// The upload can be cancelled by `cancel_upload` with the request ID of the call.
+    async fn upload_file(&self, signed_url: &str, file: &File, isbn: u64) -> UploadOutcome;
+
+    /// Sends a `WasmResponse` to the UI thread in a `WasmMessage` with the request ID of the call.
+    fn report_progress(&self, resp: &WasmResponse);
+}
+
+impl Platform for Runtime {
+    type Store = LocalStore;
+    type Files = LocalStore;
+
+    async fn http<P, R>(
+        &self,
+        url: &str,
+        method: HttpMethod<P>,
+        policy: RetryPolicy,
+        request_id: &str,
+        id_token: &Option<IdToken>,
+    ) -> crate::Result<Option<R>>
+    where
+        P: serde::Serialize,
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        fetch(url, method, policy, request_id, self, id_token).await
+    }
+
+    async fn local_storage(&self, id_token: &Option<IdToken>) -> Result<LocalStore> {
+        open_local_storage(self, id_token).await
+    }
+
+    async fn file_storage(&self, id_token: &Option<IdToken>) -> Result<LocalStore> {
+        get_file_storage(self, id_token).await
+    }
+
+    async fn upload_file(&self, signed_url: &str, file: &File, isbn: u64) -> UploadOutcome {
+        log!("Uploading file: {} / {} bytes", file.name(), file.size());
+
+        let runtime = self.clone();
+        let file_name = file.name();
+        let on_progress = Closure::<dyn FnMut(f64, f64)>::new(move |sent: f64, total: f64| {
+            let progress = UploadProgress {
+                isbn,
+                file_name: file_name.clone(),
+                bytes_sent: sent as u64,
+                total_bytes: total as u64,
+            };
+            runtime.report_progress(&WasmResponse::UploadProgress(Box::new(Some(Ok(progress)))));
+        });
+
+        // the closure must outlive the upload because JS calls it until the upload ends
+        upload_file(signed_url, file.clone(), &self.request_id, &on_progress).await
+    }
+
+    fn report_progress(&self, resp: &WasmResponse) {
+        let msg = WasmMessage {
+            request_id: &self.request_id,
+            last: false,
+            response: Some(resp),
+        };
+        report_progress(msg.to_string());
+    }
+}
+
+/// An in-memory platform for running the app logic natively in tests.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::Platform;
+    use crate::http_req::{check_status, HttpMethod, RetryPolicy};
+    use crate::storage::{FileStore, KeyValueStore};
+    use crate::utils::UploadOutcome;
+    use crate::wasm_response::WasmResponse;
+    use crate::RetryAfter;
+    use anyhow::Result;
+    use bookworm_types::IdToken;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::future::poll_fn;
+    use std::rc::Rc;
+    use std::task::Poll;
+    use wasm_bindgen::{JsCast, JsValue};
+    use web_sys::File;
+
+    /// The user ID the mock storage uses for any token because test tokens cannot be signed.
+    pub(crate) const USER_ID: &str = "test-user";
+
+    /// The value stored in place of a file, see `FileStore for MockStore`.
+    pub(crate) const MOCK_FILE: &str = "mock-file";
+
+    /// A simulated server response.
+    pub(crate) struct MockResponse {
+        /// The HTTP method the response is for, e.g. `GET`.
+        pub method: &'static str,
+        /// The response is used for the first request with a URL that contains this value.
+        pub url: String,
+        pub status: u16,
+        /// The value of `Retry-After` header.
+        pub retry_after: Option<String>,
+        pub body: String,
+    }
+
+    impl MockResponse {
+        pub(crate) fn new(method: &'static str, url: &str, status: u16, body: &str) -> Self {
+            Self {
+                method,
+                url: url.to_owned(),
+                status,
+                retry_after: None,
+                body: body.to_owned(),
+            }
+        }
+    }
+
+    /// A request made by the code under test.
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockRequest {
+        pub method: &'static str,
+        pub url: String,
+        /// The value of `REQUEST_ID_HEADER`.
+        pub request_id: String,
+        /// The JSON payload for POST and PUT.
+        pub body: Option<String>,
+    }
+
+    /// Replays queued responses in place of the network and keeps the storage in a map.
+    /// Every response is used once. A request with no matching response fails as a network error.
+    #[derive(Default)]
+    pub(crate) struct MockPlatform {
+        pub responses: RefCell<VecDeque<MockResponse>>,
+        pub requests: RefCell<Vec<MockRequest>>,
+        /// All records with their full keys, e.g. `test-user:9780143107712`.
+        pub storage: Rc<RefCell<BTreeMap<String, String>>>,
+        /// `WasmResponse` messages sent to the UI.
+        pub messages: RefCell<Vec<String>>,
+        /// The status returned for all file uploads. Uploads fail with `0` if not set.
+        pub upload_status: u32,
+        /// All file uploads are cancelled if set, whatever the status.
+        pub upload_cancelled: bool,
+    }
+
+    impl MockPlatform {
+        /// Queues a response for the next matching request.
+        pub(crate) fn respond(&self, response: MockResponse) {
+            self.responses.borrow_mut().push_back(response);
+        }
+
+        /// Returns the value stored under the key in the user namespace.
+        pub(crate) fn get_user_item(&self, key: &str) -> Option<String> {
+            self.storage.borrow().get(&[USER_ID, ":", key].concat()).cloned()
+        }
+
+        /// Stores the value under the key in the user namespace.
+        pub(crate) fn set_user_item(&self, key: &str, value: &str) {
+            self.storage
+                .borrow_mut()
+                .insert([USER_ID, ":", key].concat(), value.to_owned());
+        }
+    }
+
+    impl Platform for MockPlatform {
+        type Store = MockStore;
+        type Files = MockStore;
+
+        async fn http<P, R>(
+            &self,
+            url: &str,
+            method: HttpMethod<P>,
+            _policy: RetryPolicy,
+            request_id: &str,
+            _id_token: &Option<IdToken>,
+        ) -> crate::Result<Option<R>>
+        where
+            P: serde::Serialize,
+            R: for<'de> serde::Deserialize<'de>,
+        {
+            let (method, body) = match method {
+                HttpMethod::Get => ("GET", None),
+                HttpMethod::Post(v) => ("POST", serde_json::to_string(&v).ok()),
+                HttpMethod::Put(v) => ("PUT", serde_json::to_string(&v).ok()),
+                HttpMethod::Delete => ("DELETE", None),
+            };
+            self.requests.borrow_mut().push(MockRequest {
+                method,
+                url: url.to_owned(),
+                request_id: request_id.to_owned(),
+                body,
+            });
+
+            let response = {
+                let mut responses = self.responses.borrow_mut();
+                match responses
+                    .iter()
+                    .position(|v| v.method == method && url.contains(&v.url))
+                {
+                    Some(i) => responses.remove(i).unwrap(),
+                    None => return Err(RetryAfter::Seconds(0)),
+                }
+            };
+
+            if response.status == 409 {
+                return Err(RetryAfter::Conflict(response.body));
+            }
+            check_status(response.status, response.retry_after.as_deref())?;
+            if response.status == 204 {
+                return Ok(None);
+            }
+
+            serde_json::from_str::<R>(&response.body)
+                .map(Some)
+                .map_err(|_| RetryAfter::Never)
+        }
+
+        async fn local_storage(&self, id_token: &Option<IdToken>) -> Result<MockStore> {
+            Ok(MockStore {
+                items: self.storage.clone(),
+                user_id: id_token.as_ref().map(|_| USER_ID.to_owned()),
+            })
+        }
+
+        async fn file_storage(&self, id_token: &Option<IdToken>) -> Result<MockStore> {
+            self.local_storage(id_token).await
+        }
+
+        async fn upload_file(&self, _signed_url: &str, _file: &File, _isbn: u64) -> UploadOutcome {
+            match self.upload_cancelled {
+                true => UploadOutcome::Cancelled,
+                false => UploadOutcome::Status(self.upload_status),
+            }
+        }
+
+        fn report_progress(&self, resp: &WasmResponse) {
+            self.messages.borrow_mut().push(resp.to_string());
+        }
+    }
+
+    /// An in-memory `KeyValueStore` with the same namespaces as `LocalStore`.
+    pub(crate) struct MockStore {
+        items: Rc<RefCell<BTreeMap<String, String>>>,
+        user_id: Option<String>,
+    }
+
+    /// Returns Pending once, like a storage call waiting for IndexedDB, so that concurrent callers interleave.
+    async fn yield_now() {
+        let mut yielded = false;
+        poll_fn(|cx| match yielded {
+            true => Poll::Ready(()),
+            false => {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    impl MockStore {
+        fn full_key(&self, key: &str) -> String {
+            match &self.user_id {
+                Some(v) => [v, ":", key].concat(),
+                None => key.to_owned(),
+            }
+        }
+    }
+
+    impl KeyValueStore for MockStore {
+        async fn get_item(&self, key: &str) -> std::result::Result<Option<String>, JsValue> {
+            yield_now().await;
+            Ok(self.items.borrow().get(&self.full_key(key)).cloned())
+        }
+
+        async fn set_item(&self, key: &str, value: &str) -> std::result::Result<(), JsValue> {
+            yield_now().await;
+            self.items.borrow_mut().insert(self.full_key(key), value.to_owned());
+            Ok(())
+        }
+
+        async fn remove_item(&self, key: &str) -> std::result::Result<(), JsValue> {
+            yield_now().await;
+            self.items.borrow_mut().remove(&self.full_key(key));
+            Ok(())
+        }
+
+        async fn keys(&self) -> std::result::Result<Vec<String>, JsValue> {
+            Ok(self
+                .items
+                .borrow()
+                .keys()
+                .filter_map(|v| match &self.user_id {
+                    Some(user_id) => v.strip_prefix(user_id.as_str()).and_then(|v| v.strip_prefix(':')),
+                    None => (!v.contains(':')).then_some(v.as_str()),
+                })
+                .map(|v| v.to_owned())
+                .collect())
+        }
+
+        fn is_user(&self) -> bool {
+            self.user_id.is_some()
+        }
+    }
+
+    /// The files are kept as `MOCK_FILE` values. The file returned for them is not a real `File`,
+    /// so the code under test must not read it.
+    impl FileStore for MockStore {
+        async fn get_file(&self, key: &str) -> std::result::Result<Option<File>, JsValue> {
+            Ok(self
+                .get_item(key)
+                .await?
+                .map(|_| JsValue::UNDEFINED.unchecked_into::<File>()))
+        }
+
+        async fn set_file(&self, key: &str, _file: &File) -> std::result::Result<(), JsValue> {
+            self.set_item(key, MOCK_FILE).await
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use crate::platform::Platform;
+use crate::utils::{GlobalScope, Runtime};
+use anyhow::{bail, Result};
+use bookworm_types::{jwt, IdToken};
+use js_sys::{Array, Promise};
+use serde::Serialize;
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    DomException, File, IdbDatabase, IdbOpenDbRequest, IdbRequest, IdbTransaction, IdbTransactionMode, Storage,
+};
+
+/// The name of IndexedDB database used in place of the local storage inside web workers.
+const IDB_NAME: &str = "bookworm";
+
+/// The only object store in `IDB_NAME`.
+/// Records are stored as JSON strings keyed by the same keys as in the local storage.
+const IDB_STORE_NAME: &str = "books";
+
+/// Bump this value if the object store structure changes.
+const IDB_VERSION: u32 = 1;
+
+/// Compaction frees this much on top of what is needed for the failed write
+/// to avoid compacting again on the very next save.
+pub(crate) const COMPACTION_HEADROOM_BYTES: usize = 100_000;
+
+thread_local! {
+    /// IndexedDB takes a few callbacks to open, so the connection is reused for the life of the module.
+    static IDB: RefCell<Option<IdbDatabase>> = const { RefCell::new(None) };
+}
+
+/// Separates the user ID from the rest of the key, e.g. `8cbf...f737:9780143107712`.
+const NAMESPACE_SEPARATOR: char = ':';
+
+/// Whose records a store can see.
+enum Namespace {
+    /// Books scanned without logging in. Their keys are bare ISBNs.
+    Anonymous,
+    /// Books of a signed-in user. Their keys are prefixed with the user ID.
+    User(String),
+    /// All keys as they are. Only used for copying between backends.
+    Unscoped,
+}
+
+/// Where the records are physically stored.
+enum Backend {
+    /// The browser's `localStorage`
+    Local(Storage),
+    /// IndexedDB because `localStorage` is not available in web workers
+    Idb(IdbDatabase),
+}
+
+/// A key-value store for book records scoped to a single user.
+/// The callers only see their own keys without the user ID prefix,
+/// so the same code works for anonymous and signed-in users.
+///
+/// The method names and return types mirror `web_sys::Storage` to keep the call sites the same
+/// for all implementations: `LocalStore` in the browser and an in-memory map in tests.
+pub(crate) trait KeyValueStore {
+    /// Returns the value for the key or None if the key does not exist.
+    async fn get_item(&self, key: &str) -> std::result::Result<Option<String>, JsValue>;
+
+    /// Adds or replaces the value for the key.
+    async fn set_item(&self, key: &str, value: &str) -> std::result::Result<(), JsValue>;
+
+    /// Removes the key and its value. Does nothing if the key does not exist.
+    async fn remove_item(&self, key: &str) -> std::result::Result<(), JsValue>;
+
+    /// Returns all keys in the namespace of the store in no particular order.
+    async fn keys(&self) -> std::result::Result<Vec<String>, JsValue>;
+
+    /// Returns true if the store belongs to a signed-in user.
+    fn is_user(&self) -> bool;
+}
+
+/// A `KeyValueStore` that can also keep files, e.g. photos waiting to be uploaded.
+/// Use `remove_item` to delete the files.
+pub(crate) trait FileStore: KeyValueStore {
+    /// Returns the file stored under the key or None if the key does not exist.
+    async fn get_file(&self, key: &str) -> std::result::Result<Option<File>, JsValue>;
+
+    /// Adds or replaces the file for the key.
+    async fn set_file(&self, key: &str, file: &File) -> std::result::Result<(), JsValue>;
+}
+
+/// The browser implementation of `KeyValueStore` backed by the local storage or IndexedDB.
+pub(crate) struct LocalStore {
+    backend: Backend,
+    namespace: Namespace,
+}
+
+impl KeyValueStore for LocalStore {
+    async fn get_item(&self, key: &str) -> std::result::Result<Option<String>, JsValue> {
+        let key = self.full_key(key);
+        match &self.backend {
+            Backend::Local(ls) => ls.get_item(&key),
+            Backend::Idb(db) => {
+                let store = db
+                    .transaction_with_str_and_mode(IDB_STORE_NAME, IdbTransactionMode::Readonly)?
+                    .object_store(IDB_STORE_NAME)?;
+                let value = request_to_future(&store.get(&JsValue::from_str(&key))?).await?;
+                Ok(value.as_string())
+            }
+        }
+    }
+
+    /// IndexedDB writes are considered complete only after the transaction is committed.
+    async fn set_item(&self, key: &str, value: &str) -> std::result::Result<(), JsValue> {
+        let key = self.full_key(key);
+        match &self.backend {
+            Backend::Local(ls) => ls.set_item(&key, value),
+            Backend::Idb(db) => {
+                let tx = db.transaction_with_str_and_mode(IDB_STORE_NAME, IdbTransactionMode::Readwrite)?;
+                tx.object_store(IDB_STORE_NAME)?
+                    .put_with_key(&JsValue::from_str(value), &JsValue::from_str(&key))?;
+                transaction_to_future(&tx).await
+            }
+        }
+    }
+
+    async fn remove_item(&self, key: &str) -> std::result::Result<(), JsValue> {
+        let key = self.full_key(key);
+        match &self.backend {
+            Backend::Local(ls) => ls.remove_item(&key),
+            Backend::Idb(db) => {
+                let tx = db.transaction_with_str_and_mode(IDB_STORE_NAME, IdbTransactionMode::Readwrite)?;
+                tx.object_store(IDB_STORE_NAME)?.delete(&JsValue::from_str(&key))?;
+                transaction_to_future(&tx).await
+            }
+        }
+    }
+
+    /// Keys that fail to load are logged and skipped.
+    async fn keys(&self) -> std::result::Result<Vec<String>, JsValue> {
+        let keys = match &self.backend {
+            Backend::Local(ls) => {
+                let number_of_records = ls.length()?;
+                let mut keys = Vec::with_capacity(number_of_records as usize);
+
+                // get one key at a time (inefficient, but the best we have with Local Storage)
+                for i in 0..number_of_records {
+                    match ls.key(i) {
+                        Ok(Some(v)) => keys.push(v),
+                        Ok(None) => log!("Key {i} not found in local storage"),
+                        Err(e) => log!("Failed to get key {i} from local storage: {:?}", e),
+                    }
+                }
+
+                keys
+            }
+            Backend::Idb(db) => {
+                let store = db
+                    .transaction_with_str_and_mode(IDB_STORE_NAME, IdbTransactionMode::Readonly)?
+                    .object_store(IDB_STORE_NAME)?;
+                let keys = request_to_future(&store.get_all_keys()?).await?;
+                Array::from(&keys).iter().filter_map(|v| v.as_string()).collect()
+            }
+        };
+
+        // strip the namespace prefix and drop the keys of other users
+        Ok(match &self.namespace {
+            Namespace::Anonymous => keys.into_iter().filter(|v| !v.contains(NAMESPACE_SEPARATOR)).collect(),
+            Namespace::User(user_id) => keys
+                .into_iter()
+                .filter_map(|v| {
+                    v.strip_prefix(user_id.as_str())
+                        .and_then(|v| v.strip_prefix(NAMESPACE_SEPARATOR))
+                        .map(|v| v.to_owned())
+                })
+                .collect(),
+            Namespace::Unscoped => keys,
+        })
+    }
+
+    fn is_user(&self) -> bool {
+        matches!(self.namespace, Namespace::User(_))
+    }
+}
+
+/// Only IndexedDB can store files, see `get_file_storage`.
+impl FileStore for LocalStore {
+    async fn get_file(&self, key: &str) -> std::result::Result<Option<File>, JsValue> {
+        let key = self.full_key(key);
+        match &self.backend {
+            Backend::Local(_) => Err(JsValue::from_str("Files cannot be stored in local storage")),
+            Backend::Idb(db) => {
+                let store = db
+                    .transaction_with_str_and_mode(IDB_STORE_NAME, IdbTransactionMode::Readonly)?
+                    .object_store(IDB_STORE_NAME)?;
+                let value = request_to_future(&store.get(&JsValue::from_str(&key))?).await?;
+                Ok(value.dyn_into::<File>().ok())
+            }
+        }
+    }
+
+    async fn set_file(&self, key: &str, file: &File) -> std::result::Result<(), JsValue> {
+        let key = self.full_key(key);
+        match &self.backend {
+            Backend::Local(_) => Err(JsValue::from_str("Files cannot be stored in local storage")),
+            Backend::Idb(db) => {
+                let tx = db.transaction_with_str_and_mode(IDB_STORE_NAME, IdbTransactionMode::Readwrite)?;
+                tx.object_store(IDB_STORE_NAME)?
+                    .put_with_key(file, &JsValue::from_str(&key))?;
+                transaction_to_future(&tx).await
+            }
+        }
+    }
+}
+
+impl LocalStore {
+    /// Adds the namespace prefix to the key.
+    fn full_key(&self, key: &str) -> String {
+        match &self.namespace {
+            Namespace::User(user_id) => [user_id.as_str(), &NAMESPACE_SEPARATOR.to_string(), key].concat(),
+            Namespace::Anonymous | Namespace::Unscoped => key.to_owned(),
+        }
+    }
+}
+
+/// A report on how much space the book records take in the store
+/// and what was removed to free some of it.
+/// Sent to the UI inside `WasmResponse::StorageUsage`.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageUsage {
+    /// The number of book records in the store.
+    pub records: usize,
+    /// An approximate size of all book records, keys included.
+    /// Browsers store strings as UTF-16, so the actual number may be up to 2x larger.
+    pub bytes: usize,
+    /// ISBNs of books that had their Google Books data and covers removed by the last compaction.
+    /// The data is refetched when the book is opened.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub compacted: Vec<u64>,
+    /// The approximate number of bytes freed by the last compaction.
+    pub freed_bytes: usize,
+}
+
+/// Returns true if the error is a storage quota error.
+/// Firefox uses a different name for the same error.
+pub(crate) fn is_quota_error(e: &JsValue) -> bool {
+    match e.dyn_ref::<DomException>() {
+        Some(v) => {
+            let name = v.name();
+            name == "QuotaExceededError" || name == "NS_ERROR_DOM_QUOTA_REACHED"
+        }
+        None => false,
+    }
+}
+
+/// Returns the book store of the platform scoped to the user from the token.
+/// The anonymous namespace is returned if there is no valid token.
+pub(crate) async fn get_local_storage<P: Platform>(runtime: &P, id_token: &Option<IdToken>) -> Result<P::Store> {
+    runtime.local_storage(id_token).await
+}
+
+/// Returns the book store for the browser runtime scoped to the user from the token.
+/// The anonymous namespace is returned if there is no valid token.
+/// - Window: local storage
+/// - Worker: IndexedDB, opened on the first call and reused after that
+pub(crate) async fn open_local_storage(runtime: &Runtime, id_token: &Option<IdToken>) -> Result<LocalStore> {
+    Ok(LocalStore {
+        backend: get_backend(runtime).await?,
+        namespace: get_namespace(id_token),
+    })
+}
+
+/// Returns the store for files, e.g. photos waiting to be uploaded, scoped to the user from the token.
+/// The local storage only takes strings, so files always go into IndexedDB, even on the UI thread.
+/// Use `remove_item` to delete the files.
+pub(crate) async fn get_file_storage(runtime: &Runtime, id_token: &Option<IdToken>) -> Result<LocalStore> {
+    Ok(LocalStore {
+        backend: Backend::Idb(get_idb(runtime).await?),
+        namespace: get_namespace(id_token),
+    })
+}
+
+/// Returns the namespace of the user from the token or the anonymous namespace if there is no valid token.
+fn get_namespace(id_token: &Option<IdToken>) -> Namespace {
+    // an expired token still identifies whose books these are
+    match jwt::get_user_details_ignoring_expiry(id_token) {
+        Some(v) => Namespace::User(v.id),
+        None => Namespace::Anonymous,
+    }
+}
+
+/// Returns the book store for books scanned without logging in.
+pub(crate) async fn get_anonymous_storage<P: Platform>(runtime: &P) -> Result<P::Store> {
+    get_local_storage(runtime, &None).await
+}
+
+/// Copies all book records from the local storage into IndexedDB,
+/// where they can be reached by the module running inside a web worker.
+/// Records of all users are copied. Existing IndexedDB records are not overwritten.
+/// Only works on the UI thread because workers have no access to the local storage.
+/// Returns the number of copied records.
+pub(crate) async fn copy_local_storage_to_idb(runtime: &Runtime) -> Result<usize> {
+    if let GlobalScope::Worker(_) = runtime.scope {
+        bail!("Local storage is not available in web workers");
+    }
+
+    let ls = LocalStore {
+        backend: get_backend(runtime).await?,
+        namespace: Namespace::Unscoped,
+    };
+    let idb = LocalStore {
+        backend: Backend::Idb(get_idb(runtime).await?),
+        namespace: Namespace::Unscoped,
+    };
+
+    let keys = match ls.keys().await {
+        Ok(v) => v,
+        Err(e) => bail!("Failed to get local storage keys: {:?}", e),
+    };
+
+    let mut copied = 0;
+    for key in keys {
+        // only book records are copied, with or without the user prefix
+        let is_book = key
+            .rsplit(NAMESPACE_SEPARATOR)
+            .next()
+            .is_some_and(|v| v.starts_with("97"));
+        if !is_book {
+            continue;
+        }
+
+        // existing IDB records are more recent than the LS ones because the worker wrote them
+        if let Ok(Some(_)) = idb.get_item(&key).await {
+            log!("Already in IndexedDB: {key}");
+            continue;
+        }
+
+        let value = match ls.get_item(&key).await {
+            Ok(Some(v)) => v,
+            _ => {
+                log!("Value not found in local storage: {key}");
+                continue;
+            }
+        };
+
+        match idb.set_item(&key, &value).await {
+            Ok(()) => copied += 1,
+            Err(e) => log!("Failed to copy {key} to IndexedDB: {:?}", e),
+        }
+    }
+
+    Ok(copied)
+}
+
+/// Returns the physical storage for the runtime.
+async fn get_backend(runtime: &Runtime) -> Result<Backend> {
+    match &runtime.scope {
+        GlobalScope::Window(v) => match v.local_storage() {
+            Ok(Some(v)) => Ok(Backend::Local(v)),
+            Err(e) => {
+                bail!("Failed to get local storage: {:?}", e);
+            }
+            _ => {
+                bail!("Local storage not available (OK(None))");
+            }
+        },
+        GlobalScope::Worker(_) => Ok(Backend::Idb(get_idb(runtime).await?)),
+    }
+}
+
+/// Returns a cached IndexedDB connection or opens a new one,
+/// creating the object store on the first run.
+async fn get_idb(runtime: &Runtime) -> Result<IdbDatabase> {
+    if let Some(db) = IDB.with(|v| v.borrow().clone()) {
+        return Ok(db);
+    }
+
+    let factory = match runtime.indexed_db() {
+        Ok(Some(v)) => v,
+        Err(e) => {
+            bail!("Failed to get IndexedDB: {:?}", e);
+        }
+        _ => {
+            bail!("IndexedDB not available (OK(None))");
+        }
+    };
+
+    let open_request = match factory.open_with_u32(IDB_NAME, IDB_VERSION) {
+        Ok(v) => v,
+        Err(e) => bail!("Failed to open IndexedDB: {:?}", e),
+    };
+
+    // the object store can only be created inside the upgrade callback
+    let upgrade_request = open_request.clone();
+    let on_upgrade = Closure::once_into_js(move |_: JsValue| create_object_store(&upgrade_request));
+    open_request.set_onupgradeneeded(Some(on_upgrade.unchecked_ref()));
+
+    let db = match request_to_future(&open_request).await {
+        Ok(v) => IdbDatabase::from(v),
+        Err(e) => bail!("Failed to open IndexedDB: {:?}", e),
+    };
+
+    log!("IndexedDB opened");
+    IDB.with(|v| v.replace(Some(db.clone())));
+
+    Ok(db)
+}
+
+/// Creates the object store for book records if it does not exist.
+/// Called from the `upgradeneeded` event handler.
+fn create_object_store(open_request: &IdbOpenDbRequest) {
+    let db = match open_request.result() {
+        Ok(v) => IdbDatabase::from(v),
+        Err(e) => {
+            log!("No IndexedDB in upgradeneeded event: {:?}", e);
+            return;
+        }
+    };
+
+    if !db.object_store_names().contains(IDB_STORE_NAME) {
+        match db.create_object_store(IDB_STORE_NAME) {
+            Ok(_) => log!("IndexedDB store created: {IDB_STORE_NAME}"),
+            Err(e) => log!("Failed to create IndexedDB store {IDB_STORE_NAME}: {:?}", e),
+        }
+    }
+}
+
+/// Converts an IndexedDB request into a future that resolves with the request result
+/// or fails with the request error.
+async fn request_to_future(request: &IdbRequest) -> std::result::Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let success_request = request.clone();
+        let on_success = Closure::once_into_js(move |_: JsValue| {
+            let _ = resolve.call1(&JsValue::NULL, &success_request.result().unwrap_or(JsValue::UNDEFINED));
+        });
+
+        let error_request = request.clone();
+        let on_error = Closure::once_into_js(move |_: JsValue| {
+            let error = match error_request.error() {
+                Ok(Some(v)) => v.into(),
+                _ => JsValue::from_str("Unknown IndexedDB request error"),
+            };
+            let _ = reject.call1(&JsValue::NULL, &error);
+        });
+
+        request.set_onsuccess(Some(on_success.unchecked_ref()));
+        request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+
+    JsFuture::from(promise).await
+}
+
+/// Waits for an IndexedDB transaction to be committed or fail.
+async fn transaction_to_future(tx: &IdbTransaction) -> std::result::Result<(), JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let on_complete = Closure::once_into_js(move |_: JsValue| {
+            let _ = resolve.call0(&JsValue::NULL);
+        });
+
+        // a failed request aborts the transaction, e.g. QuotaExceededError
+        let error_tx = tx.clone();
+        let on_abort = Closure::once_into_js(move |_: JsValue| {
+            let error = match error_tx.error() {
+                Some(v) => v.into(),
+                None => JsValue::from_str("IndexedDB transaction aborted"),
+            };
+            let _ = reject.call1(&JsValue::NULL, &error);
+        });
+
+        tx.set_oncomplete(Some(on_complete.unchecked_ref()));
+        tx.set_onabort(Some(on_abort.unchecked_ref()));
+    });
+
+    JsFuture::from(promise).await.map(|_| ())
+}
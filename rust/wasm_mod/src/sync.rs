@@ -1,126 +1,569 @@
-use crate::http_req::{execute_http_request, HttpMethod};
-use crate::utils::{get_local_storage, log};
+use crate::book;
+use crate::http_req::{execute_http_request, execute_http_request_with_policy, HttpError, HttpMethod, RetryPolicy};
+use crate::outbox;
+use crate::platform::Platform;
+use crate::storage::{get_local_storage, KeyValueStore};
+use crate::wasm_response::WasmResponse;
+use crate::RetryAfter;
 use anyhow::{bail, Error, Result};
-use bookworm_types::{Book, Books, IdToken, ISBN_URL_PARAM_NAME, SYNC_HTML_ENDPOINT_URL};
-use std::collections::HashSet;
-use web_sys::Window;
+use bookworm_types::{
+    Book, BookSyncResult, BookSyncResults, Books, BooksPage, IdToken, ISBN_URL_PARAM_NAME, PAGE_URL_PARAM_NAME,
+    SINCE_URL_PARAM_NAME, SYNC_HTML_ENDPOINT_URL, TOMBSTONE_RETENTION_DAYS,
+};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 
-/// Try to save the book to the cloud DB and update the sync status in the local storage.
-/// No action is taken if there is no token or the book is already sync'd.
-/// The sync is only from local to cloud.
+/// A key in the user namespace with the time of the latest cloud change applied locally.
+/// Value: RFC3339 timestamp, see `sync_books`.
+const SYNC_CURSOR_KEY: &str = "sync-cursor";
+
+/// How far the saved sync cursor is set back from the latest cloud change.
+/// The cloud changes are looked up in an eventually consistent index, so a write made just before
+/// the latest one may show up in the index after the sync. The overlap requests it again next time.
+const SYNC_CURSOR_OVERLAP_MINUTES: i64 = 5;
+
+/// How many times a write rejected because of a stale version is merged and sent again.
+const MAX_CONFLICT_RETRIES: usize = 2;
+
+/// The max number of pages `sync_books` requests in a single sync.
+/// It stops a buggy cursor from looping forever. A page has up to 500 books.
+const MAX_SYNC_PAGES: usize = 1000;
+
+/// A key in the user namespace with the outcome of the latest sync.
+/// Value: `SyncStatus` as JSON without the live fields.
+const SYNC_STATUS_KEY: &str = "sync-status";
+
+thread_local! {
+    /// The number of syncs in progress. Entry points run concurrently, so there can be more than one.
+    static SYNCS_RUNNING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Tells the UI whether the library is in sync with the cloud.
+/// Sent as `WasmResponse::SyncStatus` at the start and the end of every sync.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStatus {
+    /// The number of changes waiting in the outbox to be sent to the cloud.
+    #[serde(skip_deserializing)]
+    pub pending: usize,
+    /// When the latest sync completed without errors.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_success: Option<DateTime<Utc>>,
+    /// The error of the latest sync. It is cleared by the next successful sync.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_error: Option<String>,
+    /// True while a sync is in progress.
+    #[serde(skip_deserializing)]
+    pub running: bool,
+}
+
+/// Marks the start of a sync and reports the status to the UI.
+/// Every call must be followed by `finish_sync`.
+pub(crate) async fn start_sync(runtime: &impl Platform, id_token: &Option<IdToken>) {
+    SYNCS_RUNNING.with(|v| v.set(v.get() + 1));
+    report_sync_status(None, runtime, id_token).await;
+}
+
+/// Marks the end of a sync started with `start_sync`, records the outcome and reports the status to the UI.
+pub(crate) async fn finish_sync(
+    result: std::result::Result<(), String>,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) {
+    SYNCS_RUNNING.with(|v| v.set(v.get().saturating_sub(1)));
+    report_sync_status(Some(result), runtime, id_token).await;
+}
+
+/// Sends `WasmResponse::SyncStatus` to the UI, saving the outcome of the sync first if there is one.
+/// Errors are logged.
+pub(crate) async fn report_sync_status(
+    outcome: Option<std::result::Result<(), String>>,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) {
+    let ls = match get_local_storage(runtime, id_token).await {
+        Ok(v) => v,
+        Err(e) => {
+            log!("Failed to get local storage: {:?}", e);
+            return;
+        }
+    };
+
+    let mut status = match ls.get_item(SYNC_STATUS_KEY).await {
+        Ok(Some(v)) => serde_json::from_str::<SyncStatus>(&v).unwrap_or_default(),
+        _ => SyncStatus::default(),
+    };
+
+    if let Some(outcome) = outcome {
+        match outcome {
+            Ok(()) => {
+                status.last_success = Some(Utc::now());
+                status.last_error = None;
+            }
+            Err(e) => status.last_error = Some(e),
+        }
+
+        match serde_json::to_string(&status) {
+            Ok(v) => {
+                if let Err(e) = ls.set_item(SYNC_STATUS_KEY, &v).await {
+                    log!("Failed to save sync status: {:?}", e);
+                }
+            }
+            Err(e) => log!("Failed to serialize sync status: {:?}", e),
+        }
+    }
+
+    status.pending = outbox::pending(&ls).await;
+    status.running = SYNCS_RUNNING.with(|v| v.get()) > 0;
+
+    runtime.report_progress(&WasmResponse::SyncStatus(Box::new(Some(Ok(status)))));
+}
+
+/// Sends the local copies of the books to the cloud DB and updates their sync status in the local storage.
+/// Books that are already sync'd, deleted or missing locally are skipped.
+/// More than one book is sent in a single request.
+/// Only the local storage namespace of the user from the token is read and written.
+/// Returns the ISBNs of the books that failed to sync with the reason, see `outbox::replay`.
 /// All errors are logged.
-pub(crate) async fn sync_book(isbn: u64, runtime: &Window, id_token: &Option<IdToken>) -> Result<()> {
+pub(crate) async fn push_books(
+    isbns: &[u64],
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> Result<Vec<(u64, RetryAfter)>> {
     // nothing to do if the user is not logged in
     if id_token.is_none() {
         log!("No token. Sync skipped.");
-        return Ok(());
+        return Ok(Vec::new());
     }
 
-    let ls = get_local_storage(runtime)?;
+    let local_books = match isbns {
+        [isbn] => get_local_book(*isbn, runtime, id_token).await?.into_iter().collect(),
+        _ => {
+            let isbns = isbns.iter().collect::<HashSet<_>>();
+            crate::books::get(runtime, id_token)
+                .await?
+                .books
+                .into_iter()
+                .filter(|v| isbns.contains(&v.isbn))
+                .collect::<Vec<_>>()
+        }
+    };
 
-    let local_book = match ls.get_item(&isbn.to_string()) {
+    // only the books changed since the last sync are sent
+    let local_books = local_books.into_iter().filter(|v| v.needs_sync()).collect::<Vec<_>>();
+
+    let (books, result) = match local_books.len() {
+        0 => {
+            log!("Book sync is current");
+            return Ok(Vec::new());
+        }
+        1 => {
+            let (book, result) = upload_book(local_books.into_iter().next().unwrap(), runtime, id_token).await;
+            (book.into_iter().collect(), result)
+        }
+        _ => upload_books(local_books, runtime, id_token).await,
+    };
+
+    // the request error applies to all books that were not sync'd
+    let reason = result.err().unwrap_or(RetryAfter::Never);
+    Ok(books
+        .into_iter()
+        .filter(|v| v.timestamp_sync.is_none())
+        .map(|v| (v.isbn, reason.clone()))
+        .collect())
+}
+
+/// Returns the local copy of the book if it exists and is not a tombstone.
+/// Unparseable records are logged and treated as missing. See `integrity::check` for repairing them.
+async fn get_local_book(isbn: u64, runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<Option<Book>> {
+    let ls = get_local_storage(runtime, id_token).await?;
+
+    match ls.get_item(&isbn.to_string()).await {
         Ok(Some(v)) => {
             log!("Found in local storage: {isbn}");
             match serde_json::from_str::<Book>(&v) {
-                Ok(v) => v,
+                Ok(v) if v.is_deleted() => Ok(None),
+                Ok(v) => Ok(Some(v)),
                 Err(e) => {
                     log!("Failed to parse local storage book record for {isbn}: {:?}", e);
-                    bail!("Failed to parse local storage book record");
+                    Ok(None)
                 }
             }
         }
         Ok(None) => {
             log!("Book not found in local storage: {isbn}");
-            bail!("Book not found in local storage");
+            Ok(None)
         }
         Err(e) => {
             log!("Failed to get local storage book record for {isbn}: {:?}", e);
             bail!("Failed to get local storage book record");
         }
-    };
-
-    // check if the books needs a sync
-    if let Some(v) = local_book.timestamp_sync {
-        if v > local_book.timestamp_update {
-            log!("Book sync is current: {isbn}");
-            return Ok(());
-        }
     }
+}
 
+/// Sends the book to the cloud DB and saves it in the local storage with the new sync status.
+/// A write rejected because of a stale version is retried after merging the current cloud record into the book.
+/// Returns the book with the updated sync timestamp: the current time on success or None on failure.
+/// Returns None if the book was deleted on another device after the local change and was removed locally.
+/// The second value is the error of the last request, if any.
+/// All errors are logged.
+async fn upload_book(
+    local_book: Book,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> (Option<Book>, crate::Result<()>) {
     log!("Sending book data to lambda: {}", local_book.isbn);
 
-    // some fields are never saved in the cloud
-    let mut cloud_book = Book::new(local_book.isbn);
-    // these fields are saved in the cloud
-    cloud_book.authors = local_book.authors.clone();
-    cloud_book.read_status = local_book.read_status;
-    cloud_book.timestamp_update = local_book.timestamp_update;
-    cloud_book.title = local_book.title.clone();
+    let mut local_book = local_book;
+    let mut result = Err(RetryAfter::Never);
 
-    // send the data to the cloud DB
-    // set the new sync timestamp to the current time on success or None on failure
-    let book = if execute_http_request::<Book, ()>(SYNC_HTML_ENDPOINT_URL, HttpMethod::Post(cloud_book), runtime, id_token)
+    for _ in 0..=MAX_CONFLICT_RETRIES {
+        // send the data to the cloud DB
+        match execute_http_request::<Book, BookSyncResult>(
+            SYNC_HTML_ENDPOINT_URL,
+            HttpMethod::Post(to_cloud_book(&local_book)),
+            runtime,
+            id_token,
+        )
         .await
-        .is_ok()
-    {
-        log!("Book sync'd with the cloud DB");
+        {
+            Ok(v) => {
+                log!("Book sync'd with the cloud DB");
+                local_book.version = v.and_then(|v| v.version).or(local_book.version);
+                result = Ok(());
+                break;
+            }
+            Err(HttpError {
+                retry_after: RetryAfter::Conflict(v),
+                ..
+            }) => match serde_json::from_str::<Book>(&v) {
+                Ok(cloud_book) => {
+                    log!("Version conflict for {}: {:?}", local_book.isbn, cloud_book.version);
+                    if !resolve_conflict(&mut local_book, &cloud_book) {
+                        remove_deleted_book(local_book.isbn, runtime, id_token).await;
+                        return (None, Ok(()));
+                    }
+                }
+                Err(e) => {
+                    log!("Failed to parse the conflicting cloud record: {:?}", e);
+                    break;
+                }
+            },
+            Err(e) => {
+                log!("Failed to sync the book with the cloud DB: {:?}", e);
+                result = Err(e.retry_after);
+                break;
+            }
+        }
+    }
+
+    // set the new sync timestamp to the current time on success or None on failure
+    let book = if result.is_ok() {
         local_book.with_new_sync_timestamp()
     } else {
-        log!("Failed to sync the book with the cloud DB");
         local_book.without_sync_timestamp()
     };
 
     // try to save the book with the updated sync field in the local storage
-    match serde_json::to_string(&book) {
-        Ok(v) => match ls.set_item(&book.isbn.to_string(), &v) {
-            Ok(()) => log!("Sync status updated to {:?}", book.timestamp_sync),
+    match book::save(&book, runtime, id_token).await {
+        Ok(()) => log!("Sync status updated to {:?}", book.timestamp_sync),
+        Err(e) => {
+            log!("Failed to update sync status: {:?}", e);
+        }
+    };
+
+    (Some(book), result)
+}
+
+/// Sends the books to the cloud DB in a single request and saves them in the local storage with the new sync status.
+/// The books rejected because of stale versions are merged with the current cloud records and sent again.
+/// Returns the books with the updated sync timestamps: the current time for the books the cloud DB saved or None for the rest.
+/// The books deleted on another device after the local change are removed locally and not returned.
+/// The second value is the error of the last request, if any.
+/// All errors are logged.
+async fn upload_books(
+    local_books: Vec<Book>,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> (Vec<Book>, crate::Result<()>) {
+    log!("Sending {} books to lambda", local_books.len());
+
+    let mut books = Vec::with_capacity(local_books.len());
+    let mut pending = local_books;
+    let mut result = Ok(());
+
+    for _ in 0..=MAX_CONFLICT_RETRIES {
+        if pending.is_empty() {
+            break;
+        }
+
+        let cloud_books = Books {
+            books: pending.iter().map(to_cloud_book).collect(),
+        };
+
+        // books missing from the response are treated as failed
+        let mut results = match execute_http_request::<Books, BookSyncResults>(
+            SYNC_HTML_ENDPOINT_URL,
+            HttpMethod::Post(cloud_books),
+            runtime,
+            id_token,
+        )
+        .await
+        {
+            Ok(Some(v)) => v.results.into_iter().map(|v| (v.isbn, v)).collect::<HashMap<_, _>>(),
+            Ok(None) => {
+                log!("Empty batch sync response");
+                HashMap::new()
+            }
             Err(e) => {
-                log!("Failed to update sync status: {:?}", e);
+                log!("Failed to sync the books with the cloud DB: {:?}", e);
+                result = Err(e.retry_after);
+                break;
             }
-        },
+        };
+
+        let mut conflicts = Vec::new();
+        for mut book in pending {
+            match results.remove(&book.isbn) {
+                Some(result) if result.saved => {
+                    book.version = result.version.or(book.version);
+                    books.push(book.with_new_sync_timestamp());
+                }
+                Some(BookSyncResult {
+                    conflict: Some(cloud_book),
+                    ..
+                }) => {
+                    log!("Version conflict for {}: {:?}", book.isbn, cloud_book.version);
+                    if resolve_conflict(&mut book, &cloud_book) {
+                        conflicts.push(book);
+                    } else {
+                        remove_deleted_book(book.isbn, runtime, id_token).await;
+                    }
+                }
+                result => {
+                    log!(
+                        "Failed to sync {} with the cloud DB: {:?}",
+                        book.isbn,
+                        result.and_then(|v| v.error)
+                    );
+                    books.push(book.without_sync_timestamp());
+                }
+            }
+        }
+
+        pending = conflicts;
+    }
+
+    // the books that failed or still conflict are retried on the next sync
+    books.extend(pending.into_iter().map(|v| v.without_sync_timestamp()));
+
+    log!(
+        "Books sync'd with the cloud DB: {}",
+        books.iter().filter(|v| v.timestamp_sync.is_some()).count()
+    );
+
+    // try to save the books with the updated sync field in the local storage
+    for book in &books {
+        if let Err(e) = book::save(book, runtime, id_token).await {
+            log!("Failed to update sync status for {}: {:?}", book.isbn, e);
+        }
+    }
+
+    (books, result)
+}
+
+/// Merges the current cloud record into the local book after a version conflict.
+/// Returns false if the cloud record is a tombstone newer than the local changes,
+/// in which case the book should be removed locally instead of being sent again.
+fn resolve_conflict(local_book: &mut Book, cloud_book: &Book) -> bool {
+    if let Some(deleted) = cloud_book.timestamp_delete {
+        if local_book.timestamp_update <= deleted {
+            return false;
+        }
+        log!("Local book changed after the cloud deletion: {}", local_book.isbn);
+    }
+
+    local_book.merge_from_cloud(cloud_book);
+    true
+}
+
+/// Removes a book deleted on another device from the local storage.
+/// Errors are logged.
+async fn remove_deleted_book(isbn: u64, runtime: &impl Platform, id_token: &Option<IdToken>) {
+    let ls = match get_local_storage(runtime, id_token).await {
+        Ok(v) => v,
         Err(e) => {
-            log!("Failed to serialize book record for {}: {:?}", book.isbn, e);
+            log!("Failed to get local storage: {:?}", e);
+            return;
         }
     };
 
-    Ok(())
+    match ls.remove_item(&isbn.to_string()).await {
+        Ok(()) => log!("Deleted in the cloud, removed from local storage: {isbn}"),
+        Err(e) => log!("Failed to remove {isbn} deleted in the cloud: {:?}", e),
+    }
+}
+
+/// Returns a copy of the book with only the fields that are saved in the cloud.
+fn to_cloud_book(local_book: &Book) -> Book {
+    let mut cloud_book = Book::new(local_book.isbn);
+    cloud_book.authors = local_book.authors.clone();
+    cloud_book.read_status = local_book.read_status;
+    cloud_book.timestamp_update = local_book.timestamp_update;
+    cloud_book.title = local_book.title.clone();
+    cloud_book.field_timestamps = local_book.field_timestamps;
+    cloud_book.version = local_book.version;
+    cloud_book
 }
 
-/// Get the list of books from the cloud DB and update the local storage.
+/// Get the list of books changed in the cloud DB since the last sync and update the local storage,
+/// then send local books that were never sync'd or changed since the last sync to the cloud DB.
+/// `books` must come from the namespace of the user from the token because
+/// the cloud books are saved into that namespace.
+/// The progress and the outcome are reported to the UI as `WasmResponse::SyncStatus`.
 /// Returns:
 /// - the updated list of books on success
 /// - None if there was no change
 /// - Error with a user-friendly message on error
-///
-/// TODO: send unsync'd books to the cloud
-pub(crate) async fn sync_books(books: Books, runtime: &Window, id_token: &Option<IdToken>) -> Result<Option<Books>> {
+pub(crate) async fn sync_books(
+    books: Books,
+    runtime: &impl Platform,
+    id_token: &Option<IdToken>,
+) -> Result<Option<Books>> {
     // nothing to do if the user is not logged in
     if id_token.is_none() {
         log!("No token. Sync skipped.");
         return Ok(None);
     }
 
+    start_sync(runtime, id_token).await;
+    let result = sync_all_books(books, runtime, id_token).await;
+    finish_sync(
+        result.as_ref().map(|_| ()).map_err(|e| format!("{:#}", e)),
+        runtime,
+        id_token,
+    )
+    .await;
+
+    result
+}
+
+/// The body of `sync_books` that runs between the status reports.
+async fn sync_all_books(books: Books, runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<Option<Books>> {
+    // send the local deletions first, so that the cloud does not return the deleted books
+    match crate::books::get_deleted(runtime, id_token).await {
+        Ok(v) => {
+            for isbn in v {
+                let _ = delete_book(&isbn.to_string(), runtime, id_token).await;
+            }
+        }
+        Err(e) => log!("Failed to get deleted books: {:?}", e),
+    }
+
+    // only the changes since the last sync are requested, or all books if it's the first sync
+    let ls = get_local_storage(runtime, id_token).await?;
+    let cursor = get_sync_cursor(&ls).await;
+
+    // the cloud DB keeps tombstones for `TOMBSTONE_RETENTION_DAYS` only, so a device that has not sync'd
+    // for longer than that would miss some deletions and has to compare its books with the full list
+    let stale_cursor = cursor.is_some_and(|v| v < Utc::now() - TimeDelta::days(TOMBSTONE_RETENTION_DAYS));
+    let cursor = match stale_cursor {
+        true => {
+            log!("Sync cursor is older than the tombstones: {:?}", cursor);
+            None
+        }
+        false => cursor,
+    };
+
+    let url = match cursor {
+        Some(v) => [
+            SYNC_HTML_ENDPOINT_URL,
+            "?",
+            SINCE_URL_PARAM_NAME,
+            "=",
+            &v.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        ]
+        .concat(),
+        None => SYNC_HTML_ENDPOINT_URL.to_string(),
+    };
+
     // get the list of books from the lambda
-    let cloud_books = match execute_http_request::<(), Books>(SYNC_HTML_ENDPOINT_URL, HttpMethod::Get, runtime, id_token).await {
-        Ok(Some(v)) => v,
-        Ok(None) => {
+    // no content means no cloud changes, but the local changes still have to be uploaded
+    // and an empty full list still removes the books deleted on other devices
+    let cloud_books = match get_cloud_books(&url, runtime, id_token).await? {
+        Some(v) => v,
+        None => {
             log!("No books in the cloud DB");
-            return Ok(None);
-        }
-        Err(e) => {
-            log!("Failed to get books from the cloud DB: {:?}", e);
-            return Err(Error::msg("Failed to get books from the cloud DB"));
+            Books { books: Vec::new() }
         }
     };
 
-    log!("Cloud books: {}, local: {}", cloud_books.books.len(), books.books.len());
+    log!(
+        "Cloud books since {:?}: {}, local: {}",
+        cursor,
+        cloud_books.books.len(),
+        books.books.len()
+    );
+
+    // the next sync starts a little before the latest change in this one, see `SYNC_CURSOR_OVERLAP_MINUTES`
+    // the cloud write timestamps are used to avoid depending on the local clock
+    // the cursor never goes back, so the same overlap is not requested over and over
+    let new_cursor = cloud_books
+        .books
+        .iter()
+        .filter_map(|v| v.timestamp_sync)
+        .max()
+        .map(|v| v - TimeDelta::minutes(SYNC_CURSOR_OVERLAP_MINUTES))
+        .max(cursor);
+    // the cursor is not moved if any of the changes failed to save locally so that they are requested again
+    let mut saved_all = true;
 
     // index the local books by ISBN for faster lookups
-    let mut local_books = books
-        .books
-        .into_iter()
-        .map(|v| (v.isbn, v))
-        .collect::<std::collections::HashMap<_, _>>();
+    let mut local_books = books.books.into_iter().map(|v| (v.isbn, v)).collect::<HashMap<_, _>>();
+
+    // apply the deletions made on other devices
+    // a local copy changed after the deletion is kept and uploaded below, which restores the book in the cloud
+    let (tombstones, cloud_books): (Vec<_>, Vec<_>) = cloud_books.books.into_iter().partition(|v| v.is_deleted());
+    let cloud_books = Books { books: cloud_books };
+    let mut books_removed = 0;
+    for tombstone in tombstones {
+        let deleted = tombstone.timestamp_delete.unwrap_or(tombstone.timestamp_update);
+        match local_books.get(&tombstone.isbn) {
+            Some(local_book) if local_book.needs_sync() && local_book.timestamp_update > deleted => {
+                log!("Local book changed after the cloud deletion: {}", tombstone.isbn);
+            }
+            Some(_) => match remove_deleted_in_cloud(&ls, tombstone.isbn).await {
+                true => {
+                    local_books.remove(&tombstone.isbn);
+                    books_removed += 1;
+                }
+                false => saved_all = false,
+            },
+            None => {}
+        }
+    }
+
+    // the books deleted on other devices so long ago that their tombstones are gone
+    // are the sync'd local books missing from the full list
+    if stale_cursor {
+        let cloud_isbns = cloud_books.books.iter().map(|v| v.isbn).collect::<HashSet<_>>();
+        let expired = local_books
+            .values()
+            .filter(|v| !v.needs_sync() && !cloud_isbns.contains(&v.isbn))
+            .map(|v| v.isbn)
+            .collect::<Vec<_>>();
+        for isbn in expired {
+            match remove_deleted_in_cloud(&ls, isbn).await {
+                true => {
+                    local_books.remove(&isbn);
+                    books_removed += 1;
+                }
+                false => saved_all = false,
+            }
+        }
+    }
 
     // find local books that need to be updated from the cloud
     let books_to_update = cloud_books
@@ -130,15 +573,18 @@ pub(crate) async fn sync_books(books: Books, runtime: &Window, id_token: &Option
             match local_books.get_mut(&cloud_book.isbn) {
                 Some(local_book) => {
                     // the book is already in the local storage
-                    // check if the cloud book is newer
-                    if cloud_book.timestamp_update > local_book.timestamp_update {
-                        // the cloud book is newer
-                        // update the local book
+                    // the latest change to each field wins
+                    let changed = local_book.merge_from_cloud(cloud_book);
+                    if !local_book.same_cloud_fields(cloud_book) {
+                        // some of the local changes are newer and have to be uploaded
+                        log!("Local changes are newer for ISBN: {}", cloud_book.isbn);
+                        local_book.timestamp_sync = None;
+                        None
+                    } else if changed || local_book.needs_sync() {
+                        // the local book is the same as the cloud one now
                         log!("Merge from cloud for ISBN: {}", cloud_book.isbn);
-                        local_book.merge_from_cloud(cloud_book);
                         Some(local_book.isbn)
                     } else {
-                        // the local book is newer
                         None
                     }
                 }
@@ -164,72 +610,84 @@ pub(crate) async fn sync_books(books: Books, runtime: &Window, id_token: &Option
         })
         .collect::<Vec<_>>();
 
-    // exit now if there is nothing to add or update
-    if books_to_add.is_empty() && books_to_update.is_empty() {
-        log!("No new books to add or update");
+    // local books the cloud does not have or has an older copy of
+    // the books merged from the cloud are excluded because they get a new sync timestamp below
+    // the merged books with newer local changes have their sync timestamp reset above
+    let books_to_upload = local_books
+        .values()
+        .filter(|v| v.needs_sync() && !books_to_update.contains(&v.isbn))
+        .map(|v| v.isbn)
+        .collect::<HashSet<_>>();
+
+    // exit now if there is nothing to add, update or upload
+    if books_to_add.is_empty() && books_to_update.is_empty() && books_to_upload.is_empty() && books_removed == 0 {
+        log!("No new books to add, update, upload or remove");
+        save_sync_cursor(&ls, new_cursor).await;
         return Ok(None);
     };
 
     log!(
-        "Cloud books to add: {}, update: {}",
+        "Cloud books to add: {}, update: {}, upload: {}, removed: {}",
         books_to_add.len(),
-        books_to_update.len()
+        books_to_update.len(),
+        books_to_upload.len(),
+        books_removed
     );
 
-    let ls = get_local_storage(runtime)?;
-
     // convert the hashmap back to a Vec list of books ans save any updated books along the way
     let mut books = Books {
-        books: local_books
-            .into_values()
-            .map(|book| {
-                if books_to_update.contains(&book.isbn) {
-                    let book = book.with_new_sync_timestamp();
-                    match serde_json::to_string(&book) {
-                        Ok(v) => match ls.set_item(&book.isbn.to_string(), &v) {
-                            Ok(()) => {
-                                log!("Updated in local storage: {}", book.isbn);
-                                book
-                            }
-                            Err(e) => {
-                                log!("Failed to update sync status for {}: {:?}", book.isbn, e);
-                                // this makes no sense because the record in LS may have a different value
-                                book.without_sync_timestamp()
-                            }
-                        },
-                        Err(e) => {
-                            log!("Failed to serialize book record for {}: {:?}", book.isbn, e);
-                            book.without_sync_timestamp()
-                        }
-                    }
-                } else {
+        books: Vec::with_capacity(local_books.len() + books_to_add.len()),
+    };
+    let mut unsynced_books = Vec::with_capacity(books_to_upload.len());
+    for book in local_books.into_values() {
+        let book = if books_to_update.contains(&book.isbn) {
+            let book = book.with_new_sync_timestamp();
+            match book::save(&book, runtime, id_token).await {
+                Ok(()) => {
+                    log!("Updated in local storage: {}", book.isbn);
                     book
                 }
-            })
-            .collect(),
-    };
+                Err(e) => {
+                    log!("Failed to update sync status for {}: {:?}", book.isbn, e);
+                    saved_all = false;
+                    // this makes no sense because the record in LS may have a different value
+                    book.without_sync_timestamp()
+                }
+            }
+        } else if books_to_upload.contains(&book.isbn) {
+            unsynced_books.push(book);
+            continue;
+        } else {
+            book
+        };
 
-    // reallocate the local list of books to accommodate the new books
-    books.books.reserve(books_to_add.len());
+        books.books.push(book);
+    }
+
+    // send unsync'd books to the cloud, a failed upload is retried on the next sync
+    if unsynced_books.len() > 1 {
+        books
+            .books
+            .extend(upload_books(unsynced_books, runtime, id_token).await.0);
+    } else {
+        for book in unsynced_books {
+            books.books.extend(upload_book(book, runtime, id_token).await.0);
+        }
+    }
 
     // save the new books to the local storage and add them to the list of local books
     for cloud_book in books_to_add {
         // try to save the book with the updated sync field in the local storage
         let cloud_book = cloud_book.with_new_sync_timestamp();
-        let cloud_book = match serde_json::to_string(&cloud_book) {
-            Ok(v) => match ls.set_item(&cloud_book.isbn.to_string(), &v) {
-                Ok(()) => {
-                    log!("Added to local storage: {}", cloud_book.isbn);
-                    cloud_book
-                }
-                Err(e) => {
-                    log!("Failed to update sync status for {}: {:?}", cloud_book.isbn, e);
-                    // this makes no sense because the record in LS may have a different value
-                    cloud_book.without_sync_timestamp()
-                }
-            },
+        let cloud_book = match book::save(&cloud_book, runtime, id_token).await {
+            Ok(()) => {
+                log!("Added to local storage: {}", cloud_book.isbn);
+                cloud_book
+            }
             Err(e) => {
-                log!("Failed to serialize book record for {}: {:?}", cloud_book.isbn, e);
+                log!("Failed to update sync status for {}: {:?}", cloud_book.isbn, e);
+                saved_all = false;
+                // this makes no sense because the record in LS may have a different value
                 cloud_book.without_sync_timestamp()
             }
         };
@@ -237,15 +695,120 @@ pub(crate) async fn sync_books(books: Books, runtime: &Window, id_token: &Option
         books.books.push(cloud_book);
     }
 
+    if saved_all {
+        save_sync_cursor(&ls, new_cursor).await;
+    }
+
     books.sort();
 
     Ok(Some(books))
 }
 
+/// Removes a book deleted on another device from the local storage.
+/// Returns false if the removal failed. Errors are logged.
+async fn remove_deleted_in_cloud(ls: &impl KeyValueStore, isbn: u64) -> bool {
+    match ls.remove_item(&isbn.to_string()).await {
+        Ok(()) => {
+            log!("Deleted in the cloud, removed from local storage: {isbn}");
+            true
+        }
+        Err(e) => {
+            log!("Failed to remove {isbn} deleted in the cloud: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Gets all pages of the books from the lambda starting at `url`, following the page cursors.
+/// Returns None if the lambda returned no content.
+/// Returns an error if any of the pages failed because a partial list would make
+/// the missing cloud books look like local-only books.
+async fn get_cloud_books(url: &str, runtime: &impl Platform, id_token: &Option<IdToken>) -> Result<Option<Books>> {
+    // a page of a large library may take longer than the default timeout
+    let policy = RetryPolicy {
+        timeout_ms: 60_000,
+        ..RetryPolicy::DEFAULT
+    };
+    let separator = if url.contains('?') { "&" } else { "?" };
+
+    let mut books = Vec::new();
+    let mut next_page: Option<String> = None;
+    for page in 0..MAX_SYNC_PAGES {
+        let page_url = match &next_page {
+            Some(v) => [url, separator, PAGE_URL_PARAM_NAME, "=", v].concat(),
+            None => url.to_string(),
+        };
+
+        match execute_http_request_with_policy::<(), BooksPage>(&page_url, HttpMethod::Get, policy, runtime, id_token)
+            .await
+        {
+            Ok(Some(v)) => {
+                books.extend(v.books);
+                next_page = v.next_page;
+            }
+            Ok(None) if page == 0 => return Ok(None),
+            Ok(None) => next_page = None,
+            // the request ID in the error matches it with the lambda logs
+            Err(e) => return Err(Error::new(e).context("Failed to get books from the cloud DB")),
+        }
+
+        if next_page.is_none() {
+            return Ok(Some(Books { books }));
+        }
+    }
+
+    log!("Too many pages of cloud books: {}", books.len());
+    Err(Error::msg("Failed to get books from the cloud DB"))
+}
+
+/// Returns the time of the latest cloud change applied locally by `sync_books`.
+/// Returns None if the books were never sync'd or the value is invalid.
+async fn get_sync_cursor(ls: &impl KeyValueStore) -> Option<DateTime<Utc>> {
+    match ls.get_item(SYNC_CURSOR_KEY).await {
+        Ok(Some(v)) => match DateTime::parse_from_rfc3339(&v) {
+            Ok(v) => Some(v.with_timezone(&Utc)),
+            Err(e) => {
+                log!("Invalid sync cursor {v}: {:?}", e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            log!("Failed to get sync cursor: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Removes the sync cursor to make the next `sync_books` download all books.
+/// Call it after removing local books that may still be in the cloud.
+/// Errors are logged.
+pub(crate) async fn reset_sync_cursor(ls: &impl KeyValueStore) {
+    if let Err(e) = ls.remove_item(SYNC_CURSOR_KEY).await {
+        log!("Failed to reset sync cursor: {:?}", e);
+    }
+}
+
+/// Saves the sync cursor in the local storage namespace of the user.
+/// Errors are logged. The next sync requests more changes than needed if the cursor is not saved.
+async fn save_sync_cursor(ls: &impl KeyValueStore, cursor: Option<DateTime<Utc>>) {
+    let cursor = match cursor {
+        Some(v) => v.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        None => return,
+    };
+
+    match ls.set_item(SYNC_CURSOR_KEY, &cursor).await {
+        Ok(()) => log!("Sync cursor saved: {cursor}"),
+        Err(e) => log!("Failed to save sync cursor: {:?}", e),
+    }
+}
+
 /// Try to delete the book from the cloud DB.
-/// By this time the book should not exist in the local storage.
-/// No action is taken if the book fails to delete - it will reappear on the next sync.
-pub(crate) async fn delete_book(isbn: &str, runtime: &Window, id_token: &Option<IdToken>) -> Result<()> {
+/// The cloud DB keeps a tombstone record for other devices to remove their copies, see `sync_books`.
+/// By this time the book should be replaced with a tombstone in the local storage.
+/// The local tombstone is removed on success or kept for `sync_books` to retry on failure.
+/// Returns the request error on failure, see `outbox::replay`.
+pub(crate) async fn delete_book(isbn: &str, runtime: &impl Platform, id_token: &Option<IdToken>) -> crate::Result<()> {
     // nothing to do if the user is not logged in
     if id_token.is_none() {
         log!("No token. Sync skipped.");
@@ -258,14 +821,418 @@ pub(crate) async fn delete_book(isbn: &str, runtime: &Window, id_token: &Option<
     let url = [SYNC_HTML_ENDPOINT_URL, "?", ISBN_URL_PARAM_NAME, "=", isbn].concat();
 
     // send the ISBN to the cloud DB
-    if execute_http_request::<Book, ()>(&url, HttpMethod::Delete, runtime, id_token)
-        .await
-        .is_ok()
+    // a failed deletion is retried by the outbox or the next `sync_books` with longer delays
+    match execute_http_request_with_policy::<Book, ()>(
+        &url,
+        HttpMethod::Delete,
+        RetryPolicy::NO_RETRY,
+        runtime,
+        id_token,
+    )
+    .await
     {
-        log!("Book deleted from the cloud DB");
-        Ok(())
-    } else {
-        log!("Failed to delete the book from the cloud DB");
-        bail!("Failed to delete the book from the cloud DB");
+        Ok(_) => {
+            log!("Book deleted from the cloud DB");
+            remove_tombstone(isbn, runtime, id_token).await;
+            Ok(())
+        }
+        Err(e) => {
+            log!("Failed to delete the book from the cloud DB: {:?}", e);
+            Err(e.retry_after)
+        }
+    }
+}
+
+/// Removes the local tombstone of the book once the deletion reached the cloud DB.
+/// Does nothing if the book was scanned again in the meantime.
+/// Errors are logged.
+async fn remove_tombstone(isbn: &str, runtime: &impl Platform, id_token: &Option<IdToken>) {
+    let ls = match get_local_storage(runtime, id_token).await {
+        Ok(v) => v,
+        Err(e) => {
+            log!("Failed to get local storage: {:?}", e);
+            return;
+        }
+    };
+
+    let is_tombstone = match ls.get_item(isbn).await {
+        Ok(Some(v)) => serde_json::from_str::<Book>(&v).is_ok_and(|v| v.is_deleted()),
+        _ => false,
+    };
+
+    if is_tombstone {
+        match ls.remove_item(isbn).await {
+            Ok(()) => log!("Tombstone removed from local storage: {isbn}"),
+            Err(e) => log!("Failed to remove tombstone for {isbn}: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{MockPlatform, MockResponse};
+    use crate::wasm_response::WasmErrorCode;
+    use futures::executor::block_on;
+
+    const ISBN_A: u64 = 9780143107712;
+    const ISBN_B: u64 = 9781761186769;
+    const ISBN_C: u64 = 9780241950432;
+
+    fn token() -> Option<IdToken> {
+        Some("test-token".to_owned())
+    }
+
+    fn new_book(isbn: u64, title: &str) -> Book {
+        let mut book = Book::new(isbn);
+        book.title = Some(title.to_owned());
+        book.authors = Some(vec!["Author".to_owned()]);
+        book
+    }
+
+    fn save_local(platform: &MockPlatform, book: &Book) {
+        platform.set_user_item(&book.isbn.to_string(), &serde_json::to_string(book).unwrap());
+    }
+
+    fn get_local(platform: &MockPlatform, isbn: u64) -> Option<Book> {
+        platform
+            .get_user_item(&isbn.to_string())
+            .map(|v| serde_json::from_str::<Book>(&v).unwrap())
+    }
+
+    /// A cursor from a day ago, well within `TOMBSTONE_RETENTION_DAYS`.
+    fn recent_cursor() -> String {
+        (Utc::now() - TimeDelta::days(1)).to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    fn sync_result(isbn: u64, version: u64) -> String {
+        serde_json::to_string(&BookSyncResult {
+            isbn,
+            saved: true,
+            error: None,
+            version: Some(version),
+            conflict: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn first_sync() {
+        let platform = MockPlatform::default();
+
+        // A was scanned locally, C was sync'd earlier and then deleted on another device
+        save_local(&platform, &new_book(ISBN_A, "Local"));
+        save_local(&platform, &new_book(ISBN_C, "Deleted").with_new_sync_timestamp());
+
+        // B was added on another device
+        let cloud_books = Books {
+            books: vec![
+                new_book(ISBN_B, "Cloud").with_new_sync_timestamp(),
+                Book::new_tombstone(ISBN_C).with_new_sync_timestamp(),
+            ],
+        };
+        let cursor = cloud_books.books.iter().filter_map(|v| v.timestamp_sync).max().unwrap();
+        platform.respond(MockResponse::new(
+            "GET",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &serde_json::to_string(&cloud_books).unwrap(),
+        ));
+        platform.respond(MockResponse::new(
+            "POST",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &sync_result(ISBN_A, 1),
+        ));
+
+        let local_books = block_on(crate::books::get(&platform, &token())).unwrap();
+        let books = block_on(sync_books(local_books, &platform, &token())).unwrap().unwrap();
+
+        let isbns = books.books.iter().map(|v| v.isbn).collect::<HashSet<_>>();
+        assert_eq!(isbns, HashSet::from([ISBN_A, ISBN_B]));
+
+        let book_a = get_local(&platform, ISBN_A).unwrap();
+        assert!(!book_a.needs_sync());
+        assert_eq!(book_a.version, Some(1));
+        assert_eq!(get_local(&platform, ISBN_B).unwrap().title.as_deref(), Some("Cloud"));
+        assert!(get_local(&platform, ISBN_C).is_none());
+
+        // the first sync requests all books and the next one starts a little before the latest cloud change
+        assert_eq!(platform.requests.borrow()[0].url, SYNC_HTML_ENDPOINT_URL);
+        let cursor = cursor - TimeDelta::minutes(SYNC_CURSOR_OVERLAP_MINUTES);
+        assert_eq!(
+            platform.get_user_item(SYNC_CURSOR_KEY),
+            Some(cursor.to_rfc3339_opts(SecondsFormat::Nanos, true))
+        );
+
+        // the UI is told about the start and the end of the sync
+        let messages = platform.messages.borrow();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|v| v.starts_with(r#"{"syncStatus""#)));
+        assert!(messages[0].contains(r#""running":true"#));
+        assert!(messages[1].contains(r#""running":false"#));
+        assert!(messages[1].contains("lastSuccess"));
+    }
+
+    #[test]
+    fn next_sync_uses_cursor() {
+        let platform = MockPlatform::default();
+        let cursor = recent_cursor();
+        platform.set_user_item(SYNC_CURSOR_KEY, &cursor);
+        platform.respond(MockResponse::new("GET", SYNC_HTML_ENDPOINT_URL, 200, r#"{"books":[]}"#));
+
+        let books = block_on(sync_books(Books { books: Vec::new() }, &platform, &token())).unwrap();
+
+        assert!(books.is_none());
+        assert_eq!(
+            platform.requests.borrow()[0].url,
+            [SYNC_HTML_ENDPOINT_URL, "?", SINCE_URL_PARAM_NAME, "=", &cursor].concat()
+        );
+    }
+
+    #[test]
+    fn empty_cloud_uploads_local_books() {
+        let platform = MockPlatform::default();
+        platform.set_user_item(SYNC_CURSOR_KEY, &recent_cursor());
+        save_local(&platform, &new_book(ISBN_A, "Local"));
+        platform.respond(MockResponse::new("GET", SYNC_HTML_ENDPOINT_URL, 204, ""));
+        platform.respond(MockResponse::new(
+            "POST",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &sync_result(ISBN_A, 1),
+        ));
+
+        let local_books = block_on(crate::books::get(&platform, &token())).unwrap();
+        let books = block_on(sync_books(local_books, &platform, &token())).unwrap().unwrap();
+        assert_eq!(books.books.len(), 1);
+
+        let book_a = get_local(&platform, ISBN_A).unwrap();
+        assert!(!book_a.needs_sync());
+        assert_eq!(book_a.version, Some(1));
+        assert!(platform.requests.borrow().iter().any(|v| v.method == "POST"));
+    }
+
+    #[test]
+    fn stale_cursor_syncs_all_books() {
+        let platform = MockPlatform::default();
+        let stale = Utc::now() - TimeDelta::days(TOMBSTONE_RETENTION_DAYS + 1);
+        platform.set_user_item(SYNC_CURSOR_KEY, &stale.to_rfc3339_opts(SecondsFormat::Nanos, true));
+
+        // A is still in the cloud, the tombstone of B expired, C was never sync'd
+        save_local(&platform, &new_book(ISBN_A, "Kept").with_new_sync_timestamp());
+        save_local(
+            &platform,
+            &new_book(ISBN_B, "Deleted long ago").with_new_sync_timestamp(),
+        );
+        save_local(&platform, &new_book(ISBN_C, "Local"));
+        let cloud_books = Books {
+            books: vec![new_book(ISBN_A, "Kept").with_new_sync_timestamp()],
+        };
+        platform.respond(MockResponse::new(
+            "GET",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &serde_json::to_string(&cloud_books).unwrap(),
+        ));
+        platform.respond(MockResponse::new(
+            "POST",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &sync_result(ISBN_C, 1),
+        ));
+
+        let local_books = block_on(crate::books::get(&platform, &token())).unwrap();
+        let books = block_on(sync_books(local_books, &platform, &token())).unwrap().unwrap();
+
+        // the full list is requested instead of the changes since the stale cursor
+        assert_eq!(platform.requests.borrow()[0].url, SYNC_HTML_ENDPOINT_URL);
+        let isbns = books.books.iter().map(|v| v.isbn).collect::<HashSet<_>>();
+        assert_eq!(isbns, HashSet::from([ISBN_A, ISBN_C]));
+        assert!(get_local(&platform, ISBN_B).is_none());
+        assert!(!get_local(&platform, ISBN_C).unwrap().needs_sync());
+        let cursor = DateTime::parse_from_rfc3339(&platform.get_user_item(SYNC_CURSOR_KEY).unwrap()).unwrap();
+        assert!(cursor > stale);
+    }
+
+    #[test]
+    fn cursor_overlap_does_not_go_back() {
+        let platform = MockPlatform::default();
+        let cursor = recent_cursor();
+        platform.set_user_item(SYNC_CURSOR_KEY, &cursor);
+
+        // a change from the overlap of the previous sync is returned again
+        let mut book = new_book(ISBN_A, "Cloud");
+        let cursor_time = DateTime::parse_from_rfc3339(&cursor).unwrap().with_timezone(&Utc);
+        book.timestamp_sync = Some(cursor_time + TimeDelta::minutes(2));
+        let cloud_books = Books { books: vec![book] };
+        platform.respond(MockResponse::new(
+            "GET",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &serde_json::to_string(&cloud_books).unwrap(),
+        ));
+
+        block_on(sync_books(Books { books: Vec::new() }, &platform, &token())).unwrap();
+
+        assert!(get_local(&platform, ISBN_A).is_some());
+        assert_eq!(platform.get_user_item(SYNC_CURSOR_KEY), Some(cursor));
+    }
+
+    #[test]
+    fn sync_follows_pages() {
+        let platform = MockPlatform::default();
+        let page = |isbn: u64, next_page: Option<&str>| {
+            serde_json::to_string(&BooksPage {
+                books: vec![new_book(isbn, "Cloud").with_new_sync_timestamp()],
+                next_page: next_page.map(|v| v.to_owned()),
+            })
+            .unwrap()
+        };
+        platform.respond(MockResponse::new(
+            "GET",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &page(ISBN_A, Some("abc")),
+        ));
+        platform.respond(MockResponse::new("GET", "?page=abc", 200, &page(ISBN_B, None)));
+
+        let books = block_on(sync_books(Books { books: Vec::new() }, &platform, &token()))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(books.books.len(), 2);
+        assert!(get_local(&platform, ISBN_A).is_some());
+        assert!(get_local(&platform, ISBN_B).is_some());
+        assert_eq!(platform.requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn failed_page_fails_sync() {
+        let platform = MockPlatform::default();
+        let cursor = recent_cursor();
+        platform.set_user_item(SYNC_CURSOR_KEY, &cursor);
+        let first_page = BooksPage {
+            books: vec![new_book(ISBN_A, "Cloud").with_new_sync_timestamp()],
+            next_page: Some("abc".to_owned()),
+        };
+        platform.respond(MockResponse::new(
+            "GET",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &serde_json::to_string(&first_page).unwrap(),
+        ));
+
+        let result = block_on(sync_books(Books { books: Vec::new() }, &platform, &token()));
+
+        assert!(result.is_err());
+        assert!(platform.requests.borrow()[1].url.ends_with("Z&page=abc"));
+        assert!(get_local(&platform, ISBN_A).is_none());
+        assert_eq!(
+            platform.get_user_item(SYNC_CURSOR_KEY).as_deref(),
+            Some(cursor.as_str())
+        );
+    }
+
+    #[test]
+    fn failed_sync_is_reported() {
+        let platform = MockPlatform::default();
+
+        let result = block_on(sync_books(Books { books: Vec::new() }, &platform, &token()));
+
+        // the UI can retry a sync that failed for lack of network
+        let e = result.unwrap_err();
+        assert_eq!(WasmErrorCode::Storage.or_network(&e), WasmErrorCode::Network);
+        let status = serde_json::from_str::<SyncStatus>(&platform.get_user_item(SYNC_STATUS_KEY).unwrap()).unwrap();
+        let request_id = platform.requests.borrow()[0].request_id.clone();
+        assert_eq!(
+            status.last_error,
+            Some(format!(
+                "Failed to get books from the cloud DB: the network or the server is unavailable. Request ID: {request_id}"
+            ))
+        );
+        assert!(platform.get_user_item(SYNC_CURSOR_KEY).is_none());
+    }
+
+    #[test]
+    fn push_merges_conflicts() {
+        let platform = MockPlatform::default();
+
+        // the cloud has a newer title while the status was changed locally
+        let mut local_book = new_book(ISBN_A, "Old title");
+        local_book.version = Some(1);
+        local_book.field_timestamps.title = Some(local_book.timestamp_update);
+        let mut cloud_book = new_book(ISBN_A, "New title");
+        cloud_book.version = Some(2);
+        cloud_book.field_timestamps.title = Some(cloud_book.timestamp_update);
+        local_book.set_read_status(Some(bookworm_types::ReadStatus::Read));
+        save_local(&platform, &local_book);
+
+        platform.respond(MockResponse::new(
+            "POST",
+            SYNC_HTML_ENDPOINT_URL,
+            409,
+            &serde_json::to_string(&cloud_book).unwrap(),
+        ));
+        platform.respond(MockResponse::new(
+            "POST",
+            SYNC_HTML_ENDPOINT_URL,
+            200,
+            &sync_result(ISBN_A, 3),
+        ));
+
+        let failed = block_on(push_books(&[ISBN_A], &platform, &token())).unwrap();
+
+        assert!(failed.is_empty());
+        let requests = platform.requests.borrow();
+        assert_eq!(requests.len(), 2);
+        let resent = serde_json::from_str::<Book>(requests[1].body.as_ref().unwrap()).unwrap();
+        assert_eq!(resent.version, Some(2));
+        assert_eq!(resent.title.as_deref(), Some("New title"));
+        assert_eq!(resent.read_status, Some(bookworm_types::ReadStatus::Read));
+
+        let saved = get_local(&platform, ISBN_A).unwrap();
+        assert_eq!(saved.version, Some(3));
+        assert!(!saved.needs_sync());
+    }
+
+    #[test]
+    fn push_failure_keeps_retry_after() {
+        let platform = MockPlatform::default();
+        save_local(&platform, &new_book(ISBN_A, "Local"));
+        save_local(&platform, &new_book(ISBN_B, "Local"));
+        let mut response = MockResponse::new("POST", SYNC_HTML_ENDPOINT_URL, 503, "");
+        response.retry_after = Some("120".to_owned());
+        platform.respond(response);
+
+        let mut failed = block_on(push_books(&[ISBN_A, ISBN_B], &platform, &token())).unwrap();
+        failed.sort_by_key(|v| v.0);
+
+        assert_eq!(
+            failed,
+            vec![(ISBN_A, RetryAfter::Seconds(120)), (ISBN_B, RetryAfter::Seconds(120))]
+        );
+        assert!(get_local(&platform, ISBN_A).unwrap().needs_sync());
+    }
+
+    #[test]
+    fn delete_removes_tombstone() {
+        let platform = MockPlatform::default();
+        save_local(&platform, &Book::new_tombstone(ISBN_A));
+        platform.respond(MockResponse::new("DELETE", "isbn=9780143107712", 204, ""));
+
+        block_on(delete_book(&ISBN_A.to_string(), &platform, &token())).unwrap();
+
+        assert!(get_local(&platform, ISBN_A).is_none());
+    }
+
+    #[test]
+    fn anonymous_user_is_not_synced() {
+        let platform = MockPlatform::default();
+
+        assert!(block_on(sync_books(Books { books: Vec::new() }, &platform, &None))
+            .unwrap()
+            .is_none());
+        assert!(platform.requests.borrow().is_empty());
     }
 }
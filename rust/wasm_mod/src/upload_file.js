@@ -1,29 +1,73 @@
+// In-flight uploads by the request ID of the wasm call that started them.
+// They are kept for abort_uploads().
+const uploads = new Map();
+
 // This function is imported into lib.rs as a simpler way of uploading a file to S3.
 // Returns HTTP status that is converted into u32 in Rust.
-// 0 is returned if an error occurs.
-export async function upload_file_return_http_status(signed_url, file) {
+// 0 is returned if an error occurs, -1 if the upload was aborted.
+// XMLHttpRequest is used instead of fetch because fetch does not report upload progress.
+// on_progress(bytes_sent, total_bytes) is called while the file is being sent.
+export function upload_file_return_http_status(signed_url, file, request_id, on_progress) {
   console.log(`Url: ${signed_url}, file: `, file);
 
-  try {
-  // Send the file to the server
-    const response = await fetch(signed_url, {
-      body: file,
-      method: 'PUT',
-      headers: {
-        'Content-Type': 'image/jpg',
-      },
-      mode: 'cors',
-      cache: 'no-cache',
-    });
+  return new Promise((resolve) => {
+    const xhr = new XMLHttpRequest();
+
+    if (!uploads.has(request_id)) {
+      uploads.set(request_id, new Set());
+    }
+    uploads.get(request_id).add(xhr);
 
     // log and return the response status
-    const status = response.status
-    console.log(`File upload status: ${status}`);
-    
-    return status;
+    const done = (status) => {
+      const active = uploads.get(request_id);
+      if (active) {
+        active.delete(xhr);
+        if (active.size == 0) {
+          uploads.delete(request_id);
+        }
+      }
+      console.log(`File upload status: ${status}`);
+      resolve(status);
+    };
+
+    xhr.upload.onprogress = (event) => {
+      on_progress(event.loaded, event.lengthComputable ? event.total : 0);
+    };
+    xhr.onload = () => done(xhr.status);
+    xhr.onerror = () => {
+      console.log("File upload error");
+      done(0);
+    };
+    xhr.ontimeout = xhr.onerror;
+    xhr.onabort = () => done(-1);
+
+    try {
+      // Send the file to the server
+      xhr.open('PUT', signed_url);
+      // the type is set by the wasm module and must match the content type of the presigned URL
+      xhr.setRequestHeader('Content-Type', file.type || 'image/jpeg');
+      xhr.send(file);
+    } catch (error) {
+      console.log("File upload error:", error);
+      done(0);
+    }
+  });
+}
 
-  } catch (error) {
-    console.log("File upload error:", error);
+// Aborts the uploads started by the wasm call with the request ID.
+// Returns the number of aborted uploads.
+export function abort_uploads(request_id) {
+  const active = uploads.get(request_id);
+  if (!active) {
     return 0;
   }
-}
\ No newline at end of file
+
+  const count = active.size;
+  // abort() calls onabort, which removes the upload from the set
+  for (const xhr of [...active]) {
+    xhr.abort();
+  }
+
+  return count;
+}
@@ -1,30 +1,109 @@
-use anyhow::{bail, Result};
+use js_sys::Promise;
 use wasm_bindgen::prelude::*;
-use web_sys::{File, Storage, Window};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{File, IdbFactory, Request, Window, WorkerGlobalScope};
 
 /// Logs output into browser console.
+/// Native builds, e.g. tests, print to stdout instead because there is no console to call.
 macro_rules!  log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into())
+    ( $( $t:tt )* ) => {{
+        #[cfg(target_arch = "wasm32")]
+        web_sys::console::log_1(&format!( $( $t )* ).into());
+        #[cfg(not(target_arch = "wasm32"))]
+        println!( $( $t )* );
+    }}
+}
+
+/// The global scope the module is running in.
+/// The UI thread has a `Window`, a dedicated web worker has a `WorkerGlobalScope`.
+/// Both provide `fetch`, but only `Window` has `localStorage`.
+#[derive(Clone)]
+pub(crate) enum GlobalScope {
+    Window(Window),
+    Worker(WorkerGlobalScope),
+}
+
+/// The browser environment of a single entry point call.
+#[derive(Clone)]
+pub(crate) struct Runtime {
+    pub scope: GlobalScope,
+    /// The ID the UI passed to the entry point. It is echoed in every message of the call, see `WasmMessage`.
+    pub request_id: String,
+}
+
+impl Runtime {
+    /// Calls `fetch` on whatever global scope is available.
+    pub(crate) fn fetch_with_request(&self, request: &Request) -> Promise {
+        match &self.scope {
+            GlobalScope::Window(v) => v.fetch_with_request(request),
+            GlobalScope::Worker(v) => v.fetch_with_request(request),
+        }
+    }
+
+    /// Calls `setTimeout` on whatever global scope is available.
+    /// Returns the timer handle for `clear_timeout`.
+    pub(crate) fn set_timeout(&self, handler: &js_sys::Function, timeout_ms: i32) -> std::result::Result<i32, JsValue> {
+        match &self.scope {
+            GlobalScope::Window(v) => v.set_timeout_with_callback_and_timeout_and_arguments_0(handler, timeout_ms),
+            GlobalScope::Worker(v) => v.set_timeout_with_callback_and_timeout_and_arguments_0(handler, timeout_ms),
+        }
+    }
+
+    /// Calls `clearTimeout` on whatever global scope is available.
+    pub(crate) fn clear_timeout(&self, handle: i32) {
+        match &self.scope {
+            GlobalScope::Window(v) => v.clear_timeout_with_handle(handle),
+            GlobalScope::Worker(v) => v.clear_timeout_with_handle(handle),
+        }
+    }
+
+    /// Returns the IndexedDB factory for the global scope, if IndexedDB is available.
+    pub(crate) fn indexed_db(&self) -> std::result::Result<Option<IdbFactory>, JsValue> {
+        match &self.scope {
+            GlobalScope::Window(v) => v.indexed_db(),
+            GlobalScope::Worker(v) => v.indexed_db(),
+        }
     }
 }
-// this line is needed to make the macro available to the rest of the module
-pub(crate) use log;
 
-/// Returns the right type of runtime (Window) for the current browser
+/// Returns the right type of runtime (Window or WorkerGlobalScope) for the current browser
 /// or an error if the runtime is not available.
-pub(crate) async fn get_runtime() -> std::result::Result<Window, &'static str> {
-    // this is a fallback for Firefox, but it does not make sense why they would use Window in
-    // web workers
-    match web_sys::window() {
-        Some(v) => {
-            // log!("Runtime Window found");
-            Ok(v)
+/// The request ID is sent back to the UI with every message, see `WasmMessage`.
+pub(crate) async fn get_runtime(request_id: &str) -> std::result::Result<Runtime, &'static str> {
+    // the UI thread
+    if let Some(v) = web_sys::window() {
+        // log!("Runtime Window found");
+        return Ok(Runtime {
+            scope: GlobalScope::Window(v),
+            request_id: request_id.to_owned(),
+        });
+    }
+
+    // a dedicated worker has no Window, but has its own global scope
+    match js_sys::global().dyn_into::<WorkerGlobalScope>() {
+        Ok(v) => {
+            // log!("Runtime WorkerGlobalScope found");
+            Ok(Runtime {
+                scope: GlobalScope::Worker(v),
+                request_id: request_id.to_owned(),
+            })
         }
-        None => Err("Missing browser runtime. It's a bug."),
+        Err(_) => Err("Missing browser runtime. It's a bug."),
     }
 }
 
+/// Resolves after the delay. Resolves immediately if the timer cannot be set.
+pub(crate) async fn sleep(runtime: &Runtime, delay_ms: u32) {
+    let promise = Promise::new(&mut |resolve, _| {
+        if let Err(e) = runtime.set_timeout(&resolve, delay_ms.min(i32::MAX as u32) as i32) {
+            log!("Failed to set timer: {:?}", e);
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    });
+
+    let _ = JsFuture::from(promise).await;
+}
+
 #[allow(dead_code)]
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
@@ -37,31 +116,58 @@ pub fn set_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
-/// A shorcut for getting the local storage.
-/// TODO: put it inside OneCell, but it's probably not Send
-pub(crate) fn get_local_storage(runtime: &Window) -> Result<Storage> {
-    // connect to the local storage
-    match runtime.local_storage() {
-        Ok(Some(v)) => Ok(v),
-        Err(e) => {
-            bail!("Failed to get local storage: {:?}", e);
-        }
-        _ => {
-            bail!("Local storage not available (OK(None))");
-        }
-    }
+/// The outcome of a file upload to S3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UploadOutcome {
+    /// The HTTP status code of the upload or `0` if the upload failed with an error.
+    Status(u32),
+    /// The upload was aborted by `cancel_upload`.
+    Cancelled,
 }
 
 /// Uploads a file to S3 using a signed URL via an external JS function call.
-/// Returns the HTTP status code of the upload or `0` if the upload failed with an error.
-/// The error is logged in the console by the JS function.
-pub(crate) async fn upload_file(signed_url: &str, file: File) -> u32 {
-    let result = upload_file_return_http_status(signed_url, file).await;
-    result.as_f64().unwrap_or(0.0).trunc() as u32
+/// `on_progress` is called with the number of bytes sent and the total while the file is being sent.
+/// The upload can be aborted with `abort_uploads` and the same request ID.
+/// Errors are logged in the console by the JS function.
+pub(crate) async fn upload_file(
+    signed_url: &str,
+    file: File,
+    request_id: &str,
+    on_progress: &Closure<dyn FnMut(f64, f64)>,
+) -> UploadOutcome {
+    let result = upload_file_return_http_status(signed_url, file, request_id, on_progress.as_ref().unchecked_ref());
+    match JsFuture::from(result).await.ok().and_then(|v| v.as_f64()) {
+        Some(v) if v < 0.0 => UploadOutcome::Cancelled,
+        Some(v) => UploadOutcome::Status(v.trunc() as u32),
+        None => UploadOutcome::Status(0),
+    }
+}
+
+/// Decodes an image with the browser and returns it as PNG without metadata.
+/// This is the fallback for the formats the Rust decoders do not support, e.g. HEIC in Safari.
+/// Returns None if the browser cannot decode the image either.
+pub(crate) async fn decode_with_browser(file: &File) -> Option<Vec<u8>> {
+    match JsFuture::from(decode_image_with_browser(file)).await {
+        Ok(v) if !v.is_null() => Some(js_sys::Uint8Array::new(&v).to_vec()),
+        _ => None,
+    }
 }
 
-/// A JS-based file uploader using fetch+PUT for S3.
+#[wasm_bindgen(module = "/src/decode_image.js")]
+extern "C" {
+    fn decode_image_with_browser(file: &File) -> Promise;
+}
+
+/// A JS-based file uploader using XMLHttpRequest+PUT for S3.
 #[wasm_bindgen(module = "/src/upload_file.js")]
 extern "C" {
-    async fn upload_file_return_http_status(signed_url: &str, file: File) -> JsValue;
+    fn upload_file_return_http_status(
+        signed_url: &str,
+        file: File,
+        request_id: &str,
+        on_progress: &js_sys::Function,
+    ) -> Promise;
+
+    /// Aborts the uploads started with the request ID and returns how many there were.
+    pub(crate) fn abort_uploads(request_id: &str) -> u32;
 }
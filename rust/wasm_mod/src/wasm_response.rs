@@ -1,12 +1,105 @@
+use crate::backup::{ImportReport, LibraryExport};
+use crate::http_req::HttpError;
+use crate::integrity::LibraryReport;
+use crate::merge::MergeOffer;
+use crate::photos::UploadProgress;
+use crate::storage::StorageUsage;
+use crate::sync::SyncStatus;
+use crate::RetryAfter;
 use bookworm_types::{Book, Books};
 use serde::Serialize;
 use std::fmt;
 use wasm_bindgen::prelude::*;
 
 /// Wraps the result into a struct for JS to tell success from errors.
-/// The error is a text message to be logged in the console for now.
-/// It will have to be a more structured error in the future.
-pub type WasmResult<T> = std::result::Result<T, String>;
+/// See `WasmError` for the error side.
+pub type WasmResult<T> = std::result::Result<T, WasmError>;
+
+/// What kind of failure the UI is told about in `WasmError`.
+/// The values are part of the contract with the UI and must not be renamed.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WasmErrorCode {
+    /// The local storage could not be read or written.
+    Storage,
+    /// A photo could not be uploaded. It may be in the outbox to be retried automatically.
+    Upload,
+    /// The file from the user is not a valid library backup.
+    InvalidFile,
+    /// The user cancelled the operation, e.g. with `cancel_upload`.
+    Cancelled,
+    /// The network or the server was not available. The call may succeed later.
+    Network,
+}
+
+impl WasmErrorCode {
+    /// The key of the message the UI shows to the user for this code.
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            WasmErrorCode::Storage => "error.storage",
+            WasmErrorCode::Upload => "error.upload",
+            WasmErrorCode::InvalidFile => "error.invalidFile",
+            WasmErrorCode::Cancelled => "error.cancelled",
+            WasmErrorCode::Network => "error.network",
+        }
+    }
+
+    /// True if repeating the same call later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WasmErrorCode::Storage | WasmErrorCode::InvalidFile | WasmErrorCode::Cancelled => false,
+            WasmErrorCode::Upload | WasmErrorCode::Network => true,
+        }
+    }
+
+    /// Returns `Network` if the error was caused by an HTTP request that may succeed later
+    /// or this code otherwise. See `RetryAfter::Seconds`.
+    pub fn or_network(self, e: &anyhow::Error) -> Self {
+        let is_network = e.chain().any(|v| {
+            matches!(
+                v.downcast_ref::<HttpError>(),
+                Some(HttpError {
+                    retry_after: RetryAfter::Seconds(_),
+                    ..
+                })
+            )
+        });
+
+        if is_network {
+            WasmErrorCode::Network
+        } else {
+            self
+        }
+    }
+}
+
+/// The error side of `WasmResult`.
+/// The UI decides what to do based on `code` and `retryable` and shows the message by `message_key`.
+/// `details` are for the console and bug reports only.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WasmError {
+    /// A stable identifier of the kind of the error.
+    pub code: WasmErrorCode,
+    /// The key of the user-facing message, e.g. `error.storage`.
+    pub message_key: &'static str,
+    /// True if repeating the same call later may succeed.
+    pub retryable: bool,
+    /// The error with its causes, if there is anything to add to the code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl WasmError {
+    /// Creates an error with the message key and the retry flag of the code and the error chain as details.
+    pub fn new(code: WasmErrorCode, e: &anyhow::Error) -> Self {
+        WasmError {
+            code,
+            message_key: code.message_key(),
+            retryable: code.is_retryable(),
+            details: Some(format!("{:#}", e)),
+        }
+    }
+}
 
 /// A shared container for all types of responses placed in their own fields.
 /// There can only be one type of response at a time.
@@ -23,6 +116,24 @@ pub enum WasmResponse {
     LocalBook(Box<Option<WasmResult<Book>>>),
     /// Result of a deletion operation for the enclosed ISBN.
     Deleted(Box<Option<WasmResult<String>>>),
+    /// How much space the books take in the local storage.
+    /// Sent on request and every time the storage is compacted to make room for a new record.
+    StorageUsage(Box<Option<WasmResult<StorageUsage>>>),
+    /// Problems found in the local storage records and what was done about them.
+    LibraryCheck(Box<Option<WasmResult<LibraryReport>>>),
+    /// Books scanned before logging in that the user can add to the account.
+    /// Sent until the user decides about all of them. The UI replies with `merge_anonymous_library()`.
+    MergeOffer(Box<Option<WasmResult<MergeOffer>>>),
+    /// The contents of a backup file for the UI to save.
+    LibraryExport(Box<Option<WasmResult<LibraryExport>>>),
+    /// The outcome of restoring a backup file.
+    LibraryImport(Box<Option<WasmResult<ImportReport>>>),
+    /// Whether the library is in sync with the cloud.
+    /// Sent at the start and the end of every sync and on request.
+    SyncStatus(Box<Option<WasmResult<SyncStatus>>>),
+    /// How much of a photo has been uploaded.
+    /// Sent repeatedly while an upload is in progress, see `cancel_upload()`.
+    UploadProgress(Box<Option<WasmResult<UploadProgress>>>),
 }
 
 impl fmt::Display for WasmResponse {
@@ -42,12 +153,136 @@ impl fmt::Display for WasmResponse {
     }
 }
 
+/// The envelope of every message sent to the UI.
+/// The response is flattened into it, so the UI reads the fields of `WasmResponse` directly,
+/// e.g. `{"requestId":"1","last":false,"localBook":{"Ok":{..}}}`.
+/// Every entry point call ends with a message that has `last` set and no response.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WasmMessage<'a> {
+    /// The ID the UI passed to the entry point that sent the message.
+    pub request_id: &'a str,
+    /// True for the final message of the call.
+    pub last: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub response: Option<&'a WasmResponse>,
+}
+
+impl fmt::Display for WasmMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_string(&self) {
+            Ok(v) => write!(f, "{v}"),
+            Err(e) => {
+                log!("Failed to serialize WasmMessage {:?}", e);
+                write!(f, "Failed to serialize WasmMessage. {:?}", e)
+            }
+        }
+    }
+}
+
+/// Sends the final message of an entry point call to the UI when dropped,
+/// so that it is the last message on every return path.
+pub(crate) struct LastMessage<'a>(pub &'a str);
+
+impl Drop for LastMessage<'_> {
+    fn drop(&mut self) {
+        let msg = WasmMessage {
+            request_id: self.0,
+            last: true,
+            response: None,
+        };
+        report_progress(msg.to_string());
+    }
+}
+
 /// WASM responses are sent back to the UI thread via Messaging API.
 /// They are packaged into a common structure with each data type in its own field.
-/// See `WasmResult` and `WasmResponse` for more details.
+/// See `WasmMessage`, `WasmResult` and `WasmResponse` for more details.
 /// This function a proxy for report_progress() in progress.js
 /// that does the actual sending.
 #[wasm_bindgen(module = "/src/progress.js")]
 extern "C" {
     pub fn report_progress(msg: String);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_json() {
+        let resp = WasmResponse::Deleted(Box::new(Some(Err(WasmError::new(
+            WasmErrorCode::Upload,
+            &anyhow::anyhow!("Photo upload failed"),
+        )))));
+
+        assert_eq!(
+            resp.to_string(),
+            [
+                r#"{"deleted":{"Err":{"code":"Upload","messageKey":"error.upload","retryable":true,"#,
+                r#""details":"Photo upload failed"}}}"#
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn message_json() {
+        let resp = WasmResponse::Deleted(Box::new(Some(Ok("9780143107712".to_owned()))));
+        let msg = WasmMessage {
+            request_id: "abc-1",
+            last: false,
+            response: Some(&resp),
+        };
+        assert_eq!(
+            msg.to_string(),
+            r#"{"requestId":"abc-1","last":false,"deleted":{"Ok":"9780143107712"}}"#
+        );
+
+        let msg = WasmMessage {
+            request_id: "abc-1",
+            last: true,
+            response: None,
+        };
+        assert_eq!(msg.to_string(), r#"{"requestId":"abc-1","last":true}"#);
+    }
+
+    #[test]
+    fn network_errors_are_retryable() {
+        let e = anyhow::Error::new(HttpError {
+            retry_after: RetryAfter::Seconds(0),
+            request_id: "abc-1".to_owned(),
+        })
+        .context("Failed to get books from the cloud DB");
+        let error = WasmError::new(WasmErrorCode::Storage.or_network(&e), &e);
+
+        assert_eq!(error.code, WasmErrorCode::Network);
+        assert_eq!(error.message_key, "error.network");
+        assert!(error.retryable);
+        assert!(error.details.unwrap().ends_with("Request ID: abc-1"));
+
+        // the server rejected the request, so repeating it would not help
+        let e = anyhow::Error::new(HttpError {
+            retry_after: RetryAfter::Never,
+            request_id: "abc-2".to_owned(),
+        });
+        assert_eq!(WasmErrorCode::Storage.or_network(&e), WasmErrorCode::Storage);
+        assert_eq!(
+            WasmErrorCode::Storage.or_network(&anyhow::anyhow!("Quota exceeded")),
+            WasmErrorCode::Storage
+        );
+    }
+
+    #[test]
+    fn details_are_optional() {
+        let error = WasmError {
+            details: None,
+            ..WasmError::new(WasmErrorCode::Storage, &anyhow::anyhow!(""))
+        };
+
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"code":"Storage","messageKey":"error.storage","retryable":false}"#
+        );
+    }
+}
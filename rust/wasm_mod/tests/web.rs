@@ -8,6 +8,6 @@ use wasm_bindgen_test::*;
 wasm_bindgen_test_configure!(run_in_browser);
 
 #[wasm_bindgen_test]
-fn pass () {
+fn pass() {
     assert_eq!(1 + 1, 2);
 }
@@ -1,24 +1,10 @@
-import { ReadStatus } from "@/wasm-rust/isbn_mod";
+import type { Book, ReadStatus } from "@/wasm-types";
 
-/// A mirror of the Rust's type
-export interface VolumeInfo {
-  description: string | undefined,
-}
+// the shapes of the wasm types are generated from Rust, see `rust/ts-gen`
+export type * from "@/wasm-types";
 
 /** A string-based type of ReadStatus enum */
-export type ReadStatusStrings = keyof typeof ReadStatus;
-
-/** A mirror of the Rust's type */
-export interface Book {
-  isbn: number,
-  title: string | undefined,
-  authors: string[] | undefined,
-  readStatus: ReadStatusStrings | undefined,
-  cover: string | undefined,
-  volumeInfo: VolumeInfo | undefined,
-  shareId: number | undefined,
-  photos: string[] | undefined,
-}
+export type ReadStatusStrings = ReadStatus;
 
 /** Creates a standardised book URL.
  * TODO: move it to WASM
@@ -40,4 +26,11 @@ export function buildBookUrl(book: Book, readerId?: string): string {
   }
 
   return url;
-}
\ No newline at end of file
+}
+
+/** Returns a new ID for a call to a wasm entry point.
+ * Every message sent for the call has it in `requestId`, see `WasmMessage`.
+ */
+export function newRequestId(): string {
+  return crypto.randomUUID();
+}
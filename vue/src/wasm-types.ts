@@ -0,0 +1,347 @@
+// Generated by `cargo run -p ts-gen` from the Rust types in `bookworm_types` and `wasm_mod`.
+// Do not edit. The file is checked by `cargo test` and `build-wasm.sh`.
+
+/**
+ * The envelope of every message sent to the UI.
+ * The response is flattened into it, so the UI reads the fields of `WasmResponse` directly,
+ * e.g. `{"requestId":"1","last":false,"localBook":{"Ok":{..}}}`.
+ * Every entry point call ends with a message that has `last` set and no response.
+ */
+export type WasmMessage = {
+  /** The ID the UI passed to the entry point that sent the message. */
+  requestId: string;
+  /** True for the final message of the call. */
+  last: boolean;
+} & (WasmResponse | {});
+
+/**
+ * Where the reader is with the book.
+ * Defaults to None.
+ */
+export type ReadStatus =
+  | "ToRead"
+  | "Read"
+  | "Liked"
+  ;
+
+/**
+ * A shared container for all types of responses placed in their own fields.
+ * There can only be one type of response at a time.
+ * This is needed for easy identification of the response type in JS.
+ */
+export type WasmResponse =
+  /** A list of book records. */
+  | { localBooks: WasmResult<Books> | null }
+  /** A single book record. */
+  | { localBook: WasmResult<Book> | null }
+  /** Result of a deletion operation for the enclosed ISBN. */
+  | { deleted: WasmResult<string> | null }
+  /**
+   * How much space the books take in the local storage.
+   * Sent on request and every time the storage is compacted to make room for a new record.
+   */
+  | { storageUsage: WasmResult<StorageUsage> | null }
+  /** Problems found in the local storage records and what was done about them. */
+  | { libraryCheck: WasmResult<LibraryReport> | null }
+  /**
+   * Books scanned before logging in that the user can add to the account.
+   * Sent until the user decides about all of them. The UI replies with `merge_anonymous_library()`.
+   */
+  | { mergeOffer: WasmResult<MergeOffer> | null }
+  /** The contents of a backup file for the UI to save. */
+  | { libraryExport: WasmResult<LibraryExport> | null }
+  /** The outcome of restoring a backup file. */
+  | { libraryImport: WasmResult<ImportReport> | null }
+  /**
+   * Whether the library is in sync with the cloud.
+   * Sent at the start and the end of every sync and on request.
+   */
+  | { syncStatus: WasmResult<SyncStatus> | null }
+  /**
+   * How much of a photo has been uploaded.
+   * Sent repeatedly while an upload is in progress, see `cancel_upload()`.
+   */
+  | { uploadProgress: WasmResult<UploadProgress> | null }
+  ;
+
+/** A list of book records. */
+export interface Books {
+  books: Book[];
+}
+
+/**
+ * Wraps the result into a struct for JS to tell success from errors.
+ * See `WasmError` for the error side.
+ */
+export type WasmResult<T> = { Ok: T } | { Err: WasmError };
+
+/**
+ * An internal representation of a book record.
+ * Stored in the local storage and in the cloud.
+ * This struct does not Default implementation to force thinking what attributes go where.
+ */
+export interface Book {
+  /** This ISBN may differ from the key in the local storage or the industry IDs in the Google Books API. */
+  isbn: number;
+  /** When the book was last updated. */
+  timestampUpdate: string;
+  /**
+   * When the book was last sync'd.
+   * Cloud records have it set to when the record was last written in the cloud DB.
+   */
+  timestampSync?: string;
+  /**
+   * When title, authors and read_status were last changed.
+   * See `merge_fields()`.
+   */
+  fieldTimestamps?: FieldTimestamps;
+  /**
+   * The version of the cloud record this copy was last sync'd with.
+   * The cloud DB rejects writes with a stale version, see `BookSyncResult::conflict`.
+   * None if the book was never sync'd.
+   */
+  version?: number;
+  /**
+   * When the book was deleted.
+   * Only tombstone records have it. They have no other details and are never shown to the user.
+   */
+  timestampDelete?: string;
+  /** Reading status, where the reader is with the book. */
+  readStatus?: ReadStatus;
+  /** The cover image URL. */
+  cover?: string;
+  title?: string;
+  authors?: string[];
+  /** The book details from Google Books API */
+  volumeInfo?: VolumeInfo;
+  /**
+   * A list of URLs for user-uploaded photos of the book.
+   * The list is sorted by the timestamp of the photo in the chronological order.
+   */
+  photos?: string[];
+  /**
+   * A shortcode to access the book details for this user.
+   * It is set to the timestamp of the first photo and is never updated.
+   */
+  shareId?: number;
+}
+
+/**
+ * A report on how much space the book records take in the store
+ * and what was removed to free some of it.
+ * Sent to the UI inside `WasmResponse::StorageUsage`.
+ */
+export interface StorageUsage {
+  /** The number of book records in the store. */
+  records: number;
+  /**
+   * An approximate size of all book records, keys included.
+   * Browsers store strings as UTF-16, so the actual number may be up to 2x larger.
+   */
+  bytes: number;
+  /**
+   * ISBNs of books that had their Google Books data and covers removed by the last compaction.
+   * The data is refetched when the book is opened.
+   */
+  compacted?: number[];
+  /** The approximate number of bytes freed by the last compaction. */
+  freedBytes: number;
+}
+
+/** The result of `check_local_library` sent to the UI as `WasmResponse::LibraryCheck`. */
+export interface LibraryReport {
+  /** The number of book records checked. */
+  checked: number;
+  /** True if the check was run with repairs enabled. */
+  repair: boolean;
+  issues: LibraryIssue[];
+}
+
+/**
+ * Books scanned without logging in that can be added to the user's account.
+ * Sent to the UI as `WasmResponse::MergeOffer`.
+ */
+export interface MergeOffer {
+  /** A lean copy of the anonymous books for the UI to show what would be merged. */
+  books: Books;
+}
+
+/**
+ * The contents of a library backup file.
+ * It is sent to the UI as `WasmResponse::LibraryExport` for saving into a file
+ * and is read back by `import_library()`.
+ */
+export interface LibraryExport {
+  /** Always `bookworm-library`. */
+  format: string;
+  /** See `LIBRARY_EXPORT_VERSION`. */
+  version: number;
+  /** When the file was created. */
+  exported: string;
+  /** Complete book records as they are stored locally, including photo IDs and Google Books data. */
+  books: Book[];
+}
+
+/**
+ * The outcome of `import_library()`.
+ * Sent to the UI as `WasmResponse::LibraryImport`.
+ */
+export interface ImportReport {
+  /** True if the local library was cleared before the import. */
+  replace: boolean;
+  /** The number of books saved into the local storage. */
+  imported: number;
+  /** The number of books skipped because the local copy was newer. */
+  skipped: number;
+  /** Records that failed validation with the reason. */
+  invalid?: string[];
+  /** Valid records that could not be saved into the local storage with the reason, e.g. a full disk. */
+  failed?: string[];
+  /** The number of local books removed because they were not in the backup. Only in replace mode. */
+  removed: number;
+  /**
+   * ISBNs of the local books with changes that had not reached the cloud when the library was replaced.
+   * Those changes are lost and the UI should tell the user about them.
+   */
+  unsynced?: number[];
+}
+
+/**
+ * Tells the UI whether the library is in sync with the cloud.
+ * Sent as `WasmResponse::SyncStatus` at the start and the end of every sync.
+ */
+export interface SyncStatus {
+  /** The number of changes waiting in the outbox to be sent to the cloud. */
+  pending: number;
+  /** When the latest sync completed without errors. */
+  lastSuccess?: string;
+  /** The error of the latest sync. It is cleared by the next successful sync. */
+  lastError?: string;
+  /** True while a sync is in progress. */
+  running: boolean;
+}
+
+/**
+ * How much of a photo has been sent to S3.
+ * Sent to the UI as `WasmResponse::UploadProgress` while the upload is in progress.
+ */
+export interface UploadProgress {
+  isbn: number;
+  /** The name of the file as picked by the user. */
+  fileName: string;
+  bytesSent: number;
+  /** The size of the upload. It is 0 if the browser cannot tell. */
+  totalBytes: number;
+}
+
+/**
+ * The error side of `WasmResult`.
+ * The UI decides what to do based on `code` and `retryable` and shows the message by `message_key`.
+ * `details` are for the console and bug reports only.
+ */
+export interface WasmError {
+  /** A stable identifier of the kind of the error. */
+  code: WasmErrorCode;
+  /** The key of the user-facing message, e.g. `error.storage`. */
+  messageKey: string;
+  /** True if repeating the same call later may succeed. */
+  retryable: boolean;
+  /** The error with its causes, if there is anything to add to the code. */
+  details?: string;
+}
+
+/**
+ * When each of the fields shared with the cloud was last changed.
+ * Fields without a timestamp fall back onto `Book::timestamp_update` if they have a value.
+ */
+export interface FieldTimestamps {
+  title?: string;
+  authors?: string;
+  readStatus?: string;
+}
+
+/** Part of GoogleBooks API response */
+export interface VolumeInfo {
+  title: string;
+  authors: string[];
+  description?: string;
+  pageCount?: number;
+  imageLinks?: ImageLinks;
+}
+
+/** A single problem found in the local storage. */
+export interface LibraryIssue {
+  /** The local storage key of the record. */
+  key: string;
+  kind: IssueKind;
+  /** What the repair does or would do about it. */
+  action: IssueAction;
+  /** True if the action was applied successfully. */
+  fixed: boolean;
+  /** A human-readable explanation, e.g. the parser error. */
+  details?: string;
+}
+
+/**
+ * What kind of failure the UI is told about in `WasmError`.
+ * The values are part of the contract with the UI and must not be renamed.
+ */
+export type WasmErrorCode =
+  /** The local storage could not be read or written. */
+  | "Storage"
+  /** A photo could not be uploaded. It may be in the outbox to be retried automatically. */
+  | "Upload"
+  /** The file from the user is not a valid library backup. */
+  | "InvalidFile"
+  /** The user cancelled the operation, e.g. with `cancel_upload`. */
+  | "Cancelled"
+  /** The network or the server was not available. The call may succeed later. */
+  | "Network"
+  ;
+
+/** Part of GoogleBooks API response */
+export interface ImageLinks {
+  /** ~80 pixels wide */
+  smallThumbnail: string | null;
+  /** ~128 pixels wide */
+  thumbnail: string | null;
+  /** ~300 pixels wide */
+  small: string | null;
+  /** ~575 pixels wide */
+  medium: string | null;
+  /** ~800 pixels wide */
+  large: string | null;
+  /** ~1280 pixels wide */
+  extraLarge: string | null;
+}
+
+/** What is wrong with a local storage record. */
+export type IssueKind =
+  /** The value is not a valid `Book` JSON. */
+  | "InvalidJson"
+  /** The ISBN inside the record differs from the storage key. */
+  | "IsbnMismatch"
+  /**
+   * The record has no title and is not shown to the user,
+   * e.g. because Google Books was not available when the book was scanned.
+   */
+  | "MissingTitle"
+  /** The same book is stored under its ISBN-10 and ISBN-13 keys. */
+  | "DuplicateIsbn"
+  /** Different ISBNs with the same title and authors, e.g. a paperback and a hardcover. */
+  | "DuplicateEdition"
+  ;
+
+/** What the repair does about the issue. */
+export type IssueAction =
+  /** The record is updated in place. */
+  | "Fix"
+  /** The record is moved under `QUARANTINE_KEY_PREFIX`. */
+  | "Quarantine"
+  /** The record is merged into the other copy of the same book and quarantined. */
+  | "Merge"
+  /** The details are fetched from Google Books again. The record is kept as it is if that fails. */
+  | "Refetch"
+  /** Nothing can be done automatically. The issue is for the user to decide. */
+  | "None"
+  ;
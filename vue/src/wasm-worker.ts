@@ -0,0 +1,16 @@
+// A dedicated module worker that runs the wasm module off the UI thread.
+// The UI sends it `WasmCall` messages via `callWasm` in wasm.ts.
+// The module posts its messages to the global scope of this worker,
+// which is the `Worker` object on the UI thread.
+import initWasmModule, * as wasm from '@/wasm-rust/isbn_mod'
+import type { WasmCall } from '@/wasm'
+
+const ready = initWasmModule()
+
+self.onmessage = async (ev: MessageEvent<WasmCall>) => {
+  await ready
+  const { entryPoint, args } = ev.data
+  const fn = wasm[entryPoint] as (...args: unknown[]) => Promise<void>
+  // the entry points report their own errors via messages, so a rejection here is a bug
+  fn(...args).catch((e) => console.error(`${entryPoint} failed in the worker:`, e))
+}
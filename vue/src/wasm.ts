@@ -0,0 +1,63 @@
+import initWasmModule, { migrate_local_storage } from '@/wasm-rust/isbn_mod'
+import { newRequestId } from '@/interfaces'
+
+/** The wasm entry points the UI calls via the worker. */
+export type WasmEntryPoint =
+  | "get_book_data"
+  | "get_scanned_books"
+  | "update_book_status"
+  | "delete_book"
+  | "upload_pic"
+  | "cancel_upload"
+  | "get_storage_usage"
+  | "check_local_library"
+  | "merge_anonymous_library"
+  | "get_sync_status"
+  | "replay_outbox"
+  | "export_library"
+  | "import_library";
+
+/** A call to a wasm entry point sent to the worker.
+ * `args` start with the request ID, followed by the arguments of the entry point.
+ */
+export interface WasmCall {
+  entryPoint: WasmEntryPoint;
+  args: unknown[];
+}
+
+// Set in the local storage after the books were copied into IndexedDB, see `startWorker`.
+const MIGRATED_KEY = "wasm-idb-migrated";
+
+let worker: Promise<Worker> | undefined;
+
+/** Starts the wasm worker and relays its messages to `window`.
+ * The worker has no local storage, so the books saved by the UI thread before it existed
+ * are copied into IndexedDB first. It's done once per browser.
+ */
+async function startWorker(): Promise<Worker> {
+  if (!localStorage.getItem(MIGRATED_KEY)) {
+    try {
+      await initWasmModule();
+      await migrate_local_storage(newRequestId(), undefined);
+      localStorage.setItem(MIGRATED_KEY, new Date().toISOString());
+    } catch (e) {
+      // the worker still works with whatever is in IndexedDB, the copy is retried on the next page load
+      console.error("Failed to copy the books into IndexedDB:", e);
+    }
+  }
+
+  const w = new Worker(new URL('./wasm-worker.ts', import.meta.url), { type: 'module' });
+  // the views listen to `window` for the messages, as they did when the module ran on the UI thread
+  w.onmessage = (ev) => window.postMessage(ev.data, window.location.origin);
+  return w;
+}
+
+/** Calls a wasm entry point in the worker with a new request ID followed by `args`.
+ * Returns the request ID. The messages of the call arrive on `window` with it in `requestId`.
+ */
+export function callWasm(entryPoint: WasmEntryPoint, ...args: unknown[]): string {
+  const requestId = newRequestId();
+  worker ??= startWorker();
+  worker.then((w) => w.postMessage({ entryPoint, args: [requestId, ...args] } satisfies WasmCall));
+  return requestId;
+}